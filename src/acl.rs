@@ -0,0 +1,211 @@
+//! Source address access control for listeners (`--allow`, `--deny`).
+//!
+//! Checked as soon as a connection attempt comes in, before the handshake is awaited. Denied
+//! clients still complete the handshake, but only so they can be told why they're turned away:
+//! the connection is closed right after with [`ACCESS_DENIED`].
+
+use std::net::IpAddr;
+
+use quinn::{Connecting, Connection, ConnectionError, VarInt};
+use tracing::{debug, warn};
+
+use crate::{tasks, Cli};
+
+/// Application error code used to close connections from denied addresses.
+pub const ACCESS_DENIED: u32 = 6;
+
+/// Whether the listener closed `conn` because of our address.
+pub fn denied(conn: &Connection) -> bool {
+    matches!(
+        conn.close_reason(),
+        Some(ConnectionError::ApplicationClosed(close))
+            if close.error_code == VarInt::from_u32(ACCESS_DENIED)
+    )
+}
+
+/// Network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A plain address is a /32 or
+/// /128.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Self, String> {
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address '{}'", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length '{}'", prefix))?,
+            None => max,
+        };
+        // matched against clients the way they're compared, as plain IPv4
+        if let IpAddr::V6(v6) = addr {
+            if let Some(v4) = v6.to_ipv4_mapped().filter(|_| prefix >= 96) {
+                return Ok(Cidr {
+                    addr: IpAddr::V4(v4),
+                    prefix: prefix - 96,
+                });
+            }
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // clients on a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` bits of `a` and `b` are equal.
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let bytes = (prefix / 8) as usize;
+    let bits = prefix % 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+/// Allow and deny lists of a listener.
+#[derive(Default)]
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn from_args(args: &Cli) -> Self {
+        Acl {
+            allow: args.allow.clone(),
+            deny: args.deny.clone(),
+        }
+    }
+
+    /// Denied addresses are refused even if they're also allowed. If there's an allow list,
+    /// addresses not on it are refused.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Returns the connection attempt if its source is permitted, or turns it away in the
+    /// background.
+    pub fn check(&self, connecting: Connecting) -> Option<Connecting> {
+        let addr = connecting.remote_address();
+        if self.permits(addr.ip()) {
+            return Some(connecting);
+        }
+        warn!("refusing connection from {}", addr);
+        tasks::spawn(async move {
+            match connecting.await {
+                Ok(conn) => conn.close(VarInt::from_u32(ACCESS_DENIED), b"access denied"),
+                Err(e) => debug!("handshake with refused client {} failed: {}", addr, e),
+            }
+        });
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn contains(cidr: &str, addr: &str) -> bool {
+        Cidr::parse(cidr).unwrap().contains(ip(addr))
+    }
+
+    #[test]
+    fn plain_addresses_are_single_hosts() {
+        assert!(contains("192.0.2.7", "192.0.2.7"));
+        assert!(!contains("192.0.2.7", "192.0.2.8"));
+        assert!(contains("2001:db8::1", "2001:db8::1"));
+        assert!(!contains("2001:db8::1", "2001:db8::2"));
+    }
+
+    #[test]
+    fn bad_networks_are_refused() {
+        for cidr in [
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/",
+            "192.0.2.0/-1",
+            "192.0.2/24",
+            "example.com",
+        ] {
+            assert!(Cidr::parse(cidr).is_err(), "{} was accepted", cidr);
+        }
+    }
+
+    #[test]
+    fn prefixes_match_up_to_their_length() {
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("192.0.2.7/32", "192.0.2.7"));
+        assert!(!contains("192.0.2.7/32", "192.0.2.6"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::3"));
+        assert!(contains("2001:db8::/127", "2001:db8::1"));
+        // not on a byte boundary
+        assert!(contains("10.16.0.0/12", "10.31.255.255"));
+        assert!(!contains("10.16.0.0/12", "10.32.0.0"));
+        assert!(!contains("10.16.0.0/12", "10.15.255.255"));
+    }
+
+    #[test]
+    fn families_dont_mix() {
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(!contains("::/0", "192.0.2.7"));
+        assert!(!contains("::/96", "192.0.2.7"));
+    }
+
+    #[test]
+    fn mapped_addresses_are_ipv4() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/104", "10.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/104", "::ffff:10.1.2.3"));
+        assert!(!contains("::ffff:10.0.0.0/104", "11.1.2.3"));
+        assert!(!contains("::ffff:10.1.2.3", "2001:db8::1"));
+    }
+
+    #[test]
+    fn denying_wins_over_allowing() {
+        let acl = Acl {
+            allow: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+            deny: vec![Cidr::parse("10.0.0.13").unwrap()],
+        };
+        assert!(acl.permits(ip("10.0.0.12")));
+        assert!(!acl.permits(ip("10.0.0.13")));
+        assert!(!acl.permits(ip("192.0.2.7")));
+        assert!(Acl::default().permits(ip("192.0.2.7")));
+    }
+}
@@ -0,0 +1,378 @@
+//! Directory transfers (`--send-dir PATH`, `--recv-dir PATH`).
+//!
+//! The tree under the sender's PATH is streamed over the session stream and recreated under the
+//! receiver's PATH, with permissions and modification times. Symbolic links and special files are
+//! skipped.
+//!
+//! Framing: `NQD1` magic, then one entry per directory and file, parents first, then an end
+//! entry. Numbers are big-endian.
+//!
+//! ```text
+//! kind (u8: 0 end, 1 directory, 2 file)
+//! path length (u16), path (UTF-8, relative, `/`-separated)
+//! mode (u32, Unix permission bits), mtime (u64 seconds and u32 nanoseconds since the epoch)
+//! for files: size (u64), then the data
+//! ```
+//!
+//! Each file is written to a temporary file next to it and renamed into place once complete, so
+//! files never show up half-written, and a transfer cut short leaves the files received so far.
+//! Directory permissions and times are applied last, since adding files changes the times and a
+//! read-only directory couldn't be filled.
+//!
+//! The receiver doesn't trust the paths and modes it's sent: paths can't leave the root or go
+//! through a symbolic link already under it, existing files are only replaced with
+//! `--overwrite`, and the setuid, setgid and sticky bits are dropped.
+
+use std::{
+    error::Error,
+    fs::{self, Metadata},
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use quinn::{ReadExactError, RecvStream, SendStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+use crate::counters::StreamCounter;
+use crate::rate::TokenBucket;
+use crate::sched::Share;
+use crate::timers;
+
+const MAGIC: &[u8; 4] = b"NQD1";
+const END: u8 = 0;
+const DIRECTORY: u8 = 1;
+const FILE: u8 = 2;
+const CHUNK: usize = 64 * 1024;
+const PART_SUFFIX: &str = ".nesquic-part";
+
+/// A directory or file to send, with its path relative to the root.
+struct Entry {
+    path: PathBuf,
+    relative: String,
+    metadata: Metadata,
+}
+
+/// Lists the tree under `root`, parents before their contents, in name order.
+fn walk(root: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        let mut children: Vec<_> = fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+        children.sort_by_key(|child| child.file_name());
+        let mut subdirs = Vec::new();
+        for child in children {
+            let path = child.path();
+            let Some(name) = child.file_name().to_str().map(str::to_string) else {
+                warn!("[archive] skipping {}: not valid UTF-8", path.display());
+                continue;
+            };
+            let relative = format!("{}{}", prefix, name);
+            if relative.len() > u16::MAX as usize {
+                warn!("[archive] skipping {}: path too long", path.display());
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                subdirs.push((path.clone(), format!("{}/", relative)));
+            } else if !metadata.is_file() {
+                warn!(
+                    "[archive] skipping {}: not a regular file or directory",
+                    path.display()
+                );
+                continue;
+            }
+            entries.push(Entry {
+                path,
+                relative,
+                metadata,
+            });
+        }
+        // popped in name order
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> u32 {
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+    if metadata.permissions().readonly() {
+        mode & !0o222
+    } else {
+        mode
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+fn set_mtime(path: &Path, mtime: SystemTime) -> io::Result<()> {
+    fs::File::open(path)?.set_modified(mtime)
+}
+
+fn header(kind: u8, entry: &Entry) -> Vec<u8> {
+    let mtime = entry
+        .metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let mut header = vec![kind];
+    header.extend_from_slice(&(entry.relative.len() as u16).to_be_bytes());
+    header.extend_from_slice(entry.relative.as_bytes());
+    header.extend_from_slice(&mode(&entry.metadata).to_be_bytes());
+    header.extend_from_slice(&mtime.as_secs().to_be_bytes());
+    header.extend_from_slice(&mtime.subsec_nanos().to_be_bytes());
+    header
+}
+
+/// Writes to the stream, counted and at the allowed rate.
+struct Writer<'a> {
+    send: &'a mut SendStream,
+    counter: &'a mut StreamCounter,
+    limiter: Option<Arc<TokenBucket>>,
+    share: &'a Share,
+}
+
+impl Writer<'_> {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(limiter) = &self.limiter {
+            limiter.take(data.len()).await;
+        }
+        self.share.write_all(self.send, data).await?;
+        timers::touch();
+        self.counter.add(data.len());
+        Ok(())
+    }
+}
+
+/// Sends the tree under `root` and finishes the stream.
+pub async fn send(
+    root: &Path,
+    send: &mut SendStream,
+    counter: &mut StreamCounter,
+    limiter: Option<Arc<TokenBucket>>,
+    share: &Share,
+) -> Result<(), Box<dyn Error>> {
+    let entries = walk(root).map_err(|e| format!("could not read {}: {}", root.display(), e))?;
+    let mut writer = Writer {
+        send,
+        counter,
+        limiter,
+        share,
+    };
+    writer.write(MAGIC).await?;
+    let (mut files, mut bytes) = (0, 0);
+    let mut chunk = vec![0; CHUNK];
+    for entry in &entries {
+        if entry.metadata.is_dir() {
+            writer.write(&header(DIRECTORY, entry)).await?;
+            continue;
+        }
+        let mut file = tokio::fs::File::open(&entry.path)
+            .await
+            .map_err(|e| format!("could not open {}: {}", entry.path.display(), e))?;
+        // the size announced is what gets sent, even if the file changes meanwhile
+        let size = entry.metadata.len();
+        let mut frame = header(FILE, entry);
+        frame.extend_from_slice(&size.to_be_bytes());
+        writer.write(&frame).await?;
+        let mut left = size;
+        while left > 0 {
+            let len = left.min(CHUNK as u64) as usize;
+            let read = file.read(&mut chunk[..len]).await?;
+            if read == 0 {
+                return Err(format!("{} shrank while being sent", entry.path.display()).into());
+            }
+            writer.write(&chunk[..read]).await?;
+            left -= read as u64;
+        }
+        debug!("[archive] sent {} ({} bytes)", entry.relative, size);
+        files += 1;
+        bytes += size;
+    }
+    writer.write(&[END]).await?;
+    writer.send.finish().await?;
+    info!("[archive] sent {} files, {} bytes", files, bytes);
+    Ok(())
+}
+
+/// Reads from the stream, failing if it ends early.
+struct Reader<'a> {
+    recv: &'a mut RecvStream,
+    counter: &'a mut StreamCounter,
+}
+
+impl Reader<'_> {
+    async fn exact(&mut self, len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = vec![0; len];
+        self.recv
+            .read_exact(&mut buffer)
+            .await
+            .map_err(|e| -> Box<dyn Error> {
+                match e {
+                    ReadExactError::FinishedEarly => "the transfer was cut short".into(),
+                    ReadExactError::ReadError(e) => e.into(),
+                }
+            })?;
+        timers::touch();
+        self.counter.add(len);
+        Ok(buffer)
+    }
+
+    async fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_be_bytes(self.exact(2).await?.try_into().unwrap()))
+    }
+
+    async fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_be_bytes(self.exact(4).await?.try_into().unwrap()))
+    }
+
+    async fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_be_bytes(self.exact(8).await?.try_into().unwrap()))
+    }
+}
+
+/// Where a path received from the peer goes under `root`, refusing anything that could land
+/// outside of it, including through a symbolic link.
+fn local_path(root: &Path, path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let mut local = root.to_path_buf();
+    for name in path.split('/') {
+        match Path::new(name).components().collect::<Vec<_>>()[..] {
+            [Component::Normal(_)] => local.push(name),
+            _ => return Err(format!("refusing to write to '{}'", path).into()),
+        }
+        if fs::symlink_metadata(&local).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(format!("refusing to write to '{}' through a symbolic link", path).into());
+        }
+    }
+    Ok(local)
+}
+
+/// Receives a tree into `root`, creating it if needed, and replacing files already there only if
+/// `overwrite` is set.
+pub async fn recv(
+    root: &Path,
+    recv: &mut RecvStream,
+    counter: &mut StreamCounter,
+    overwrite: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = Reader { recv, counter };
+    if reader.exact(MAGIC.len()).await? != MAGIC {
+        return Err("the peer isn't sending a directory (--send-dir)".into());
+    }
+    fs::create_dir_all(root).map_err(|e| format!("could not create {}: {}", root.display(), e))?;
+    let mut directories = Vec::new();
+    let (mut files, mut bytes) = (0, 0);
+    loop {
+        let kind = reader.exact(1).await?[0];
+        if kind == END {
+            break;
+        }
+        let len = reader.u16().await? as usize;
+        let path = String::from_utf8(reader.exact(len).await?)
+            .map_err(|_| "the peer sent a path that isn't valid UTF-8")?;
+        let local = local_path(root, &path)?;
+        // permission bits only, the peer doesn't get to make setuid or setgid files
+        let mode = reader.u32().await? & 0o777;
+        let (secs, nanos) = (reader.u64().await?, reader.u32().await?);
+        let mtime = (nanos < 1_000_000_000)
+            .then(|| UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+            .flatten()
+            .ok_or_else(|| format!("the peer sent an invalid mtime for '{}'", path))?;
+        match kind {
+            DIRECTORY => {
+                fs::create_dir_all(&local)
+                    .map_err(|e| format!("could not create {}: {}", local.display(), e))?;
+                directories.push((local, mode, mtime));
+            }
+            FILE => {
+                let size = reader.u64().await?;
+                if !overwrite && fs::symlink_metadata(&local).is_ok() {
+                    return Err(format!(
+                        "{} already exists (use --overwrite to replace it)",
+                        local.display()
+                    )
+                    .into());
+                }
+                let mut part = local.clone().into_os_string();
+                part.push(PART_SUFFIX);
+                let part = PathBuf::from(part);
+                let received = recv_file(&mut reader, &part, size, mode, mtime).await;
+                if let Err(e) = received.and_then(|()| Ok(fs::rename(&part, &local)?)) {
+                    let _ = fs::remove_file(&part);
+                    return Err(format!("could not write {}: {}", local.display(), e).into());
+                }
+                debug!("[archive] received {} ({} bytes)", path, size);
+                files += 1;
+                bytes += size;
+            }
+            kind => return Err(format!("unknown entry kind {} from the peer", kind).into()),
+        }
+    }
+    // innermost first, so setting a parent's time isn't undone by its children
+    for (path, mode, mtime) in directories.iter().rev() {
+        if let Err(e) = set_mtime(path, *mtime).and_then(|()| set_mode(path, *mode)) {
+            warn!(
+                "[archive] could not set the permissions or time of {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    info!(
+        "[archive] received {} files, {} bytes into {}",
+        files,
+        bytes,
+        root.display()
+    );
+    Ok(())
+}
+
+async fn recv_file(
+    reader: &mut Reader<'_>,
+    part: &Path,
+    size: u64,
+    mode: u32,
+    mtime: SystemTime,
+) -> Result<(), Box<dyn Error>> {
+    // a leftover from an interrupted transfer is replaced, but never followed if it's a link
+    match fs::remove_file(part) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(part)
+        .await?;
+    let mut left = size;
+    while left > 0 {
+        let chunk = reader.exact(left.min(CHUNK as u64) as usize).await?;
+        file.write_all(&chunk).await?;
+        left -= chunk.len() as u64;
+    }
+    file.flush().await?;
+    file.into_std().await.set_modified(mtime)?;
+    set_mode(part, mode)?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Shared secret authentication (`--auth-token SECRET`), independent of TLS.
+//!
+//! With self-signed certificates anyone can complete the handshake, so a listener on a public
+//! port would serve whoever finds it. With `--auth-token`, the first message a client sends on
+//! its first stream is an authentication frame, and the listener closes connections whose frame
+//! is missing or wrong with [`AUTH_FAILED`], before any data goes either way.
+//!
+//! Frame format: `NQA1` magic, then the HMAC-SHA256 of keying material exported from the
+//! connection's TLS session, keyed with the secret. The secret itself never goes over the wire,
+//! and since the keying material differs for every TLS session, a frame can't be replayed on
+//! another connection, nor relayed by a man in the middle holding its own session with each side.
+
+use std::{error::Error, time::Duration};
+
+use quinn::{Connection, ConnectionError, RecvStream, SendStream, VarInt};
+use ring::hmac;
+use tracing::warn;
+
+/// Application error code used to close connections that failed authentication.
+pub const AUTH_FAILED: u32 = 10;
+
+const MAGIC: &[u8; 4] = b"NQA1";
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-nesquic-auth";
+const TAG_LEN: usize = 32;
+/// How long a client has to send its frame.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks a secret given on the command line.
+pub fn parse_secret(secret: &str) -> Result<String, String> {
+    if secret.is_empty() {
+        return Err("the secret can't be empty".into());
+    }
+    Ok(secret.to_string())
+}
+
+fn key(secret: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+/// Keying material both ends of `conn` agree on, and nobody else knows.
+fn keying_material(conn: &Connection) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut material = [0u8; 32];
+    conn.export_keying_material(&mut material, EXPORTER_LABEL, &[])
+        .map_err(|_| "could not export keying material from the TLS session")?;
+    Ok(material)
+}
+
+/// Sends the authentication frame, if there's a secret.
+pub async fn send(
+    conn: &Connection,
+    send: &mut SendStream,
+    secret: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(secret) = secret else {
+        return Ok(());
+    };
+    let mut frame = MAGIC.to_vec();
+    frame.extend_from_slice(hmac::sign(&key(secret), &keying_material(conn)?).as_ref());
+    send.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Reads and checks the client's authentication frame if there's a secret, closing the
+/// connection with [`AUTH_FAILED`] if it doesn't hold up. Returns whether the client may go on.
+pub async fn verify(conn: &Connection, recv: &mut RecvStream, secret: Option<&str>) -> bool {
+    let Some(secret) = secret else {
+        return true;
+    };
+    let mut frame = [0u8; MAGIC.len() + TAG_LEN];
+    let read = tokio::time::timeout(AUTH_TIMEOUT, recv.read_exact(&mut frame)).await;
+    let valid = match read {
+        Ok(Ok(())) if &frame[..MAGIC.len()] == MAGIC => {
+            keying_material(conn).is_ok_and(|material| {
+                hmac::verify(&key(secret), &material, &frame[MAGIC.len()..]).is_ok()
+            })
+        }
+        _ => false,
+    };
+    if !valid {
+        warn!("{} failed to authenticate", conn.remote_address());
+        conn.close(VarInt::from_u32(AUTH_FAILED), b"authentication failed");
+    }
+    valid
+}
+
+/// Whether the peer closed `conn` because we failed to authenticate.
+pub fn rejected(conn: &Connection) -> bool {
+    matches!(
+        conn.close_reason(),
+        Some(ConnectionError::ApplicationClosed(close))
+            if close.error_code == VarInt::from_u32(AUTH_FAILED)
+    )
+}
@@ -0,0 +1,114 @@
+//! Where a session's data comes from and goes to: stdin and stdout, or a Unix domain socket
+//! (`--unix-socket PATH`).
+//!
+//! With `--unix-socket`, nesquic connects to the socket at PATH when the session starts, on
+//! either side of the connection, and uses it both ways instead of stdin and stdout: what's read
+//! from it is sent, and what's received is written to it. This bridges a daemon speaking over a
+//! Unix socket to the peer. Once the peer finishes sending, the socket is shut down for writing so
+//! the daemon sees EOF, and once the daemon shuts down its side, ours is finished.
+
+use std::{
+    fs::File,
+    io::{self, IoSlice, Read, Stdout, Write},
+};
+#[cfg(unix)]
+use std::{net::Shutdown, os::unix::net::UnixStream};
+
+#[cfg(unix)]
+use tracing::info;
+
+use crate::Cli;
+
+/// Source and sink of a session's data.
+pub struct Backend {
+    pub input: Box<dyn Read + Send>,
+    pub output: Output,
+}
+
+/// Whether sessions use stdin and stdout, rather than `--unix-socket`.
+pub fn uses_stdio(args: &Cli) -> bool {
+    #[cfg(unix)]
+    return args.unix_socket.is_none();
+    #[cfg(not(unix))]
+    {
+        let _ = args;
+        true
+    }
+}
+
+/// Connects to `--unix-socket` if given, or else uses stdin and stdout.
+pub fn open(args: &Cli) -> io::Result<Backend> {
+    #[cfg(unix)]
+    if let Some(path) = &args.unix_socket {
+        let socket = UnixStream::connect(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not connect to {}: {}", path.display(), e),
+            )
+        })?;
+        info!("[unix] connected to {}", path.display());
+        return Ok(Backend {
+            input: Box::new(socket.try_clone()?),
+            output: Output::Socket(socket),
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = args;
+    Ok(Backend {
+        input: Box::new(io::stdin()),
+        output: Output::stdout(),
+    })
+}
+
+/// Where received data is written.
+pub enum Output {
+    Stdout(Stdout),
+    /// The file a transfer resumes into (`--resume`).
+    File(File),
+    #[cfg(unix)]
+    Socket(UnixStream),
+}
+
+impl Output {
+    pub fn stdout() -> Self {
+        Output::Stdout(io::stdout())
+    }
+
+    /// Tells the reader of the output that nothing more is coming, if it can be told.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(_) | Output::File(_) => Ok(()),
+            #[cfg(unix)]
+            Output::Socket(socket) => socket.shutdown(Shutdown::Write),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.write(buf),
+            #[cfg(unix)]
+            Output::Socket(socket) => socket.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write_vectored(bufs),
+            Output::File(file) => file.write_vectored(bufs),
+            #[cfg(unix)]
+            Output::Socket(socket) => socket.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.flush(),
+            #[cfg(unix)]
+            Output::Socket(socket) => socket.flush(),
+        }
+    }
+}
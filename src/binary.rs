@@ -0,0 +1,80 @@
+//! Guard against dumping binary data on a terminal (`--force-binary`).
+//!
+//! When stdout is a terminal, received data that looks binary (compressed archives, NUL bytes,
+//! invalid UTF-8, lots of control characters) is refused instead of being written out, since it
+//! can garble the terminal. Text goes through, escape sequences such as colors included: this
+//! isn't a filter for what a peer may make the terminal do. Redirecting stdout or passing
+//! `--force-binary` disables the check.
+
+use std::{
+    io::{stdout, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tracing::error;
+
+use crate::Cli;
+
+/// Whether received data is checked before being written to stdout.
+static GUARD: AtomicBool = AtomicBool::new(false);
+
+/// Leading bytes of common compressed formats.
+const MAGICS: &[(&[u8], &str)] = &[
+    (&[0x1f, 0x8b], "gzip"),
+    (&[0x28, 0xb5, 0x2f, 0xfd], "zstd"),
+    (&[0x04, 0x22, 0x4d, 0x18], "lz4"),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], "xz"),
+    (b"BZh", "bzip2"),
+    (b"PK\x03\x04", "zip"),
+    (b"7z\xbc\xaf\x27\x1c", "7z"),
+];
+
+/// Turns the guard on if stdout is a terminal and `--force-binary` wasn't given.
+pub fn init(args: &Cli) {
+    GUARD.store(
+        !args.force_binary && crate::backend::uses_stdio(args) && stdout().is_terminal(),
+        Ordering::Relaxed,
+    );
+}
+
+/// Checks whether `data` may be written to stdout, logging why not if it may not.
+pub fn allow(data: &[u8]) -> bool {
+    if !GUARD.load(Ordering::Relaxed) {
+        return true;
+    }
+    match detect(data) {
+        Some(kind) => {
+            error!(
+                "received {}, refusing to write it to the terminal \
+                 (redirect stdout or pass --force-binary)",
+                kind
+            );
+            false
+        }
+        None => true,
+    }
+}
+
+/// Describes `data` if it doesn't look like text.
+fn detect(data: &[u8]) -> Option<String> {
+    if let Some((_, format)) = MAGICS.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return Some(format!("{}-compressed data", format));
+    }
+    if data.contains(&0) {
+        return Some("binary data".into());
+    }
+    if let Err(e) = std::str::from_utf8(data) {
+        // a multi-byte character may be split across chunks
+        if e.error_len().is_some() {
+            return Some("binary data".into());
+        }
+    }
+    let control = data
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x1b | 0x07 | 0x08))
+        .count();
+    if control * 10 > data.len() {
+        return Some("binary data".into());
+    }
+    None
+}
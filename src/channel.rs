@@ -0,0 +1,401 @@
+//! Numbered channels multiplexed over one connection (`--channel`, `--fd-map`).
+//!
+//! The first bidirectional stream carries stdin/stdout as usual (channel 0). Every other stream
+//! starts with a header naming its channel, and is attached to whatever is mapped to that channel
+//! locally: a path to read from (`--channel 1=<in.fifo`), a path to write to
+//! (`--channel 1=>out.fifo`) or, on Unix, an inherited file descriptor (`--fd-map 3=ch1`), which
+//! is read from and/or written to depending on how it was opened.
+//!
+//! The connecting side opens one stream per channel it has a mapping for; the listener runs a
+//! stream-accept loop and looks incoming channels up in its [`Registry`]. Streams for channels
+//! that aren't mapped are stopped with [`UNKNOWN_CHANNEL`].
+//!
+//! Streams can also carry additional sessions instead of a channel, opened by a `--mux` master on
+//! behalf of an attached invocation. The listener writes their data to stdout alongside the main
+//! session's; its stdin stays with the main session, so their sending side is finished right away.
+//!
+//! A `--ping` client opens one more stream for its probes, which the listener echoes back whole,
+//! header included (see [`crate::ping`]).
+//!
+//! Header format: `NQCH` magic, channel number as big-endian u16; `NQSS` magic for a session; or
+//! `NQPG` magic for probes.
+
+use std::{collections::BTreeMap, error::Error, path::PathBuf, sync::Arc};
+
+use quinn::{Connection, RecvStream, SendStream, VarInt};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, warn};
+
+use crate::backend::Output;
+use crate::counters::StreamCounter;
+use crate::report::{self, Direction};
+use crate::sched::{self, Scheduler, Share};
+use crate::{ping, tasks, timers, Cli};
+
+const HEADER_MAGIC: &[u8; 4] = b"NQCH";
+const SESSION_MAGIC: &[u8; 4] = b"NQSS";
+const PING_MAGIC: &[u8; 4] = b"NQPG";
+/// Application error code used to stop streams for channels that aren't mapped.
+pub const UNKNOWN_CHANNEL: u32 = 2;
+
+/// What a channel is attached to locally.
+#[derive(Clone, Debug)]
+pub enum ChannelSpec {
+    /// Send the contents of this path.
+    Read(PathBuf),
+    /// Write received data to this path.
+    Write(PathBuf),
+    /// Inherited file descriptor, used in the directions it was opened for.
+    #[cfg(unix)]
+    Fd(std::os::unix::io::RawFd),
+}
+
+/// Parses `--channel N=<PATH` or `--channel N=>PATH`.
+pub fn parse_channel(spec: &str) -> Result<(u16, ChannelSpec), String> {
+    let (id, target) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected N=<PATH or N=>PATH, got '{}'", spec))?;
+    let id = parse_id(id)?;
+    if let Some(path) = target.strip_prefix('<') {
+        Ok((id, ChannelSpec::Read(path.into())))
+    } else if let Some(path) = target.strip_prefix('>') {
+        Ok((id, ChannelSpec::Write(path.into())))
+    } else {
+        Err(format!(
+            "prefix the path with '<' to send it or '>' to write to it, got '{}'",
+            target
+        ))
+    }
+}
+
+/// Parses `--fd-map FD=chN`.
+#[cfg(unix)]
+pub fn parse_fd_map(spec: &str) -> Result<(u16, ChannelSpec), String> {
+    let (fd, channel) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected FD=chN, got '{}'", spec))?;
+    let fd = fd
+        .parse()
+        .map_err(|_| format!("invalid file descriptor '{}'", fd))?;
+    let id = channel
+        .strip_prefix("ch")
+        .ok_or_else(|| format!("expected chN, got '{}'", channel))?;
+    Ok((parse_id(id)?, ChannelSpec::Fd(fd)))
+}
+
+/// Fails if a file descriptor is mapped to more than one channel, or twice to the same one.
+#[cfg(unix)]
+pub fn check_fd_maps(args: &Cli) -> Result<(), String> {
+    let mut mapped = std::collections::BTreeSet::new();
+    for (_, spec) in &args.fd_map {
+        if let ChannelSpec::Fd(fd) = spec {
+            if !mapped.insert(*fd) {
+                return Err(format!("file descriptor {} is mapped more than once", fd));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_id(id: &str) -> Result<u16, String> {
+    match id.parse::<u16>() {
+        Ok(0) => Err("channel 0 is stdin/stdout, use 1 to 65535".into()),
+        Ok(id) => Ok(id),
+        Err(_) => Err(format!("invalid channel number '{}'", id)),
+    }
+}
+
+/// Local mappings of channel numbers.
+#[derive(Default)]
+pub struct Registry {
+    channels: BTreeMap<u16, Vec<ChannelSpec>>,
+    weights: BTreeMap<u16, u32>,
+    priorities: BTreeMap<u16, i32>,
+}
+
+impl Registry {
+    pub fn from_args(args: &Cli) -> Self {
+        let mut registry = Registry::default();
+        #[cfg(unix)]
+        let fd_maps = args.fd_map.iter();
+        #[cfg(not(unix))]
+        let fd_maps = std::iter::empty();
+        for (id, spec) in args.channel.iter().chain(fd_maps) {
+            registry.channels.entry(*id).or_default().push(spec.clone());
+        }
+        registry.weights.extend(args.weight.iter().copied());
+        registry.priorities.extend(args.priority.iter().copied());
+        registry
+    }
+}
+
+/// Channels of one connection and the tasks pumping their data.
+pub struct Channels {
+    registry: Arc<Registry>,
+    tracker: TaskTracker,
+    scheduler: Arc<Scheduler>,
+}
+
+impl Channels {
+    pub fn new(registry: Arc<Registry>) -> Self {
+        Channels {
+            registry,
+            tracker: TaskTracker::new(),
+            scheduler: Arc::default(),
+        }
+    }
+
+    /// The connection's send capacity share of channel `id`, 0 being the session.
+    pub fn share(&self, id: u16) -> Share {
+        let weight = self.registry.weights.get(&id).copied().unwrap_or(1);
+        let priority = self.registry.priorities.get(&id).copied().unwrap_or(0);
+        self.scheduler.share(weight, priority)
+    }
+
+    /// Opens a stream for every mapped channel (connecting side).
+    pub async fn open_all(&self, conn: &Connection) -> Result<(), Box<dyn Error>> {
+        for (&id, specs) in &self.registry.channels {
+            let (mut send, recv) = timers::open_bi(conn).await?;
+            let mut header = HEADER_MAGIC.to_vec();
+            header.extend_from_slice(&id.to_be_bytes());
+            send.write_all(&header).await?;
+            info!("opened channel {}", id);
+            self.attach(conn, id, specs.clone(), send, recv);
+        }
+        Ok(())
+    }
+
+    /// Accepts the streams the peer opens and attaches them to their channel (listening side).
+    pub fn accept_all(&self, conn: Connection) {
+        let registry = self.registry.clone();
+        let channels = Channels {
+            registry: registry.clone(),
+            tracker: self.tracker.clone(),
+            scheduler: self.scheduler.clone(),
+        };
+        tasks::spawn(async move {
+            while let Ok((send, mut recv)) = conn.accept_bi().await {
+                let id = match read_header(&mut recv).await {
+                    Ok(Header::Channel(id)) => id,
+                    Ok(Header::Session) => {
+                        info!("peer opened an additional session");
+                        channels.attach_session(&conn, send, recv);
+                        continue;
+                    }
+                    Ok(Header::Ping) => {
+                        info!("peer opened a ping stream");
+                        channels.tracker.spawn(async move {
+                            let mut send = send;
+                            if send.write_all(PING_MAGIC).await.is_ok() {
+                                ping::echo(send, recv).await;
+                            }
+                        });
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("rejecting stream: {}", e);
+                        let _ = recv.stop(VarInt::from_u32(UNKNOWN_CHANNEL));
+                        continue;
+                    }
+                };
+                match registry.channels.get(&id) {
+                    Some(specs) => {
+                        info!("peer opened channel {}", id);
+                        channels.attach(&conn, id, specs.clone(), send, recv);
+                    }
+                    None => {
+                        warn!("peer opened channel {}, which isn't mapped", id);
+                        let _ = recv.stop(VarInt::from_u32(UNKNOWN_CHANNEL));
+                    }
+                }
+            }
+        });
+    }
+
+    /// Waits until the data of all attached channels was transferred.
+    pub async fn wait(&self) {
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+
+    /// Spawns the task writing an additional session's data to stdout.
+    fn attach_session(&self, conn: &Connection, mut send: SendStream, recv: RecvStream) {
+        report::stream_open(recv.id(), "session", None);
+        let counter = StreamCounter::new(conn, recv.id(), Direction::Received);
+        self.tracker.spawn(async move {
+            let _ = send.finish().await;
+            let _ = crate::recv_data(recv, Output::stdout(), counter, None, None, None).await;
+        });
+    }
+
+    /// Spawns the pumps between a channel's stream and its local mappings.
+    fn attach(
+        &self,
+        conn: &Connection,
+        id: u16,
+        specs: Vec<ChannelSpec>,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) {
+        report::stream_open(send.id(), "channel", Some(id));
+        let share = self.share(id);
+        share.prioritize(&send);
+        let mut sent = StreamCounter::new(conn, send.id(), Direction::Sent);
+        let mut received = StreamCounter::new(conn, recv.id(), Direction::Received);
+        self.tracker.spawn(async move {
+            let (source, sink) = match open_specs(&specs).await {
+                Ok(ends) => ends,
+                Err(e) => {
+                    error!("channel {}: {}", id, e);
+                    let _ = recv.stop(VarInt::from_u32(UNKNOWN_CHANNEL));
+                    let _ = send.finish().await;
+                    return;
+                }
+            };
+            let expired = {
+                let outgoing = async {
+                    if let Some(mut source) = source {
+                        match sched::pump(&mut source, &mut send, &share, &mut sent).await {
+                            Ok(()) => sent.finish(),
+                            Err(e) => error!("channel {}: sending failed: {}", id, e),
+                        }
+                    }
+                    let _ = send.finish().await;
+                };
+                let incoming = async {
+                    match sink {
+                        Some(mut sink) => match pump(&mut recv, &mut sink, &mut received).await {
+                            Ok(()) => received.finish(),
+                            Err(e) => error!("channel {}: receiving failed: {}", id, e),
+                        },
+                        None => {
+                            let _ = recv.stop(VarInt::from_u32(0));
+                        }
+                    }
+                };
+                tokio::select! {
+                    _ = async { tokio::join!(outgoing, incoming) } => false,
+                    _ = timers::stream_expiry() => true,
+                }
+            };
+            if expired {
+                timers::expire_stream(&mut send, &mut recv);
+            }
+            debug!("channel {} finished", id);
+        });
+    }
+}
+
+type Source = Box<dyn AsyncRead + Unpin + Send>;
+type Sink = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Opens the local ends of a channel.
+async fn open_specs(
+    specs: &[ChannelSpec],
+) -> Result<(Option<Source>, Option<Sink>), Box<dyn Error + Send + Sync>> {
+    let mut source: Option<Source> = None;
+    let mut sink: Option<Sink> = None;
+    for spec in specs {
+        match spec {
+            ChannelSpec::Read(path) => source = Some(Box::new(File::open(path).await?)),
+            ChannelSpec::Write(path) => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+                    .await?;
+                sink = Some(Box::new(file));
+            }
+            #[cfg(unix)]
+            ChannelSpec::Fd(fd) => {
+                use std::os::unix::io::BorrowedFd;
+
+                let mode = unsafe { libc::fcntl(*fd, libc::F_GETFL) };
+                if mode == -1 {
+                    return Err(format!("file descriptor {} is not open", fd).into());
+                }
+                // SAFETY: fcntl just found the descriptor open, and nothing closes it: each
+                // session works on a duplicate of its own
+                let inherited = unsafe { BorrowedFd::borrow_raw(*fd) };
+                let file = std::fs::File::from(inherited.try_clone_to_owned()?);
+                match mode & libc::O_ACCMODE {
+                    libc::O_RDONLY => source = Some(Box::new(File::from_std(file))),
+                    libc::O_WRONLY => sink = Some(Box::new(File::from_std(file))),
+                    _ => {
+                        let clone = file.try_clone()?;
+                        source = Some(Box::new(File::from_std(file)));
+                        sink = Some(Box::new(File::from_std(clone)));
+                    }
+                }
+            }
+        }
+    }
+    Ok((source, sink))
+}
+
+/// Opens a stream for an additional session (connecting side).
+pub async fn open_session(conn: &Connection) -> Result<(SendStream, RecvStream), Box<dyn Error>> {
+    let (mut send, recv) = timers::open_bi(conn).await?;
+    send.write_all(SESSION_MAGIC).await?;
+    Ok((send, recv))
+}
+
+/// Opens the stream carrying `--ping` probes (connecting side).
+pub async fn open_ping(conn: &Connection) -> Result<(SendStream, RecvStream), Box<dyn Error>> {
+    let (mut send, recv) = timers::open_bi(conn).await?;
+    send.write_all(PING_MAGIC).await?;
+    Ok((send, recv))
+}
+
+/// What an incoming stream carries.
+enum Header {
+    Channel(u16),
+    Session,
+    Ping,
+}
+
+/// Reads the header of a stream opened by the peer.
+async fn read_header(recv: &mut RecvStream) -> Result<Header, Box<dyn Error>> {
+    let mut magic = [0u8; 4];
+    recv.read_exact(&mut magic).await?;
+    if &magic == SESSION_MAGIC {
+        return Ok(Header::Session);
+    }
+    if &magic == PING_MAGIC {
+        return Ok(Header::Ping);
+    }
+    if &magic != HEADER_MAGIC {
+        return Err("not a nesquic channel header".into());
+    }
+    let mut id = [0u8; 2];
+    recv.read_exact(&mut id).await?;
+    Ok(Header::Channel(u16::from_be_bytes(id)))
+}
+
+/// Copies `from` into `to` until EOF, counting the bytes copied on `counter`.
+pub async fn pump<R, W>(
+    from: &mut R,
+    to: &mut W,
+    counter: &mut StreamCounter,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = from.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        to.write_all(&buffer[..n]).await?;
+        timers::touch();
+        counter.add(n);
+    }
+    to.flush().await
+}
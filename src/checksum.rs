@@ -0,0 +1,174 @@
+//! End-to-end payload checksums (`--checksum sha256`).
+//!
+//! Each side hashes the session data it sends and, once its sending side is finished, sends the
+//! byte count and digest on a unidirectional stream. The receiving side hashes what it receives
+//! and, once the data is complete, compares it with the peer's. A mismatch, or no digest at all,
+//! closes the connection and exits with [`CHECKSUM_MISMATCH`]. Both sides need the flag; when
+//! the peer's hello (see [`crate::version`]) says it doesn't use it, no digest is sent or waited
+//! for, and the data goes unchecked.
+//!
+//! Digest stream format: `NQCK` magic, byte count as big-endian u64, digest.
+
+use std::{error::Error, time::Duration};
+
+use clap::ValueEnum;
+use quinn::Connection;
+use ring::digest::{Context, SHA256};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::control::{self, Magic};
+use crate::{timers, version, Cli};
+
+/// Exit code, and application error code, when the received data doesn't match the digest.
+pub const CHECKSUM_MISMATCH: i32 = 7;
+pub const MAGIC: &Magic = b"NQCK";
+/// How long the digest may take to arrive after the data, or to be accepted by the peer.
+const DIGEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Algorithm {
+    Sha256,
+}
+
+/// Running digest of one direction of a session.
+struct Digest {
+    context: Context,
+    bytes: u64,
+}
+
+impl Digest {
+    fn new(algorithm: Algorithm) -> Self {
+        let algorithm = match algorithm {
+            Algorithm::Sha256 => &SHA256,
+        };
+        Digest {
+            context: Context::new(algorithm),
+            bytes: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.context.update(data);
+        self.bytes += data.len() as u64;
+    }
+
+    fn finish(self) -> (u64, Vec<u8>) {
+        (self.bytes, self.context.finish().as_ref().to_vec())
+    }
+}
+
+/// Hashes the data sent and sends the digest at the end.
+pub struct Sender {
+    conn: Connection,
+    peer: version::Peer,
+    digest: Digest,
+}
+
+/// Hashes the data received and checks it against the peer's digest at the end.
+pub struct Verifier {
+    conn: Connection,
+    peer: version::Peer,
+    digest: Digest,
+}
+
+/// Checksumming for both directions of a session, if `--checksum` was given.
+pub fn for_session(conn: &Connection, args: &Cli) -> (Option<Sender>, Option<Verifier>) {
+    let Some(algorithm) = args.checksum else {
+        return (None, None);
+    };
+    let sender = Sender {
+        conn: conn.clone(),
+        peer: version::peer(conn),
+        digest: Digest::new(algorithm),
+    };
+    let verifier = Verifier {
+        conn: conn.clone(),
+        peer: version::peer(conn),
+        digest: Digest::new(algorithm),
+    };
+    (Some(sender), Some(verifier))
+}
+
+impl Sender {
+    pub fn update(&mut self, data: &[u8]) {
+        self.digest.update(data);
+    }
+
+    pub async fn finish(self) -> Result<(), Box<dyn Error>> {
+        let (bytes, digest) = self.digest.finish();
+        if !self.peer.uses(version::Feature::Checksum).await {
+            warn!("not sending a checksum, the peer doesn't use --checksum");
+            return Ok(());
+        }
+        let mut send = timeout(DIGEST_TIMEOUT, self.conn.open_uni())
+            .await
+            .map_err(|_| "the peer doesn't accept a checksum, does it use --checksum too?")??;
+        let mut message = MAGIC.to_vec();
+        message.extend_from_slice(&bytes.to_be_bytes());
+        message.extend_from_slice(&digest);
+        send.write_all(&message).await?;
+        send.finish().await?;
+        info!("sent checksum of {} bytes: {}", bytes, hex(&digest));
+        Ok(())
+    }
+}
+
+impl Verifier {
+    pub fn update(&mut self, data: &[u8]) {
+        self.digest.update(data);
+    }
+
+    /// Compares the digests, closing the connection and exiting if they don't match.
+    pub async fn verify(self) {
+        let (bytes, digest) = self.digest.finish();
+        if !self.peer.uses(version::Feature::Checksum).await {
+            warn!(
+                "{} bytes received without a checksum to verify them against, the peer doesn't \
+                 use --checksum",
+                bytes
+            );
+            return;
+        }
+        let expected = match timeout(DIGEST_TIMEOUT, receive(&self.conn)).await {
+            Ok(Ok(expected)) => Ok(expected),
+            Ok(Err(e)) => Err(format!("could not receive checksum: {}", e)),
+            Err(_) => Err("no checksum from the peer, does it use --checksum too?".to_string()),
+        };
+        let expected = match expected {
+            Ok(expected) => expected,
+            Err(reason) => mismatch(reason).await,
+        };
+        if expected != (bytes, digest.clone()) {
+            mismatch(format!(
+                "checksum mismatch: received {} bytes hashing to {}, the peer sent {} bytes \
+                 hashing to {}",
+                bytes,
+                hex(&digest),
+                expected.0,
+                hex(&expected.1)
+            ))
+            .await;
+        }
+        info!("checksum verified for {} bytes: {}", bytes, hex(&digest));
+    }
+}
+
+async fn receive(conn: &Connection) -> Result<(u64, Vec<u8>), Box<dyn Error>> {
+    let mut recv = control::accept(conn, MAGIC).await?;
+    let rest = recv.read_to_end(1024).await?;
+    if rest.len() < 8 {
+        return Err("truncated checksum".into());
+    }
+    let (bytes, digest) = rest.split_at(8);
+    Ok((u64::from_be_bytes(bytes.try_into()?), digest.to_vec()))
+}
+
+async fn mismatch(reason: impl AsRef<str>) -> ! {
+    let reason = reason.as_ref();
+    timers::expire(CHECKSUM_MISMATCH, reason, &format!("{}, closing", reason)).await
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,112 @@
+//! Coalescing of received data before it's written out (`--min-read`, `--max-latency`), and of
+//! stdin reads before they're sent (`--coalesce-send`).
+//!
+//! By default received data is written to stdout as soon as it arrives, whatever has arrived since
+//! the last write at once (see [`crate::output`]), in however small pieces the network delivers
+//! it. Downstream consumers that prefer fewer, larger writes can have data
+//! held back until at least `--min-read` bytes are pending, or until the oldest pending byte has
+//! waited for `--max-latency`, whichever comes first. Whatever is pending is written out when the
+//! stream ends.
+//!
+//! Likewise, every stdin read is sent right away by default (`--no-delay`), which for chatty
+//! line traffic means many small stream writes and packets. With `--coalesce-send`, reads
+//! arriving within that window of the first one are batched into a single write, much like
+//! Nagle's algorithm. Interactive mode always sends every line right away.
+
+use std::{
+    mem,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::Cli;
+
+#[derive(Clone, Copy, Default)]
+struct Thresholds {
+    min_read: Option<usize>,
+    max_latency: Option<Duration>,
+    send_window: Option<Duration>,
+}
+
+static THRESHOLDS: OnceLock<Thresholds> = OnceLock::new();
+
+pub fn init(args: &Cli) {
+    let _ = THRESHOLDS.set(Thresholds {
+        min_read: args.min_read,
+        max_latency: args.max_latency,
+        send_window: if args.no_delay || args.interactive {
+            None
+        } else {
+            args.coalesce_send
+        },
+    });
+}
+
+/// How long to wait for more stdin reads to send along with the first, if at all.
+pub fn send_window() -> Option<Duration> {
+    THRESHOLDS.get()?.send_window
+}
+
+/// Received data waiting to be written out, each piece with the offset it's written at.
+pub struct Coalescer {
+    thresholds: Thresholds,
+    pending: Vec<(u64, Bytes)>,
+    /// Bytes pending.
+    len: usize,
+    /// When the oldest pending byte arrived.
+    since: Option<Instant>,
+}
+
+impl Default for Coalescer {
+    /// Coalescer following the thresholds given on the command line.
+    fn default() -> Self {
+        Coalescer {
+            thresholds: THRESHOLDS.get().copied().unwrap_or_default(),
+            pending: Vec::new(),
+            len: 0,
+            since: None,
+        }
+    }
+}
+
+impl Coalescer {
+    fn enabled(&self) -> bool {
+        self.thresholds.min_read.is_some() || self.thresholds.max_latency.is_some()
+    }
+
+    /// Holds `data` back until it's written out along with the rest of what's pending.
+    pub fn push(&mut self, offset: u64, data: Bytes) {
+        if data.is_empty() {
+            return;
+        }
+        self.len += data.len();
+        self.pending.push((offset, data));
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    /// Bytes pending.
+    pub fn pending(&self) -> usize {
+        self.len
+    }
+
+    /// Whether what's pending is to be written out now, rather than once more arrives.
+    pub fn due(&self) -> bool {
+        let filled = self.thresholds.min_read.is_some_and(|min| self.len >= min);
+        let expired = self.deadline().is_some_and(|at| Instant::now() >= at);
+        !self.enabled() || filled || expired
+    }
+
+    /// When the pending data must be written out even if more could arrive.
+    pub fn deadline(&self) -> Option<Instant> {
+        Some(self.since? + self.thresholds.max_latency?)
+    }
+
+    /// Takes whatever is pending, to be written out.
+    pub fn take(&mut self) -> Vec<(u64, Bytes)> {
+        self.since = None;
+        self.len = 0;
+        mem::take(&mut self.pending)
+    }
+}
@@ -0,0 +1,179 @@
+//! Transparent compression of the session data (`--compress zstd|lz4`).
+//!
+//! The codec is negotiated through ALPN (`nesquic-zstd`, `nesquic-lz4`), so a peer that doesn't
+//! use the same one is caught right after the handshake and the connection is closed with
+//! [`COMPRESSION_MISMATCH`], instead of garbage ending up on stdout.
+//!
+//! zstd data is one stream, flushed after every chunk read from stdin so interactive use isn't
+//! held up. lz4 chunks are compressed as independent blocks, each preceded by its compressed
+//! length as a big-endian u32.
+
+use std::{
+    error::Error,
+    io::{self, Write},
+};
+
+use clap::ValueEnum;
+use quinn::{Connection, VarInt};
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+use crate::Cli;
+
+/// Application error code used to close connections with a peer using another codec.
+pub const COMPRESSION_MISMATCH: u32 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn alpn(self) -> &'static str {
+        match self {
+            Compression::Zstd => "nesquic-zstd",
+            Compression::Lz4 => "nesquic-lz4",
+        }
+    }
+}
+
+/// Makes sure the peer negotiated the same codec, closing the connection if not.
+pub fn check(conn: &Connection, args: &Cli) -> Result<(), Box<dyn Error>> {
+    let Some(compression) = args.compress else {
+        return Ok(());
+    };
+    if crate::negotiated_alpn(conn).as_deref() == Some(compression.alpn()) {
+        return Ok(());
+    }
+    conn.close(
+        VarInt::from_u32(COMPRESSION_MISMATCH),
+        b"compression mismatch",
+    );
+    Err(format!(
+        "the peer doesn't use --compress {}",
+        compression.to_possible_value().unwrap().get_name()
+    )
+    .into())
+}
+
+/// Codecs for both directions of a session, if `--compress` was given.
+pub fn for_session(args: &Cli) -> io::Result<(Option<Encoder>, Option<Decoder>)> {
+    match args.compress {
+        Some(compression) => Ok((
+            Some(Encoder::new(compression)?),
+            Some(Decoder::new(compression)?),
+        )),
+        None => Ok((None, None)),
+    }
+}
+
+pub enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Lz4,
+}
+
+impl Encoder {
+    fn new(compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?),
+            Compression::Lz4 => Encoder::Lz4,
+        })
+    }
+
+    /// Compresses a chunk, returning what can be sent right away.
+    pub fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Encoder::Lz4 => {
+                let block = lz4_flex::compress_prepend_size(data);
+                let mut frame = (block.len() as u32).to_be_bytes().to_vec();
+                frame.extend_from_slice(&block);
+                Ok(frame)
+            }
+        }
+    }
+
+    /// Ends the compressed data, returning whatever is left to send.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Lz4 => Ok(Vec::new()),
+        }
+    }
+}
+
+pub enum Decoder {
+    Zstd {
+        decoder: zstd::stream::raw::Decoder<'static>,
+        /// Whether the data so far ends with a complete frame.
+        complete: bool,
+    },
+    /// Received bytes of a block that isn't complete yet.
+    Lz4(Vec<u8>),
+}
+
+impl Decoder {
+    fn new(compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::Zstd => Decoder::Zstd {
+                decoder: zstd::stream::raw::Decoder::new()?,
+                complete: true,
+            },
+            Compression::Lz4 => Decoder::Lz4(Vec::new()),
+        })
+    }
+
+    /// Decompresses received data, returning what's decoded so far.
+    pub fn decode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Zstd { decoder, complete } => {
+                let mut input = InBuffer::around(data);
+                let mut decoded = Vec::new();
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    let mut output = OutBuffer::around(&mut buffer[..]);
+                    *complete = decoder.run(&mut input, &mut output)? == 0;
+                    let full = output.pos() == output.capacity();
+                    decoded.extend_from_slice(output.as_slice());
+                    if input.pos() == data.len() && !full {
+                        return Ok(decoded);
+                    }
+                }
+            }
+            Decoder::Lz4(pending) => {
+                pending.extend_from_slice(data);
+                let mut decoded = Vec::new();
+                while pending.len() >= 4 {
+                    let len = u32::from_be_bytes(pending[..4].try_into().unwrap()) as usize;
+                    if pending.len() < 4 + len {
+                        break;
+                    }
+                    let block = lz4_flex::decompress_size_prepended(&pending[4..4 + len])
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    decoded.extend_from_slice(&block);
+                    pending.drain(..4 + len);
+                }
+                Ok(decoded)
+            }
+        }
+    }
+
+    /// Checks that the compressed data didn't end halfway.
+    pub fn finish(self) -> io::Result<()> {
+        let truncated = match self {
+            Decoder::Zstd { complete, .. } => !complete,
+            Decoder::Lz4(pending) => !pending.is_empty(),
+        };
+        if truncated {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "compressed data ended halfway",
+            ));
+        }
+        Ok(())
+    }
+}
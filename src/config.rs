@@ -0,0 +1,223 @@
+//! Config file and named profiles (`--config`, `nesquic @NAME`).
+//!
+//! The config file (`~/.config/nesquic.toml` unless `--config` says otherwise) holds defaults for
+//! any long option at the top level, and named profiles in `[profile.NAME]` tables, with the
+//! address under `addr`:
+//!
+//! ```toml
+//! alpn = ["nq"]
+//!
+//! [profile.work]
+//! addr = ["203.0.113.7", "5003"]
+//! limit-rate = "5MiB/s"
+//! retry = 3
+//! ```
+//!
+//! Values are turned back into command line arguments, so they're validated exactly like flags.
+//! Flags given on the command line override the profile, which overrides the defaults.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{
+    parser::ValueSource, Arg, ArgAction, ArgMatches, Command, CommandFactory, ErrorKind,
+    FromArgMatches, Parser,
+};
+use toml::{Table, Value};
+
+use crate::messages::tr;
+use crate::plugin;
+use crate::Cli;
+
+/// Key holding the positional address in the config file.
+const ADDR_KEY: &str = "addr";
+const PROFILES_KEY: &str = "profile";
+
+/// Parses the command line, applying the config file defaults and the `@NAME` profile.
+pub fn parse_args() -> Cli {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let profile = take_profile(&mut args);
+    let mut command = Cli::command();
+    let plugin = plugin::take(&mut command, &mut args);
+
+    let matches = command
+        .try_get_matches_from_mut(&args)
+        .unwrap_or_else(|e| e.exit());
+    let explicit_path = matches.get_one::<PathBuf>("config").cloned();
+    let path = explicit_path.clone().or_else(default_path);
+
+    let table = match path.as_deref().map(load) {
+        Some(Ok(table)) => table,
+        Some(Err(e)) if explicit_path.is_some() || profile.is_some() => fail(e),
+        _ => Table::new(),
+    };
+    let mut settings = defaults(&table);
+    if let Some(name) = &profile {
+        let profile = table
+            .get(PROFILES_KEY)
+            .and_then(|profiles| profiles.get(name))
+            .and_then(Value::as_table)
+            .unwrap_or_else(|| fail(tr!(NoProfile, name)));
+        settings.extend(profile.clone());
+    }
+    let mut cli = if settings.is_empty() {
+        Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    } else {
+        let (options, addr) = to_args(&settings, &matches).unwrap_or_else(|e| fail(e));
+        let mut merged = vec![args[0].clone()];
+        merged.extend(options);
+        merged.extend(args.drain(1..));
+        merged.extend(addr);
+        Cli::parse_from(merged)
+    };
+    cli.plugin = plugin;
+    cli
+}
+
+/// Names of the profiles in the config file, none if it can't be read.
+pub fn profile_names(args: &Cli) -> Vec<String> {
+    let Some(Ok(table)) = args
+        .config
+        .clone()
+        .or_else(default_path)
+        .as_deref()
+        .map(load)
+    else {
+        return Vec::new();
+    };
+    table
+        .get(PROFILES_KEY)
+        .and_then(Value::as_table)
+        .map(|profiles| profiles.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Removes the `@NAME` argument, if any, and returns the name.
+fn take_profile(args: &mut Vec<OsString>) -> Option<String> {
+    let position = args
+        .iter()
+        .skip(1)
+        .position(|arg| arg.to_str().is_some_and(|arg| arg.starts_with('@')))?;
+    let arg = args.remove(position + 1);
+    Some(arg.to_string_lossy()[1..].to_string())
+}
+
+/// `$XDG_CONFIG_HOME/nesquic.toml`, `~/.config/nesquic.toml` or `%APPDATA%\nesquic.toml`.
+fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join("nesquic.toml"))
+}
+
+fn load(path: &Path) -> Result<Table, String> {
+    let text = fs::read_to_string(path).map_err(|e| tr!(ConfigUnreadable, path.display(), e))?;
+    text.parse()
+        .map_err(|e| tr!(ConfigInvalid, path.display(), e))
+}
+
+/// Top-level settings, which apply to every run.
+fn defaults(table: &Table) -> Table {
+    table
+        .iter()
+        .filter(|(key, _)| *key != PROFILES_KEY)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Turns settings into option arguments and address arguments, leaving out whatever was given
+/// on the command line.
+fn to_args(
+    settings: &Table,
+    matches: &ArgMatches,
+) -> Result<(Vec<OsString>, Vec<OsString>), String> {
+    let command = Cli::command();
+    let on_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut options = Vec::new();
+    let mut addr = Vec::new();
+    for (key, value) in settings {
+        if key == ADDR_KEY {
+            // the address doesn't apply to subcommands
+            if !on_command_line("addr") && matches.subcommand().is_none() {
+                addr = scalars(key, value)?;
+            }
+            continue;
+        }
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .ok_or_else(|| tr!(UnknownConfigOption, key))?;
+        // the command line also wins over settings it conflicts with, e.g. a default `send-only`
+        // when running with --recv-only
+        let conflicting = command.get_arguments().any(|other| {
+            on_command_line(other.get_id())
+                && (conflicts(&command, arg, other) || conflicts(&command, other, arg))
+        });
+        if on_command_line(arg.get_id()) || conflicting {
+            continue;
+        }
+        let flag = format!("--{}", key);
+        match value {
+            Value::Boolean(true) => options.push(flag.into()),
+            Value::Boolean(false) => {}
+            // counted flags such as `verbose = 2`
+            Value::Integer(count) if matches!(arg.get_action(), ArgAction::Count) => {
+                options.extend((0..*count).map(|_| OsString::from(&flag)));
+            }
+            value => {
+                for value in scalars(key, value)? {
+                    let mut option = OsString::from(format!("{}=", flag));
+                    option.push(value);
+                    options.push(option);
+                }
+            }
+        }
+    }
+    Ok((options, addr))
+}
+
+/// Whether `a` is declared to conflict with `b`.
+fn conflicts(command: &Command, a: &Arg, b: &Arg) -> bool {
+    command
+        .get_arg_conflicts_with(a)
+        .iter()
+        .any(|arg| arg.get_id() == b.get_id())
+}
+
+/// Turns the settings of a queued job into arguments, options first and then the address.
+pub fn job_args(settings: &Table) -> Result<Vec<OsString>, String> {
+    let matches = Cli::command()
+        .try_get_matches_from(["nesquic"])
+        .map_err(|e| e.to_string())?;
+    let (mut args, addr) = to_args(settings, &matches)?;
+    args.extend(addr);
+    Ok(args)
+}
+
+/// Values of a setting as arguments, one per array element.
+fn scalars(key: &str, value: &Value) -> Result<Vec<OsString>, String> {
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Ok(OsString::from(s)),
+        Value::Integer(i) => Ok(i.to_string().into()),
+        Value::Float(f) => Ok(f.to_string().into()),
+        _ => Err(format!(
+            "unsupported value for '{}' in the config file",
+            key
+        )),
+    };
+    match value {
+        Value::Array(values) => values.iter().map(scalar).collect(),
+        value => Ok(vec![scalar(value)?]),
+    }
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    Cli::command()
+        .error(ErrorKind::InvalidValue, message)
+        .exit()
+}
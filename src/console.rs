@@ -0,0 +1,32 @@
+//! Console quirks of the platforms nesquic runs on.
+//!
+//! Log messages are colored with escape sequences, which Windows consoles only understand once
+//! virtual terminal processing is turned on, and older ones not at all; they're left uncolored
+//! there, when stderr isn't a terminal, and when `NO_COLOR` is set. Lines typed at a Windows
+//! console end in CRLF, which [`crate::crlf`] takes care of.
+
+use std::{
+    env,
+    io::{stderr, stdin, IsTerminal},
+};
+
+/// Whether log messages on stderr are colored, turning colors on if the console needs it.
+pub fn colors() -> bool {
+    let disabled = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !disabled && stderr().is_terminal() && enable_escapes()
+}
+
+/// Whether stdin is a console whose lines end in CRLF.
+pub fn types_crlf() -> bool {
+    cfg!(windows) && stdin().is_terminal()
+}
+
+#[cfg(windows)]
+fn enable_escapes() -> bool {
+    nu_ansi_term::enable_ansi_support().is_ok()
+}
+
+#[cfg(not(windows))]
+fn enable_escapes() -> bool {
+    true
+}
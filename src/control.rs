@@ -0,0 +1,115 @@
+//! Unidirectional control streams of a session: the peer's hello (see [`crate::version`]) and
+//! its `--checksum` digest.
+//!
+//! Each kind of control stream starts with a magic of its own. A single task per connection
+//! accepts the peer's streams, reads their magic and hands each one to whoever waits for that
+//! magic, so they can arrive in any order and none is taken for another. Streams with a magic
+//! nobody knows are stopped.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use quinn::{Connection, ConnectionError, RecvStream, VarInt};
+use tokio::{sync::Notify, task::JoinSet, time::timeout};
+use tracing::debug;
+
+use crate::tasks;
+
+/// Magic every control stream starts with.
+pub type Magic = [u8; 4];
+
+/// Magics of the control streams, which the others are stopped for.
+const KNOWN: [&Magic; 2] = [crate::version::MAGIC, crate::checksum::MAGIC];
+
+/// How long the magic of a control stream may take to arrive once the stream is open.
+const MAGIC_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Streams {
+    /// Streams whose magic was read, waiting to be taken.
+    arrived: HashMap<Magic, RecvStream>,
+    /// Why the connection stopped taking streams, once it did.
+    closed: Option<ConnectionError>,
+}
+
+#[derive(Default)]
+struct Control {
+    streams: Mutex<Streams>,
+    arrival: Notify,
+}
+
+/// Control streams of the open connections, by stable id.
+static CONNECTIONS: Mutex<Option<HashMap<usize, Arc<Control>>>> = Mutex::new(None);
+
+/// Waits for the peer's control stream starting with `magic`, returning it with the magic read.
+pub async fn accept(conn: &Connection, magic: &Magic) -> Result<RecvStream, ConnectionError> {
+    let control = control(conn);
+    loop {
+        let arrival = control.arrival.notified();
+        {
+            let mut streams = control.streams.lock().unwrap();
+            if let Some(stream) = streams.arrived.remove(magic) {
+                return Ok(stream);
+            }
+            if let Some(e) = &streams.closed {
+                return Err(e.clone());
+            }
+        }
+        arrival.await;
+    }
+}
+
+/// Control streams of `conn`, accepted from now on if they weren't already.
+fn control(conn: &Connection) -> Arc<Control> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let connections = connections.get_or_insert_with(HashMap::new);
+    if let Some(control) = connections.get(&conn.stable_id()) {
+        return control.clone();
+    }
+    let control = Arc::new(Control::default());
+    connections.insert(conn.stable_id(), control.clone());
+    tasks::spawn(accept_all(conn.clone(), control.clone()));
+    control
+}
+
+async fn accept_all(conn: Connection, control: Arc<Control>) {
+    let mut sorting = JoinSet::new();
+    let closed = loop {
+        match conn.accept_uni().await {
+            Ok(stream) => {
+                sorting.spawn(sort(stream, control.clone()));
+            }
+            Err(e) => break e,
+        }
+    };
+    // streams that arrived before the connection closed can still be read
+    while sorting.join_next().await.is_some() {}
+    control.streams.lock().unwrap().closed = Some(closed);
+    control.arrival.notify_waiters();
+    if let Some(connections) = CONNECTIONS.lock().unwrap().as_mut() {
+        connections.remove(&conn.stable_id());
+    }
+}
+
+/// Reads the magic of `stream` and hands it over.
+async fn sort(mut stream: RecvStream, control: Arc<Control>) {
+    let mut magic = Magic::default();
+    match timeout(MAGIC_TIMEOUT, stream.read_exact(&mut magic)).await {
+        Ok(Ok(())) if KNOWN.contains(&&magic) => {
+            control
+                .streams
+                .lock()
+                .unwrap()
+                .arrived
+                .insert(magic, stream);
+            control.arrival.notify_waiters();
+        }
+        _ => {
+            debug!("stopping unidirectional stream {} of the peer", stream.id());
+            let _ = stream.stop(VarInt::from_u32(0));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Byte counters shared by everything that reports on transfers.
+//!
+//! Each direction of a stream is counted by a [`StreamCounter`] as the data goes through, which
+//! adds it to its connection's [`Traffic`] at the same time. The per-stream reports, the close
+//! summary and anything else reporting on a connection read these counters instead of keeping
+//! their own, so their numbers agree and are up to date while the transfer is still running.
+//!
+//! Totals over the whole run, for `--metrics`, are kept next to them.
+//!
+//! Counted bytes are application data as read from stdin or written to stdout, i.e. before
+//! compression and without QUIC and UDP overhead, which are in the connection statistics instead.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use quinn::{Connection, StreamId};
+use serde::Serialize;
+
+use crate::report::{self, Direction};
+use crate::tasks;
+
+/// The connections that are still open and their traffic, by their stable id.
+static CONNECTIONS: Mutex<BTreeMap<usize, (Connection, Arc<Traffic>)>> =
+    Mutex::new(BTreeMap::new());
+
+/// Connections tracked so far.
+static CONNECTIONS_TOTAL: AtomicU64 = AtomicU64::new(0);
+/// Bytes sent and received over every connection so far.
+static SENT_TOTAL: AtomicU64 = AtomicU64::new(0);
+static RECEIVED_TOTAL: AtomicU64 = AtomicU64::new(0);
+/// Streams with data being counted, by connection and stream id, with how many directions are.
+static STREAMS: Mutex<Option<HashMap<(usize, StreamId), u8>>> = Mutex::new(None);
+
+/// Traffic of the whole run so far.
+#[derive(Clone, Copy, Debug)]
+pub struct RunTotals {
+    pub connections: u64,
+    pub open_connections: usize,
+    pub open_streams: usize,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+}
+
+/// Bytes transferred over a connection's streams.
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+    started: Instant,
+}
+
+/// Snapshot of a connection's [`Traffic`].
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Totals {
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    pub seconds: f64,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Traffic {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    fn add(&self, direction: Direction, bytes: u64) {
+        let (counter, total) = match direction {
+            Direction::Sent => (&self.sent, &SENT_TOTAL),
+            Direction::Received => (&self.received, &RECEIVED_TOTAL),
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+        total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> Totals {
+        Totals {
+            sent_bytes: self.sent.load(Ordering::Relaxed),
+            received_bytes: self.received.load(Ordering::Relaxed),
+            seconds: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Starts counting the traffic of `conn`, until it's closed.
+pub fn track(conn: &Connection) -> Arc<Traffic> {
+    let id = conn.stable_id();
+    CONNECTIONS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    let traffic = CONNECTIONS
+        .lock()
+        .unwrap()
+        .entry(id)
+        .or_insert_with(|| (conn.clone(), Arc::new(Traffic::new())))
+        .1
+        .clone();
+    let conn = conn.clone();
+    tasks::spawn(async move {
+        conn.closed().await;
+        CONNECTIONS.lock().unwrap().remove(&id);
+    });
+    traffic
+}
+
+/// Traffic of `conn`; a connection that isn't tracked is counted on its own.
+fn traffic(conn: &Connection) -> Arc<Traffic> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .get(&conn.stable_id())
+        .map(|(_, traffic)| traffic.clone())
+        .unwrap_or_else(|| Arc::new(Traffic::new()))
+}
+
+/// The tracked connections that are still open, with their traffic so far.
+pub fn open() -> Vec<(Connection, Totals)> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(conn, traffic)| (conn.clone(), traffic.totals()))
+        .collect()
+}
+
+/// Streams of `conn` still transferring data, in order.
+pub fn streams(conn: &Connection) -> Vec<StreamId> {
+    let id = conn.stable_id();
+    let mut streams: Vec<_> = STREAMS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|streams| {
+            streams
+                .keys()
+                .filter(|(conn, _)| *conn == id)
+                .map(|&(_, stream)| stream)
+                .collect()
+        })
+        .unwrap_or_default();
+    streams.sort_by_key(|stream| stream.index());
+    streams.dedup();
+    streams
+}
+
+/// Traffic of the whole run so far.
+pub fn run_totals() -> RunTotals {
+    RunTotals {
+        connections: CONNECTIONS_TOTAL.load(Ordering::Relaxed),
+        open_connections: CONNECTIONS.lock().unwrap().len(),
+        open_streams: STREAMS.lock().unwrap().as_ref().map_or(0, HashMap::len),
+        sent_bytes: SENT_TOTAL.load(Ordering::Relaxed),
+        received_bytes: RECEIVED_TOTAL.load(Ordering::Relaxed),
+    }
+}
+
+/// Bytes transferred in one direction of a stream.
+pub struct StreamCounter {
+    conn: usize,
+    stream: StreamId,
+    direction: Direction,
+    bytes: u64,
+    started: Instant,
+    traffic: Arc<Traffic>,
+}
+
+impl StreamCounter {
+    pub fn new(conn: &Connection, stream: StreamId, direction: Direction) -> Self {
+        *STREAMS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .entry((conn.stable_id(), stream))
+            .or_default() += 1;
+        StreamCounter {
+            conn: conn.stable_id(),
+            stream,
+            direction,
+            bytes: 0,
+            started: Instant::now(),
+            traffic: traffic(conn),
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.traffic.add(self.direction, bytes as u64);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Reports how much went over the stream, once it's done.
+    pub fn finish(self) {
+        report::transferred(self.stream, self.direction, self.bytes, self.elapsed());
+    }
+}
+
+impl Drop for StreamCounter {
+    fn drop(&mut self) {
+        let mut streams = STREAMS.lock().unwrap();
+        let Some(streams) = streams.as_mut() else {
+            return;
+        };
+        let key = (self.conn, self.stream);
+        if let Some(directions) = streams.get_mut(&key) {
+            *directions -= 1;
+            if *directions == 0 {
+                streams.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Line ending translation (`-C/--crlf`, `--strip-cr`), for talking to line protocols such as
+//! SMTP, IRC or HTTP/1.x that expect CRLF, like traditional netcat.
+//!
+//! With `--crlf`, every LF sent gets a CR in front of it, unless it already has one. With
+//! `--strip-cr`, the CR of every CRLF received is dropped. Line endings split between two reads
+//! are translated all the same. Translation happens before compression and after decompression,
+//! and checksums cover the data as it's sent.
+//!
+//! Lines typed at a Windows console end in CRLF; unless `--crlf` is given, they're sent ending in
+//! LF alone, as if typed on any other system. Piped and redirected input is sent as it is.
+
+use crate::{console, Cli};
+
+/// Puts a CR before every LF of the data sent.
+#[derive(Default)]
+pub struct ToCrlf {
+    /// Whether the last byte translated was a CR.
+    after_cr: bool,
+}
+
+impl ToCrlf {
+    pub fn translate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut translated = Vec::with_capacity(data.len() + data.len() / 16);
+        for &byte in data {
+            if byte == b'\n' && !self.after_cr {
+                translated.push(b'\r');
+            }
+            translated.push(byte);
+            self.after_cr = byte == b'\r';
+        }
+        translated
+    }
+}
+
+/// Line ending translation of the data sent.
+pub enum Outgoing {
+    /// `--crlf`.
+    Crlf(ToCrlf),
+    /// CRLF to LF at the end of lines typed at a Windows console.
+    ConsoleLf,
+}
+
+impl Outgoing {
+    pub fn translate(&mut self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Outgoing::Crlf(to_crlf) => to_crlf.translate(data),
+            // the console hands over one line at a time
+            Outgoing::ConsoleLf => match data.strip_suffix(b"\r\n") {
+                Some(line) => [line, b"\n"].concat(),
+                None => data.to_vec(),
+            },
+        }
+    }
+}
+
+/// Drops the CR of every CRLF of the data received.
+#[derive(Default)]
+pub struct StripCr {
+    /// Whether the last read ended with a CR, which was held back.
+    held_cr: bool,
+}
+
+impl StripCr {
+    pub fn translate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut translated = Vec::with_capacity(data.len() + 1);
+        for &byte in data {
+            if self.held_cr && byte != b'\n' {
+                translated.push(b'\r');
+            }
+            self.held_cr = byte == b'\r';
+            if !self.held_cr {
+                translated.push(byte);
+            }
+        }
+        translated
+    }
+
+    /// What's held back once the stream ended: a CR that wasn't followed by anything.
+    pub fn finish(self) -> &'static [u8] {
+        if self.held_cr {
+            b"\r"
+        } else {
+            b""
+        }
+    }
+}
+
+/// Translators for the sending and the receiving side of a session, if asked for.
+pub fn for_session(args: &Cli) -> (Option<Outgoing>, Option<StripCr>) {
+    let outgoing = if args.crlf {
+        Some(Outgoing::Crlf(ToCrlf::default()))
+    } else if console::types_crlf() {
+        Some(Outgoing::ConsoleLf)
+    } else {
+        None
+    };
+    (outgoing, args.strip_cr.then(StripCr::default))
+}
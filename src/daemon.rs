@@ -0,0 +1,190 @@
+//! Running a listener as a service (`--daemon`).
+//!
+//! A daemon keeps serving clients concurrently instead of exiting after the first one, so it's only
+//! available for the modes that don't use stdin/stdout (`--echo`, `--discard`, `--relay` and
+//! `--serve-file`). It stays in the foreground, as service managers expect, and:
+//! - uses the UDP socket passed by systemd socket activation (`LISTEN_FDS`) instead of binding
+//!   its own, if there is one,
+//! - writes its PID to `--pidfile` and removes it when shutting down (SIGTERM/SIGINT, Ctrl+C on
+//!   Windows), after closing the connections it's serving,
+//! - reloads `--cert` and `--key` on SIGHUP, for certificate renewals; connections established
+//!   before keep the old certificate,
+//! - reports the traffic of the connections it's serving on SIGUSR1 (Ctrl+Break on Windows).
+//!
+//! Socket activation is only available on Unix.
+
+#[cfg(unix)]
+use std::{
+    env,
+    io::{self, ErrorKind},
+    net::UdpSocket,
+    os::fd::FromRawFd,
+};
+use std::{fs, future::Future, process, sync::Arc};
+
+use quinn::{Endpoint, VarInt};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+use crate::pending::Queue;
+use crate::signals::{self, Event, Signals};
+use crate::{auth, metrics, report, tasks, testpeer, util, Cli};
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Takes the UDP socket passed by systemd socket activation, if any.
+#[cfg(unix)]
+pub fn listen_socket() -> io::Result<Option<UdpSocket>> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    // the variables are meant for this process only, not for its children
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !for_us || count < 1 {
+        return Ok(None);
+    }
+    if count > 1 {
+        warn!(
+            "[daemon] systemd passed {} sockets, only the first one is used",
+            count
+        );
+    }
+    let fd = SD_LISTEN_FDS_START;
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `kind` and `len` are valid for writes of the sizes passed
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if kind != libc::SOCK_DGRAM {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "the socket passed by systemd isn't a UDP socket (ListenDatagram= is needed)",
+        ));
+    }
+    // SAFETY: systemd hands the descriptor over to this process, nothing else owns it
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    // SAFETY: plain fcntl calls on a descriptor we own
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+    }
+    info!(
+        "[daemon] using the socket passed by systemd, bound to {}",
+        socket.local_addr()?
+    );
+    Ok(Some(socket))
+}
+
+/// Runs `service` on `endpoint` as a service: with the pidfile written, reloading the certificate
+/// and reporting traffic when signaled, until the service ends or a shutdown signal is received.
+pub async fn run(endpoint: &Endpoint, args: &Cli, service: impl Future<Output = ()>) {
+    if let Some(path) = &args.pidfile {
+        if let Err(e) = fs::write(path, format!("{}\n", process::id())) {
+            error!("could not write pidfile {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+    let mut signals = match Signals::install() {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("could not install signal handlers: {}", e);
+            process::exit(1);
+        }
+    };
+    tokio::select! {
+        _ = service => {}
+        _ = handle_signals(endpoint, args, &mut signals) => {
+            info!("[daemon] shutting down");
+            endpoint.close(VarInt::from_u32(0), b"shutting down");
+            // give the close frames a chance to go out
+            let _ = tokio::time::timeout(signals::CLOSE_GRACE, endpoint.wait_idle()).await;
+        }
+    }
+    if let Some(path) = &args.pidfile {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Handles signals until asked to shut down.
+async fn handle_signals(endpoint: &Endpoint, args: &Cli, signals: &mut Signals) {
+    loop {
+        match signals.recv().await {
+            Event::Shutdown => return,
+            Event::DumpStats => report::snapshot(),
+            Event::Reload => reload(endpoint, args),
+        }
+    }
+}
+
+fn reload(endpoint: &Endpoint, args: &Cli) {
+    if args.cert.is_none() {
+        warn!("[daemon] asked to reload, but there's no --cert to reload");
+        return;
+    }
+    match util::configure_server(args) {
+        Ok((config, _)) => {
+            endpoint.set_server_config(Some(config));
+            info!("[daemon] reloaded the certificate");
+        }
+        Err(e) => error!("[daemon] could not reload the certificate: {}", e),
+    }
+}
+
+/// Serves test peer connections concurrently until the endpoint is closed.
+pub async fn serve_test_peer(endpoint: &Endpoint, mode: testpeer::Mode, args: &Cli) {
+    let mut queue = Queue::new(endpoint, args);
+    let warmup = args.warmup;
+    let secret = Arc::new(args.auth_token.clone());
+    let mut clients = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(connecting) = queue.accept() => {
+                let secret = secret.clone();
+                clients.spawn(async move {
+                    let conn = match connecting.await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            debug!("[daemon] handshake failed: {}", e);
+                            metrics::handshake_failed();
+                            return;
+                        }
+                    };
+                    report::connection(&conn);
+                    match conn.accept_bi().await {
+                        Ok((send, mut recv)) => {
+                            if auth::verify(&conn, &mut recv, secret.as_deref()).await {
+                                testpeer::serve(conn, send, recv, mode, warmup).await;
+                            }
+                        }
+                        Err(e) => {
+                            debug!("[daemon] connection closed before opening a stream: {}", e)
+                        }
+                    }
+                });
+            }
+            Some(joined) = clients.join_next() => {
+                tasks::output(joined, "[daemon] test peer");
+            }
+            else => break,
+        }
+    }
+}
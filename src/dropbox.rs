@@ -0,0 +1,201 @@
+//! Drop-box listener (`--session-dir DIR`, `--count N`): ingests what clients send, one file per
+//! connection.
+//!
+//! Instead of plumbing stdin/stdout, every client that connects is served on its own,
+//! concurrently, and what it sends on its session is written to `DIR/TIMESTAMP-PEER.bin`, e.g.
+//! `20241015T101500.123Z-203.0.113.7-50432.bin`. Once the client hung up, a metadata file is
+//! written next to it, `DIR/TIMESTAMP-PEER.json`, giving the peer, the bytes received, how long it
+//! took, why the connection closed and whether everything sent arrived; its presence means the
+//! data file is final. With `--count`, the listener exits once that many sessions were received
+//! in full. Clients send with `nesquic --send-only IP PORT < FILE`.
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use quinn::{Connecting, Connection, Endpoint, RecvStream};
+use serde::Serialize;
+use tokio::{fs::File, io::AsyncWriteExt, task::JoinSet};
+use tracing::{debug, error, info};
+
+use crate::counters::StreamCounter;
+use crate::pending::Queue;
+use crate::report::{self, Direction};
+use crate::{auth, metrics, tasks, timers, Cli};
+
+/// How long a client gets to hang up once it sent everything.
+const LINGER: Duration = Duration::from_secs(5);
+
+/// What the metadata file says about a session.
+#[derive(Serialize)]
+struct Metadata {
+    peer: SocketAddr,
+    started: String,
+    bytes: u64,
+    seconds: f64,
+    close_reason: String,
+    /// Whether the client finished its stream, i.e. the data file holds all it sent.
+    complete: bool,
+}
+
+/// Receives sessions into `dir` until `--count` were received in full, or the endpoint is
+/// closed.
+pub async fn serve(endpoint: &Endpoint, dir: &Path, args: &Cli) {
+    if let Err(e) = fs::create_dir_all(dir) {
+        error!("could not create {}: {}", dir.display(), e);
+        process::exit(1);
+    }
+    let mut queue = Queue::new(endpoint, args);
+    let secret = Arc::new(args.auth_token.clone());
+    let dir = Arc::new(dir.to_path_buf());
+    let mut clients = JoinSet::new();
+    let mut completed = 0;
+    loop {
+        let running = clients.len() as u32;
+        let wanted = args.count.is_none_or(|count| completed + running < count);
+        tokio::select! {
+            Some(connecting) = queue.accept(), if wanted => {
+                let dir = dir.clone();
+                let secret = secret.clone();
+                clients.spawn(async move { receive(connecting, &dir, secret.as_deref()).await });
+            }
+            Some(joined) = clients.join_next() => {
+                if tasks::output(joined, "[session-dir] client") == Some(true) {
+                    completed += 1;
+                }
+                if args.count == Some(completed) {
+                    info!("[session-dir] {} session(s) received", completed);
+                    break;
+                }
+            }
+            else => return,
+        }
+    }
+    endpoint.set_server_config(None);
+    let _ = tokio::time::timeout(LINGER, endpoint.wait_idle()).await;
+}
+
+/// Receives a client's session into `dir`, returning whether all of it arrived.
+async fn receive(connecting: Connecting, dir: &Path, secret: Option<&str>) -> bool {
+    let conn = match connecting.await {
+        Ok(conn) => conn,
+        Err(e) => {
+            debug!("[session-dir] handshake failed: {}", e);
+            metrics::handshake_failed();
+            return false;
+        }
+    };
+    report::connection(&conn);
+    let (mut send, mut recv) = match conn.accept_bi().await {
+        Ok(streams) => streams,
+        Err(e) => {
+            debug!(
+                "[session-dir] connection closed before opening a stream: {}",
+                e
+            );
+            return false;
+        }
+    };
+    if !auth::verify(&conn, &mut recv, secret).await {
+        return false;
+    }
+    // nothing is sent back
+    let _ = send.finish().await;
+    report::stream_open(recv.id(), "session-dir", None);
+
+    let stem = file_stem(conn.remote_address());
+    let data = dir.join(format!("{}.bin", stem));
+    let started = Utc::now().to_rfc3339();
+    let clock = Instant::now();
+    let mut counter = StreamCounter::new(&conn, recv.id(), Direction::Received);
+    let written = tokio::select! {
+        written = write(&mut recv, &data, &mut counter) => written,
+        _ = timers::stream_expiry() => {
+            timers::expire_stream(&mut send, &mut recv);
+            Err("stream expired".into())
+        }
+    };
+    let complete = match written {
+        Ok(()) => true,
+        Err(e) => {
+            error!(
+                "[session-dir] session from {} failed: {}",
+                conn.remote_address(),
+                e
+            );
+            false
+        }
+    };
+    let bytes = counter.bytes();
+    let seconds = clock.elapsed().as_secs_f64();
+    counter.finish();
+    let metadata = Metadata {
+        peer: conn.remote_address(),
+        started,
+        bytes,
+        seconds,
+        close_reason: close_reason(&conn).await,
+        complete,
+    };
+    let json = dir.join(format!("{}.json", stem));
+    let written = serde_json::to_vec_pretty(&metadata)
+        .map_err(|e| e.to_string())
+        .and_then(|json_text| fs::write(&json, json_text).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        error!("[session-dir] could not write {}: {}", json.display(), e);
+        return false;
+    }
+    info!(
+        "[session-dir] received {} bytes from {} into {}",
+        bytes,
+        conn.remote_address(),
+        data.display()
+    );
+    complete
+}
+
+/// `TIMESTAMP-PEER`, with the colons of IPv6 addresses replaced so it's a valid file name
+/// everywhere.
+fn file_stem(peer: SocketAddr) -> String {
+    format!(
+        "{}-{}-{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        peer.ip().to_string().replace(':', "_"),
+        peer.port()
+    )
+}
+
+/// Writes the stream to `path` until the client finishes it.
+async fn write(
+    recv: &mut RecvStream,
+    path: &Path,
+    counter: &mut StreamCounter,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::create(path)
+        .await
+        .map_err(|e| format!("could not create {}: {}", path.display(), e))?;
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+        file.write_all(&chunk.bytes).await?;
+        timers::touch();
+        counter.add(chunk.bytes.len());
+    }
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Why the connection closed, waiting a little for the client to hang up.
+async fn close_reason(conn: &Connection) -> String {
+    match tokio::time::timeout(LINGER, conn.closed()).await {
+        Ok(reason) => reason.to_string(),
+        Err(_) => {
+            conn.close(0u32.into(), b"done");
+            "the client didn't hang up".to_string()
+        }
+    }
+}
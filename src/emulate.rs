@@ -0,0 +1,253 @@
+//! Network emulation on the endpoint's socket (`--emulate SPEC`), for demos and for testing
+//! against a bad network without root or netem.
+//!
+//! The spec is a comma-separated list of impairments:
+//! - `delay=DURATION`: hold every packet back for this long,
+//! - `jitter=DURATION`: vary the delay by up to this much either way,
+//! - `loss=PERCENT`: drop this share of the packets,
+//! - `reorder=PERCENT`: let this share of the packets skip the delay, overtaking the ones before,
+//! - `rate=RATE`: deliver at most this many bytes per second, queuing up to [`MAX_BACKLOG`]'s
+//!   worth and dropping beyond,
+//! - `seed=N`: seed of the random choices, to reproduce a run.
+//!
+//! Only packets arriving at this end are impaired, so the delay adds to the round trip once; use
+//! `--emulate` on both ends to impair both directions. Everything above the socket, quinn
+//! included, runs as it would over a real network.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    io::{self, IoSliceMut},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use quinn::{
+    udp::{RecvMeta, Transmit, UdpState},
+    AsyncUdpSocket,
+};
+use tokio::time::{Instant, Sleep};
+
+use crate::util::{KernelSockets, SocketFactory};
+use crate::{rate, timers, Cli};
+
+/// Largest datagram batch read from the kernel at once.
+const RECV_BUFFER: usize = 64 * 1024;
+
+/// Longest queue in front of a `rate` limited link, in time to drain it.
+pub const MAX_BACKLOG: Duration = Duration::from_millis(200);
+
+/// How packets arriving at the socket are treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Emulation {
+    pub delay: Duration,
+    pub jitter: Duration,
+    /// Probability of dropping a packet, from 0 to 1.
+    pub loss: f64,
+    /// Probability of a packet skipping the delay, from 0 to 1.
+    pub reorder: f64,
+    /// Bytes per second, unlimited if `None`.
+    pub rate: Option<u64>,
+    pub seed: u64,
+}
+
+impl Emulation {
+    /// Parses a spec given on the command line, e.g. `loss=1%,delay=50ms`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut emulation = Emulation {
+            seed: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(1, |now| now.as_nanos() as u64),
+            ..Emulation::default()
+        };
+        for setting in spec.split(',').map(str::trim) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", setting))?;
+            match name.trim() {
+                "delay" => emulation.delay = timers::parse_duration(value.trim())?,
+                "jitter" => emulation.jitter = timers::parse_duration(value.trim())?,
+                "loss" => emulation.loss = parse_percent(value)?,
+                "reorder" => emulation.reorder = parse_percent(value)?,
+                "rate" => emulation.rate = Some(rate::parse_limit(value.trim())?),
+                "seed" => {
+                    emulation.seed = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid seed '{}'", value))?
+                }
+                name => {
+                    return Err(format!(
+                        "unknown impairment '{}', expected delay, jitter, loss, reorder, rate or \
+                         seed",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(emulation)
+    }
+
+    /// Impairs what `inner` receives.
+    fn wrap(&self, inner: Box<dyn AsyncUdpSocket>) -> Box<dyn AsyncUdpSocket> {
+        Box::new(EmulatedSocket {
+            inner,
+            emulation: *self,
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+                rng: self.seed | 1,
+                link_free: Instant::now(),
+            }),
+        })
+    }
+}
+
+/// Parses a share such as `1%` or `0.5%` into a probability.
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let value = value.trim();
+    let percent: f64 = value
+        .strip_suffix('%')
+        .and_then(|percent| percent.parse().ok())
+        .ok_or_else(|| format!("expected a percentage such as 1%, got '{}'", value))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("'{}' isn't between 0% and 100%", value));
+    }
+    Ok(percent / 100.0)
+}
+
+impl SocketFactory for Emulation {
+    fn bind(&self, addr: SocketAddr, args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(self.wrap(KernelSockets.bind(addr, args)?))
+    }
+
+    fn bind_v6_only(&self, addr: SocketAddr, args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(self.wrap(KernelSockets.bind_v6_only(addr, args)?))
+    }
+}
+
+struct EmulatedSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    emulation: Emulation,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Packets that arrived, with when they're delivered, in delivery order.
+    queue: VecDeque<(Instant, Vec<u8>, RecvMeta)>,
+    timer: Pin<Box<Sleep>>,
+    rng: u64,
+    /// When the `rate` limited link is done with the packets queued so far.
+    link_free: Instant,
+}
+
+impl State {
+    /// Uniformly distributed in [0, 1) (xorshift64).
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng as f64 / (u64::MAX as f64 + 1.0)
+    }
+
+    /// When a packet of `len` bytes arriving now is delivered, `None` if it's dropped.
+    fn schedule(&mut self, emulation: &Emulation, len: usize) -> Option<Instant> {
+        if emulation.loss > 0.0 && self.random() < emulation.loss {
+            return None;
+        }
+        let now = Instant::now();
+        let mut due = now;
+        if let Some(rate) = emulation.rate {
+            let start = self.link_free.max(now);
+            if start - now > MAX_BACKLOG {
+                return None;
+            }
+            self.link_free = start + Duration::from_secs_f64(len as f64 / rate.max(1) as f64);
+            due = self.link_free;
+        }
+        // only draw what's used, so adding an impairment doesn't change which packets are lost
+        if emulation.reorder > 0.0 && self.random() < emulation.reorder {
+            return Some(due);
+        }
+        let mut delay = emulation.delay.as_secs_f64();
+        if !emulation.jitter.is_zero() {
+            delay += emulation.jitter.as_secs_f64() * (2.0 * self.random() - 1.0);
+        }
+        due += Duration::from_secs_f64(delay.max(0.0));
+        Some(due)
+    }
+}
+
+impl fmt::Debug for EmulatedSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmulatedSocket")
+            .field("inner", &self.inner)
+            .field("emulation", &self.emulation)
+            .finish()
+    }
+}
+
+impl AsyncUdpSocket for EmulatedSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_send(state, cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        // take in everything that arrived, until the kernel has nothing more
+        loop {
+            let mut buffer = vec![0; RECV_BUFFER];
+            let mut arrived = [RecvMeta::default()];
+            match self
+                .inner
+                .poll_recv(cx, &mut [IoSliceMut::new(&mut buffer)], &mut arrived)
+            {
+                Poll::Ready(Ok(_)) => {
+                    let Some(due) = state.schedule(&self.emulation, arrived[0].len) else {
+                        continue;
+                    };
+                    buffer.truncate(arrived[0].len);
+                    let position = state.queue.partition_point(|(other, _, _)| *other <= due);
+                    state.queue.insert(position, (due, buffer, arrived[0]));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => break,
+            }
+        }
+        let Some(&(due, _, _)) = state.queue.front() else {
+            return Poll::Pending;
+        };
+        if due > Instant::now() {
+            state.timer.as_mut().reset(due);
+            if state.timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        let (_, data, arrived) = state.queue.pop_front().unwrap();
+        bufs[0][..data.len()].copy_from_slice(&data);
+        meta[0] = arrived;
+        Poll::Ready(Ok(1))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
@@ -0,0 +1,137 @@
+//! Escape commands typed in interactive mode (`--escape-char CHAR`), like ssh's `~` escapes.
+//!
+//! A line made of the escape character (`~` unless given) and one more character is a command to
+//! nesquic instead of data for the peer:
+//! - `~.` closes the connection
+//! - `~s` prints the statistics of the connection
+//! - `~r` moves the client to a new local UDP port, as `--rebind-every` does
+//! - `~#` lists the streams transferring data
+//! - `~?` lists the commands
+//!
+//! A line starting with the escape character twice is sent with one of them, and lines with any
+//! other command are sent as they are. `--escape-char none` turns escapes off.
+
+use quinn::{Connection, VarInt};
+use tracing::info;
+
+use crate::messages::tr;
+use crate::report::{self, Event};
+use crate::{counters, migrate, Cli};
+
+/// The escape character, if escapes are on.
+#[derive(Clone, Copy, Debug)]
+pub struct EscapeChar(Option<u8>);
+
+/// Parses `--escape-char`: a single ASCII character, or `none`.
+pub fn parse_escape_char(escape: &str) -> Result<EscapeChar, String> {
+    match escape.as_bytes() {
+        b"none" => Ok(EscapeChar(None)),
+        &[char] if char.is_ascii_graphic() => Ok(EscapeChar(Some(char))),
+        _ => Err(format!(
+            "expected a single character or 'none', got '{}'",
+            escape
+        )),
+    }
+}
+
+/// What a line typed in interactive mode stands for.
+pub enum Line {
+    /// Data for the peer.
+    Send(Vec<u8>),
+    Command(Command),
+}
+
+pub enum Command {
+    Close,
+    Stats,
+    Rebind,
+    Streams,
+    Help,
+}
+
+/// Recognizes the escape commands of one connection.
+pub struct Escapes {
+    escape: u8,
+    conn: Connection,
+}
+
+impl Escapes {
+    /// Escapes for the interactive session over `conn`, unless turned off.
+    pub fn new(conn: &Connection, args: &Cli) -> Option<Self> {
+        let escape = args.escape_char.0?;
+        Some(Escapes {
+            escape,
+            conn: conn.clone(),
+        })
+    }
+
+    /// Tells commands apart from data, taking an escaped escape character off the line.
+    pub fn parse(&self, mut line: Vec<u8>) -> Line {
+        let (Some(&first), Some(&second)) = (line.first(), line.get(1)) else {
+            return Line::Send(line);
+        };
+        if first != self.escape {
+            return Line::Send(line);
+        }
+        if second == self.escape {
+            line.remove(0);
+            return Line::Send(line);
+        }
+        let rest = &line[2..];
+        if !rest.iter().all(|byte| matches!(byte, b'\r' | b'\n')) {
+            return Line::Send(line);
+        }
+        let command = match second {
+            b'.' => Command::Close,
+            b's' => Command::Stats,
+            b'r' => Command::Rebind,
+            b'#' => Command::Streams,
+            b'?' => Command::Help,
+            _ => return Line::Send(line),
+        };
+        Line::Command(command)
+    }
+
+    /// Runs a command, returning whether the session goes on.
+    pub async fn run(&self, command: Command) -> bool {
+        let conn = &self.conn;
+        match command {
+            Command::Close => {
+                info!("[escape] closing the connection");
+                conn.close(VarInt::from_u32(0), b"closed");
+                return false;
+            }
+            Command::Stats => match counters::open()
+                .into_iter()
+                .find(|(open, _)| open.stable_id() == conn.stable_id())
+            {
+                Some((_, totals)) => {
+                    let stats = conn.stats();
+                    report::emit(Event::Stats {
+                        peer: conn.remote_address(),
+                        seconds: totals.seconds,
+                        sent_bytes: totals.sent_bytes,
+                        received_bytes: totals.received_bytes,
+                        rtt_ms: conn.rtt().as_secs_f64() * 1000.0,
+                        sent_packets: stats.path.sent_packets,
+                        lost_packets: stats.path.lost_packets,
+                    });
+                }
+                None => report::snapshot(),
+            },
+            Command::Rebind => migrate::rebind_now(conn).await,
+            Command::Streams => report::emit(Event::Streams {
+                peer: conn.remote_address(),
+                streams: counters::streams(conn)
+                    .iter()
+                    .map(|stream| stream.index())
+                    .collect(),
+            }),
+            Command::Help => {
+                let e = char::from(self.escape);
+                eprintln!("{}", tr!(EscapeHelp, e, e, e, e, e, e, e, e));
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,67 @@
+//! Shell completion scripts and the man page (`nesquic completions SHELL`, `nesquic man`),
+//! generated from the clap definition so they can't fall behind the flags.
+//!
+//! In bash, zsh and fish, the scripts also complete `@NAME` with the profiles in the config file,
+//! by running `nesquic profiles` when completing, so new profiles don't need a new script.
+
+use std::io::{self, Write};
+
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+use crate::Cli;
+
+const BIN_NAME: &str = "nesquic";
+
+const BASH_PROFILES: &str = r#"
+_nesquic_profiles() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    if [[ "$cur" == @* ]]; then
+        COMPREPLY=($(compgen -W "$(nesquic profiles 2>/dev/null)" -- "$cur"))
+    else
+        _nesquic "$@"
+    fi
+}
+
+complete -F _nesquic_profiles -o bashdefault -o default nesquic
+"#;
+
+/// Replaces the call the generated zsh script ends with, so `_nesquic` becomes the entry point
+/// both when the script is sourced and when it's autoloaded from `$fpath`.
+const ZSH_PROFILES: &str = r#"_nesquic() {
+    if [[ $PREFIX == @* ]]; then
+        compadd -- ${(f)"$(nesquic profiles 2>/dev/null)"}
+    else
+        _nesquic_options "$@"
+    fi
+}
+
+_nesquic "$@"
+"#;
+
+const FISH_PROFILES: &str = r#"complete -c nesquic -n 'string match -q -- "@*" (commandline -ct)' -f -a '(nesquic profiles 2>/dev/null)'
+"#;
+
+/// Writes the completion script for `shell` to stdout.
+pub fn completions(shell: Shell) -> io::Result<()> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), BIN_NAME, &mut script);
+    let mut script = String::from_utf8_lossy(&script).into_owned();
+    match shell {
+        Shell::Bash => script.push_str(BASH_PROFILES),
+        Shell::Zsh => {
+            script = script.replacen("\n_nesquic() {", "\n_nesquic_options() {", 1);
+            if let Some(end) = script.rfind("_nesquic \"$@\"") {
+                script.replace_range(end.., ZSH_PROFILES);
+            }
+        }
+        Shell::Fish => script.push_str(FISH_PROFILES),
+        _ => {}
+    }
+    io::stdout().write_all(script.as_bytes())
+}
+
+/// Writes the man page, in roff, to stdout.
+pub fn man() -> io::Result<()> {
+    clap_mangen::Man::new(Cli::command()).render(&mut io::stdout())
+}
@@ -0,0 +1,147 @@
+//! Commands run on connection events (`--on-connect CMD`, `--on-close CMD`), to hook nesquic up to
+//! alerting or logging systems.
+//!
+//! The command is run by the shell (`sh -c` on Unix, `cmd /C` on Windows) in the background, with
+//! the details of the connection in its environment:
+//! - `NESQUIC_EVENT`: `connect` or `close`,
+//! - `NESQUIC_PEER`: the peer's address and port,
+//! - `NESQUIC_ALPN`: the negotiated protocol, if any,
+//! - on close, `NESQUIC_BYTES`, `NESQUIC_SENT_BYTES` and `NESQUIC_RECEIVED_BYTES`: the application
+//!   data transferred, both ways and in each direction, `NESQUIC_DURATION`: how long the
+//!   connection was up, in seconds, and `NESQUIC_CLOSE_REASON`.
+//!
+//! A connection's `--on-close` command only starts once its `--on-connect` one is done.
+//!
+//! Before nesquic exits, connections still open are closed so their `--on-close` command runs,
+//! and running commands get [`EXIT_WAIT`] to finish.
+
+use std::{
+    process::Stdio,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use quinn::{Connection, VarInt};
+use tokio::process::Command;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+use crate::counters::{self, Traffic};
+use crate::Cli;
+
+/// How long running commands are waited for when nesquic exits.
+const EXIT_WAIT: Duration = Duration::from_secs(10);
+
+static HOOKS: OnceLock<Hooks> = OnceLock::new();
+
+struct Hooks {
+    on_connect: Option<String>,
+    on_close: Option<String>,
+    /// Commands running, and connections waiting to run `on_close`.
+    running: TaskTracker,
+}
+
+/// Sets up the commands given on the command line.
+pub fn init(args: &Cli) {
+    if args.on_connect.is_none() && args.on_close.is_none() {
+        return;
+    }
+    let _ = HOOKS.set(Hooks {
+        on_connect: args.on_connect.clone(),
+        on_close: args.on_close.clone(),
+        running: TaskTracker::new(),
+    });
+}
+
+/// Runs the `--on-connect` command for an established connection, and the `--on-close` one
+/// once it's closed.
+pub fn connection(conn: &Connection, traffic: Arc<Traffic>) {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    let mut env = vec![("NESQUIC_PEER", conn.remote_address().to_string())];
+    if let Some(alpn) = crate::negotiated_alpn(conn) {
+        env.push(("NESQUIC_ALPN", alpn));
+    }
+    let on_connect = hooks.on_connect.clone().map(|command| {
+        let mut env = env.clone();
+        env.push(("NESQUIC_EVENT", "connect".to_string()));
+        run(command, env)
+    });
+    let Some(command) = hooks.on_close.clone() else {
+        if let Some(on_connect) = on_connect {
+            hooks.running.spawn(on_connect);
+        }
+        return;
+    };
+    let conn = conn.clone();
+    hooks.running.spawn(async move {
+        // a short connection's close command doesn't get ahead of its connect command
+        let closed = async {
+            let reason = conn.closed().await;
+            (reason, traffic.totals())
+        };
+        let connected = async {
+            if let Some(on_connect) = on_connect {
+                on_connect.await;
+            }
+        };
+        let (_, (reason, totals)) = tokio::join!(connected, closed);
+        env.extend([
+            ("NESQUIC_EVENT", "close".to_string()),
+            (
+                "NESQUIC_BYTES",
+                (totals.sent_bytes + totals.received_bytes).to_string(),
+            ),
+            ("NESQUIC_SENT_BYTES", totals.sent_bytes.to_string()),
+            ("NESQUIC_RECEIVED_BYTES", totals.received_bytes.to_string()),
+            ("NESQUIC_DURATION", format!("{:.3}", totals.seconds)),
+            ("NESQUIC_CLOSE_REASON", reason.to_string()),
+        ]);
+        run(command, env).await;
+    });
+}
+
+/// Closes the connections still open, so their `--on-close` command runs, and waits a while for
+/// the running commands. Called before exiting.
+pub async fn finish() {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    if hooks.on_close.is_some() {
+        for (conn, _) in counters::open() {
+            conn.close(VarInt::from_u32(0), b"done");
+        }
+    }
+    hooks.running.close();
+    if tokio::time::timeout(EXIT_WAIT, hooks.running.wait())
+        .await
+        .is_err()
+    {
+        warn!("[hook] exiting with commands still running");
+    }
+}
+
+async fn run(command: String, env: Vec<(&'static str, String)>) {
+    let mut child = shell(&command);
+    child.envs(env).stdin(Stdio::null());
+    match child.status().await {
+        Ok(status) if status.success() => debug!("[hook] {} succeeded", command),
+        Ok(status) => warn!("[hook] {} failed: {}", command, status),
+        Err(e) => warn!("[hook] could not run {}: {}", command, e),
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
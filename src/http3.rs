@@ -0,0 +1,88 @@
+//! Single HTTP/3 GET (`--http3 URL`), making nesquic a minimal h3 probe and downloader.
+//!
+//! The response body is written to stdout and the status line is reported on stderr with `-v`.
+//! As with any other connection, the server's certificate isn't verified. A status other than
+//! 2xx makes nesquic exit with an error once the body has been written out, like
+//! `curl --fail-with-body`.
+
+use std::{error::Error, future::poll_fn, time::Duration};
+
+use bytes::Buf;
+use http::{uri::Scheme, Request, Uri};
+use quinn::VarInt;
+use tokio::io::{stdout, AsyncWriteExt};
+use tracing::{debug, info};
+
+use crate::report::{self, Event};
+use crate::{binary, tap, timers, util::make_client_endpoint, Cli};
+
+/// ALPN protocol offered unless `--alpn` says otherwise.
+pub const ALPN: &str = "h3";
+const DEFAULT_PORT: u16 = 443;
+
+/// URL to fetch; only `https` URLs make sense over QUIC.
+pub fn parse_url(url: &str) -> Result<Uri, String> {
+    let uri: Uri = url.parse().map_err(|e| format!("invalid URL: {}", e))?;
+    if uri.scheme() != Some(&Scheme::HTTPS) {
+        return Err("only https:// URLs can be fetched over HTTP/3".into());
+    }
+    if uri.host().is_none() {
+        return Err("the URL has no host".into());
+    }
+    Ok(uri)
+}
+
+/// Fetches `uri` and writes the response body to stdout.
+pub async fn get(uri: &Uri, args: &Cli) -> Result<(), Box<dyn Error>> {
+    // parse_url made sure there is a host
+    let host = uri.host().unwrap_or_default();
+    let port = uri.port_u16().unwrap_or(DEFAULT_PORT);
+    // IPv6 literals come bracketed
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    let addr = tokio::net::lookup_host((name, port))
+        .await?
+        .next()
+        .ok_or_else(|| format!("no address found for {}", name))?;
+    let endpoint = make_client_endpoint(addr, args).await?;
+
+    let conn = timers::connect(endpoint.connect(addr, name)?, args.connect_timeout).await?;
+    report::connection(&conn);
+    info!("[http3] connected: addr={}", conn.remote_address());
+
+    let (mut driver, mut requests) =
+        h3::client::new(h3_quinn::Connection::new(conn.clone())).await?;
+    let driver = tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let mut stream = requests
+        .send_request(Request::get(uri.clone()).body(())?)
+        .await?;
+    stream.finish().await?;
+    let response = stream.recv_response().await?;
+    report::emit(Event::Response {
+        status: response.status().as_u16(),
+    });
+    debug!("[http3] response headers: {:?}", response.headers());
+
+    let mut out = stdout();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        let data = chunk.copy_to_bytes(chunk.remaining());
+        timers::touch();
+        tap::received(&data);
+        if !binary::allow(&data) {
+            return Err("refusing to write the response body".into());
+        }
+        out.write_all(&data).await?;
+    }
+    out.flush().await?;
+
+    drop(requests);
+    conn.close(VarInt::from_u32(0), b"done");
+    driver.abort();
+    // give the close frame a chance to go out
+    let _ = tokio::time::timeout(Duration::from_millis(100), endpoint.wait_idle()).await;
+
+    if !response.status().is_success() {
+        return Err(format!("server answered {}", response.status()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! Stdin for the sending side of a session, or whatever else it reads from (see
+//! [`crate::backend`]), read on its own thread into a bounded buffer (`--buffer SIZE`, 4MiB unless
+//! given).
+//!
+//! The reader stops reading once SIZE bytes are waiting to be sent, so a file or a fast pipe
+//! doesn't pile up in memory when the peer or the network is slower; the stream's flow control
+//! holds up the writer, which holds up the reader, which holds up whatever writes to stdin. With
+//! `--coalesce-send`, reads arriving within the window of the first one are sent together; with
+//! `-i`, reads are instead handed out a line at a time, so every line is paced on its own.
+//!
+//! Stdin is read `--chunk-size` bytes at a time (64KiB unless given), into memory allocated for
+//! [`RING_CHUNKS`] reads at once, and the reads are handed over to the stream as they are,
+//! without copying. Larger chunks mean fewer reads and writes on fast links; the stream side of
+//! the session reads received data in chunks of the same size, unless `--read-chunk-size` is
+//! given (see [`crate::output`]).
+//!
+//! Both kinds of waits are logged, so they can be told apart: the buffer filling up means the
+//! input is faster than the connection, and a write held up for [`STALL_REPORT`] means the peer
+//! isn't taking data as fast as it's offered (it reads slowly, or its receive window is too small
+//! for the round trip).
+
+use std::{
+    future::Future,
+    io::{self, Read},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, info};
+
+use crate::{coalesce, rate, Cli};
+
+/// Smallest `--chunk-size`, and the default.
+const MIN_CHUNK: usize = 64 * 1024;
+
+/// Largest `--chunk-size`.
+const MAX_CHUNK: usize = 16 * 1024 * 1024;
+
+/// Reads the memory for stdin is allocated for at a time.
+pub const RING_CHUNKS: usize = 4;
+
+/// Most bytes batched into a single send with `--coalesce-send`.
+const MAX_BATCH: usize = 64 * 1024;
+
+/// How long a write is held up before it's reported as a stall.
+pub const STALL_REPORT: Duration = Duration::from_secs(1);
+
+/// Bytes of stdin buffered when `--buffer` isn't given.
+pub const DEFAULT_BUFFER: u64 = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+struct Settings {
+    buffer: u64,
+    chunk: usize,
+    /// Whether reads are handed out one line at a time, for `-i`.
+    by_line: bool,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+const DEFAULT_SETTINGS: Settings = Settings {
+    buffer: DEFAULT_BUFFER,
+    chunk: MIN_CHUNK,
+    by_line: false,
+};
+
+pub fn init(args: &Cli) {
+    let _ = SETTINGS.set(Settings {
+        buffer: args.buffer.unwrap_or(DEFAULT_BUFFER),
+        chunk: args.chunk_size.unwrap_or(MIN_CHUNK),
+        by_line: args.interval.is_some(),
+    });
+}
+
+/// Most bytes read at once from stdin, and from the session's stream unless
+/// `--read-chunk-size` is given.
+pub fn chunk_size() -> usize {
+    SETTINGS.get().unwrap_or(&DEFAULT_SETTINGS).chunk
+}
+
+/// Parses a buffer size such as `4MiB` or `256KB` into bytes.
+pub fn parse_buffer(size: &str) -> Result<u64, String> {
+    if size.contains('/') || size.ends_with("ps") {
+        return Err(format!(
+            "'{}' is a rate, expected a size such as 4MiB",
+            size
+        ));
+    }
+    let bytes = rate::parse_limit(size)
+        .map_err(|_| format!("invalid size '{}', expected e.g. 4MiB or 256KB", size))?;
+    if bytes < MIN_CHUNK as u64 {
+        return Err(format!("'{}' is too small, use at least 64KiB", size));
+    }
+    Ok(bytes)
+}
+
+/// Parses a chunk size, from 64KiB to 16MiB.
+pub fn parse_chunk_size(size: &str) -> Result<usize, String> {
+    let bytes = parse_buffer(size)?;
+    if bytes > MAX_CHUNK as u64 {
+        return Err(format!("'{}' is too large, use at most 16MiB", size));
+    }
+    Ok(bytes as usize)
+}
+
+/// Stdin, read ahead into the buffer.
+pub struct Input {
+    reads: mpsc::Receiver<io::Result<Bytes>>,
+    /// `--coalesce-send` window, if reads are batched.
+    window: Option<Duration>,
+    by_line: bool,
+    /// What's left of a read after the line handed out.
+    rest: Option<Bytes>,
+}
+
+impl Input {
+    /// Starts reading stdin.
+    pub fn stdin() -> Self {
+        Self::new(io::stdin())
+    }
+
+    /// Starts reading `source`.
+    pub fn new(mut source: impl Read + Send + 'static) -> Self {
+        let Settings {
+            buffer,
+            chunk,
+            by_line,
+        } = *SETTINGS.get().unwrap_or(&DEFAULT_SETTINGS);
+        let (tx, reads) = mpsc::channel((buffer / chunk as u64).max(1) as usize);
+        thread::spawn(move || {
+            let mut ring = BytesMut::with_capacity(chunk * RING_CHUNKS);
+            loop {
+                // takes back the memory once the chunks read into it were sent, or else
+                // allocates more
+                if ring.capacity() < chunk {
+                    ring.reserve(chunk * RING_CHUNKS);
+                }
+                ring.resize(chunk, 0);
+                let read = match source.read(&mut ring[..]) {
+                    Ok(0) => break,
+                    Ok(length) => {
+                        ring.truncate(length);
+                        Ok(ring.split().freeze())
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = read.is_err();
+                let sent = match tx.try_send(read) {
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(read)) => {
+                        debug!("[send] buffer full, waiting for the stream before reading on");
+                        tx.blocking_send(read).map_err(drop)
+                    }
+                    Err(TrySendError::Closed(_)) => Err(()),
+                };
+                if failed || sent.is_err() {
+                    break;
+                }
+            }
+        });
+        Input {
+            reads,
+            // pacing lines and batching them contradict each other
+            window: coalesce::send_window().filter(|_| !by_line),
+            by_line,
+            rest: None,
+        }
+    }
+
+    /// Waits for the next read and, with `--coalesce-send`, whatever else was read within the
+    /// window. With `-i`, the next line or what's read of it instead. No chunks means EOF.
+    pub async fn next(&mut self) -> io::Result<Vec<Bytes>> {
+        let read = match self.rest.take() {
+            Some(rest) => Some(rest),
+            None => self.reads.recv().await.transpose()?,
+        };
+        let Some(mut first) = read else {
+            return Ok(Vec::new());
+        };
+        if self.by_line {
+            if let Some(end) = first.iter().position(|&byte| byte == b'\n') {
+                let line = first.split_to(end + 1);
+                self.rest = (!first.is_empty()).then_some(first);
+                return Ok(vec![line]);
+            }
+            return Ok(vec![first]);
+        }
+        let mut length = first.len();
+        let mut batch = vec![first];
+        let Some(window) = self.window else {
+            return Ok(batch);
+        };
+        let deadline = tokio::time::Instant::now() + window;
+        while length < MAX_BATCH {
+            match tokio::time::timeout_at(deadline, self.reads.recv()).await {
+                Ok(Some(read)) => {
+                    let read = read?;
+                    length += read.len();
+                    batch.push(read);
+                }
+                // at EOF, the next call returns nothing
+                Ok(None) | Err(_) => break,
+            }
+        }
+        Ok(batch)
+    }
+}
+
+/// Awaits a write to the stream, logging when it's held up for more than [`STALL_REPORT`] and
+/// when it goes through again.
+pub async fn watch_stall<T>(write: impl Future<Output = T>) -> T {
+    tokio::pin!(write);
+    tokio::select! {
+        written = &mut write => return written,
+        _ = tokio::time::sleep(STALL_REPORT) => {}
+    }
+    let since = Instant::now() - STALL_REPORT;
+    info!("[send] stalled: the peer isn't taking more data");
+    let written = write.await;
+    info!(
+        "[send] resumed after stalling for {:.1}s",
+        since.elapsed().as_secs_f64()
+    );
+    written
+}
@@ -0,0 +1,233 @@
+//! Line-oriented chat mode (`--interactive`).
+//!
+//! Stdin is read one line at a time on a dedicated thread (tokio's stdin can't be cancelled,
+//! which would hang shutdown until the user presses enter), and received data is printed line
+//! by line prefixed with the peer address. Both directions run independently: reaching EOF on
+//! stdin only finishes our send stream, and the peer closing its side only stops the receive
+//! loop.
+//!
+//! Lines typed as escape commands, such as `~.` to close the connection, are run instead of sent
+//! (see [`crate::escape`]).
+//!
+//! With `--latency`, the time from sending a line to receiving the next response line is printed
+//! to stderr for each request, pairing requests and responses in order.
+
+use std::{
+    collections::VecDeque,
+    io::{stdin, stdout, BufRead, IsTerminal, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use quinn::{ConnectionError, ReadError, RecvStream, SendStream, VarInt};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+use crate::counters::StreamCounter;
+use crate::crlf::{Outgoing, StripCr};
+use crate::escape::{Escapes, Line};
+use crate::messages::tr;
+use crate::rate::TokenBucket;
+use crate::report::{self, Event, LogFormat};
+use crate::sched::Share;
+use crate::Cli;
+use crate::{binary, tap, timers};
+
+/// Switches a client whose stdin is a terminal to interactive mode, so it shows what the peer
+/// sends right away instead of seemingly hanging while waiting for input to send.
+pub fn enable_for_terminal(args: &mut Cli) {
+    // one-way transfers don't exchange lines, even when typed in
+    let one_way = args.recv_only || args.send_only;
+    if args.interactive
+        || args.script_mode
+        || args.listen
+        || args.relay
+        || one_way
+        || !crate::backend::uses_stdio(args)
+        || !stdin().is_terminal()
+    {
+        return;
+    }
+    args.interactive = true;
+    if args.log_format == LogFormat::Text {
+        eprintln!("{}", tr!(InteractivePrompt));
+    }
+}
+
+/// Send times of lines still waiting for a response line.
+#[derive(Default)]
+pub struct Latency {
+    pending: Mutex<VecDeque<Instant>>,
+}
+
+impl Latency {
+    fn sent(&self) {
+        self.pending.lock().unwrap().push_back(Instant::now());
+    }
+
+    /// Prints the latency of the oldest unanswered line, if any.
+    fn received(&self) {
+        if let Some(sent) = self.pending.lock().unwrap().pop_front() {
+            report::emit(Event::Latency {
+                ms: sent.elapsed().as_secs_f64() * 1000.0,
+            });
+        }
+    }
+}
+
+/// Spawns a thread reading stdin line by line. The channel is closed on EOF.
+fn spawn_line_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(16);
+    thread::spawn(move || {
+        let stdin = stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            let mut line = Vec::new();
+            match stdin.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    if tx.blocking_send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("failed to read from stdin: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Sends stdin to the peer one line at a time until EOF or until the peer stops reading.
+pub async fn send_lines(
+    mut send: SendStream,
+    mut counter: StreamCounter,
+    limiter: Option<Arc<TokenBucket>>,
+    latency: Option<Arc<Latency>>,
+    share: Share,
+    mut crlf: Option<Outgoing>,
+    escapes: Option<Escapes>,
+) -> Result<(), ()> {
+    let mut lines = spawn_line_reader();
+    // a terminal already echoes what's typed, piped input is echoed so the transcript is complete
+    let echo = !stdin().is_terminal();
+
+    loop {
+        tokio::select! {
+            line = lines.recv() => {
+                let Some(mut line) = line else {
+                    break;
+                };
+                if let Some(escapes) = &escapes {
+                    match escapes.parse(line) {
+                        Line::Send(data) => line = data,
+                        Line::Command(command) => {
+                            if !escapes.run(command).await {
+                                return Ok(());
+                            }
+                            continue;
+                        }
+                    }
+                }
+                if let Some(crlf) = &mut crlf {
+                    line = crlf.translate(&line);
+                }
+                if let Some(limiter) = &limiter {
+                    limiter.take(line.len()).await;
+                }
+                if let Err(e) = share.write_all(&mut send, &line).await {
+                    error!("failed to send line: {}", e);
+                    return Err(());
+                }
+                if let Some(latency) = &latency {
+                    latency.sent();
+                }
+                timers::touch();
+                tap::sent(&line);
+                debug!("sent {} bytes", line.len());
+                counter.add(line.len());
+                if echo {
+                    let mut stdout = stdout().lock();
+                    let _ = stdout.write_all(b"[local] ");
+                    let _ = stdout.write_all(&line);
+                    let _ = stdout.flush();
+                }
+                timers::send_interval().await;
+            }
+            _ = send.stopped() => {
+                info!("peer stopped reading, no longer sending");
+                return Ok(());
+            }
+        }
+    }
+
+    info!("stdin closed, finishing send stream");
+    let _ = send.finish().await;
+    counter.finish();
+    Ok(())
+}
+
+/// Prints everything received from the peer line by line, each prefixed with `[peer]`.
+pub async fn recv_lines(
+    mut recv: RecvStream,
+    mut counter: StreamCounter,
+    peer: SocketAddr,
+    latency: Option<Arc<Latency>>,
+    mut strip_cr: Option<StripCr>,
+) -> Result<(), ()> {
+    let prefix = format!("[{}] ", peer);
+    let mut pending = Vec::new();
+    loop {
+        match recv.read_chunk(1024 * 1024, true).await {
+            Ok(Some(chunk)) => {
+                debug!("received {} bytes", chunk.bytes.len());
+                counter.add(chunk.bytes.len());
+                timers::touch();
+                tap::received(&chunk.bytes);
+                if !binary::allow(&chunk.bytes) {
+                    let _ = recv.stop(VarInt::from_u32(0));
+                    return Err(());
+                }
+                pending.extend_from_slice(&chunk.bytes);
+                let mut stdout = stdout().lock();
+                while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+                    let mut line: Vec<u8> = pending.drain(..=pos).collect();
+                    if let Some(strip_cr) = &mut strip_cr {
+                        line = strip_cr.translate(&line);
+                    }
+                    if let Some(latency) = &latency {
+                        latency.received();
+                    }
+                    let _ = stdout.write_all(prefix.as_bytes());
+                    let _ = stdout.write_all(&line);
+                }
+                let _ = stdout.flush();
+            }
+            Ok(None) => {
+                if !pending.is_empty() {
+                    // print whatever is left of an unterminated last line
+                    let mut stdout = stdout().lock();
+                    let _ = stdout.write_all(prefix.as_bytes());
+                    let _ = stdout.write_all(&pending);
+                    let _ = stdout.write_all(b"\n");
+                    let _ = stdout.flush();
+                }
+                info!("stream was closed by the peer.");
+                counter.finish();
+                return Ok(());
+            }
+            Err(ReadError::ConnectionLost(ConnectionError::LocallyClosed)) => {
+                info!("connection closed, no longer receiving");
+                return Ok(());
+            }
+            Err(e) => {
+                error!("unexpected error, shutting down {}", e);
+                return Err(());
+            }
+        }
+    }
+}
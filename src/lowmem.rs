@@ -0,0 +1,70 @@
+//! Memory-limited operation (`--low-memory[=BUDGET]`), for routers and single-board computers
+//! running nesquic as a tunnel endpoint.
+//!
+//! Every connection keeps its buffers within BUDGET bytes, 1MiB unless given: half of it for data
+//! received but not yet read, at most a quarter of it per stream, and the other half for data
+//! sent but not yet acknowledged. Streams are capped at [`MAX_STREAMS`] per connection and a listener at
+//! [`MAX_CONNECTIONS`] connections, so a process never holds much more than
+//! `MAX_CONNECTIONS * BUDGET` of connection buffers. Packets are kept to [`MAX_UDP_PAYLOAD`] bytes
+//! and aren't batched for segmentation offload, which shrinks the endpoint's own buffers too.
+//!
+//! Throughput drops once the round trip outgrows what the windows can cover (BUDGET / 2 per
+//! round-trip time).
+
+use quinn::{EndpointConfig, ServerConfig, TransportConfig, VarInt};
+
+use crate::rate;
+
+/// Most concurrent streams the peer may open.
+pub const MAX_STREAMS: u32 = 4;
+
+/// Most connections a listener handles at once.
+pub const MAX_CONNECTIONS: u32 = 16;
+
+/// Largest UDP payload sent or received, which fits Ethernet without fragmenting.
+pub const MAX_UDP_PAYLOAD: u16 = 1472;
+
+/// Most buffered QUIC datagrams (`--remote-stats` reports), each way.
+const DATAGRAM_BUFFER: u64 = 16 * 1024;
+
+/// Parses a budget such as `512KiB` or `2MB` into bytes.
+pub fn parse_budget(budget: &str) -> Result<u64, String> {
+    if budget.contains('/') || budget.ends_with("ps") {
+        return Err(format!(
+            "'{}' is a rate, expected a size such as 512KiB",
+            budget
+        ));
+    }
+    let bytes = rate::parse_limit(budget)
+        .map_err(|_| format!("invalid size '{}', expected e.g. 512KiB or 2MB", budget))?;
+    if bytes < 64 * 1024 {
+        return Err(format!("'{}' is too small, use at least 64KiB", budget));
+    }
+    Ok(bytes)
+}
+
+/// Shrinks the windows and stream limits of `config` to `budget`, keeping a lower
+/// `--max-streams`.
+pub fn limit_transport(config: &mut TransportConfig, budget: u64, max_streams: Option<u32>) {
+    let window = |bytes: u64| VarInt::from_u64(bytes).unwrap_or(VarInt::MAX);
+    config
+        .receive_window(window(budget / 2))
+        .stream_receive_window(window(budget / 4))
+        .send_window(budget / 2)
+        .max_concurrent_bidi_streams(max_streams.unwrap_or(MAX_STREAMS).min(MAX_STREAMS).into())
+        .datagram_receive_buffer_size(Some(DATAGRAM_BUFFER.min(budget / 16) as usize))
+        .datagram_send_buffer_size(DATAGRAM_BUFFER.min(budget / 16) as usize)
+        .enable_segmentation_offload(false);
+}
+
+/// Caps the packets `config`'s endpoint handles, and so its receive buffer.
+pub fn limit_endpoint(config: &mut EndpointConfig) {
+    config
+        .max_udp_payload_size(MAX_UDP_PAYLOAD)
+        .expect("valid UDP payload size");
+}
+
+/// Caps the connections a listener handles at once.
+pub fn limit_server(config: &mut ServerConfig) {
+    config.concurrent_connections(MAX_CONNECTIONS);
+}
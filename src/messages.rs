@@ -0,0 +1,217 @@
+//! Catalog of the messages shown to users: usage errors, prompts and the errors a run ends with,
+//! in the language of the locale (`LC_ALL`, `LC_MESSAGES` or `LANG`, the first one set).
+//!
+//! Log messages and machine-readable output (`--scan` results, JSON events, close reasons sent to
+//! the peer) stay in English, so scripts and bug reports don't depend on the locale.
+//!
+//! To add a language, add it to [`Language`] and [`Language::from_locale`]: the compiler then
+//! points at every message missing its translation. Placeholders are `{}`, filled in order by
+//! [`tr!`](crate::messages::tr).
+
+use std::{env, fmt, sync::OnceLock};
+
+use crate::report::LogFormat;
+use crate::Cli;
+
+static LANGUAGE: OnceLock<Language> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    English,
+    Portuguese,
+}
+
+const LANGUAGES: usize = 2;
+
+impl Language {
+    /// Language of a POSIX locale name such as `pt_BR.UTF-8`, English unless translated.
+    fn from_locale(locale: &str) -> Self {
+        match locale.split(['_', '.', '@']).next() {
+            Some("pt") => Language::Portuguese,
+            _ => Language::English,
+        }
+    }
+
+    fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|locale| !locale.is_empty())
+            .map_or(Language::English, |locale| Language::from_locale(&locale))
+    }
+}
+
+/// Picks the language, English for JSON output.
+pub fn init(args: &Cli) {
+    let language = match args.log_format {
+        LogFormat::Json => Language::English,
+        LogFormat::Text => Language::from_env(),
+    };
+    let _ = LANGUAGE.set(language);
+}
+
+fn language() -> Language {
+    // messages can come up before [`init`], e.g. while reading the config file
+    LANGUAGE.get().copied().unwrap_or_else(Language::from_env)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Message {
+    ConnectNeedsAddress,
+    ListenNeedsPort,
+    ScanNeedsRange,
+    NotAnIp,
+    NotAPort,
+    InteractivePrompt,
+    EscapeHelp,
+    AuthRejected,
+    AccessDenied,
+    VersionRefused,
+    Refused,
+    ConnectTimedOut,
+    ConnectFailed,
+    DeadlineReached,
+    IdleTimeout,
+    NoProfile,
+    UnknownConfigOption,
+    ConfigUnreadable,
+    ConfigInvalid,
+    UdpNotPermitted,
+    PrivilegedPort,
+    Ipv6Unavailable,
+    NoRoute,
+}
+
+fn translations(message: Message) -> [&'static str; LANGUAGES] {
+    use Message::*;
+    match message {
+        ConnectNeedsAddress => [
+            "connecting needs the IP address and PORT of the server (or -l to listen)",
+            "para conectar, informe o endereço IP e a PORTA do servidor (ou -l para escutar)",
+        ],
+        ListenNeedsPort => [
+            "listeners take a PORT, or an IP address and a PORT, repeated to listen on several",
+            "para escutar, informe uma PORTA, ou um endereço IP e uma PORTA, repetidos para \
+             escutar em vários",
+        ],
+        ScanNeedsRange => [
+            "--scan needs an IP address and a port range",
+            "--scan precisa de um endereço IP e de um intervalo de portas",
+        ],
+        NotAnIp => [
+            "'{}' isn't an IP address (host names aren't supported)",
+            "'{}' não é um endereço IP (nomes de host não são suportados)",
+        ],
+        NotAPort => [
+            "'{}' isn't a port number (0 to 65535)",
+            "'{}' não é um número de porta (0 a 65535)",
+        ],
+        InteractivePrompt => [
+            "stdin is a terminal, running in interactive mode: type lines to send them (the \
+             listener sees the session once the first one is sent), received lines are shown as \
+             they arrive, Ctrl+D stops sending. Pipe data into nesquic to send it as is.",
+            "stdin é um terminal, executando no modo interativo: digite linhas para enviá-las \
+             (quem escuta vê a sessão assim que a primeira for enviada), as linhas recebidas \
+             aparecem assim que chegam, Ctrl+D para de enviar. Redirecione dados para o nesquic \
+             para enviá-los como estão.",
+        ],
+        EscapeHelp => [
+            "escape commands, on a line of their own: {}. close the connection, {}s show \
+             statistics, {}r move to a new local port, {}# list the open streams, {}? this help; \
+             start a line with {}{} to send it with a single {}",
+            "comandos de escape, sozinhos em uma linha: {}. fecha a conexão, {}s mostra as \
+             estatísticas, {}r muda para uma nova porta local, {}# lista os fluxos abertos, {}? \
+             esta ajuda; comece uma linha com {}{} para enviá-la com um só {}",
+        ],
+        AuthRejected => [
+            "the peer rejected the connection, check --auth-token",
+            "o outro lado recusou a conexão, confira o --auth-token",
+        ],
+        AccessDenied => [
+            "the listener refused the connection from our address, see its --allow and --deny",
+            "o servidor recusou a conexão vinda do nosso endereço, veja seus --allow e --deny",
+        ],
+        VersionRefused => [
+            "the peer refused the connection, it requires a newer nesquic version",
+            "o outro lado recusou a conexão, ele exige uma versão mais nova do nesquic",
+        ],
+        Refused => [
+            "the listener refused the connection: {}",
+            "o servidor recusou a conexão: {}",
+        ],
+        ConnectTimedOut => [
+            "could not connect within {}",
+            "não foi possível conectar em {}",
+        ],
+        ConnectFailed => ["could not connect: {}", "não foi possível conectar: {}"],
+        DeadlineReached => ["deadline reached, closing", "prazo atingido, encerrando"],
+        IdleTimeout => [
+            "idle timeout, closing",
+            "tempo de inatividade esgotado, encerrando",
+        ],
+        NoProfile => [
+            "no profile named '{}' in the config file",
+            "não há perfil chamado '{}' no arquivo de configuração",
+        ],
+        UnknownConfigOption => [
+            "unknown option '{}' in the config file",
+            "opção desconhecida '{}' no arquivo de configuração",
+        ],
+        ConfigUnreadable => [
+            "could not read config file {}: {}",
+            "não foi possível ler o arquivo de configuração {}: {}",
+        ],
+        ConfigInvalid => [
+            "invalid config file {}: {}",
+            "arquivo de configuração inválido {}: {}",
+        ],
+        UdpNotPermitted => [
+            "UDP isn't allowed here (by a sandbox, a seccomp filter or a security policy): QUIC \
+             only runs over UDP and nesquic has no TCP fallback, so allow UDP for nesquic or run \
+             it outside the sandbox",
+            "UDP não é permitido aqui (por um sandbox, um filtro seccomp ou uma política de \
+             segurança): o QUIC só funciona sobre UDP e o nesquic não tem alternativa via TCP, \
+             então permita UDP para o nesquic ou execute-o fora do sandbox",
+        ],
+        PrivilegedPort => [
+            "ports below 1024 need root or CAP_NET_BIND_SERVICE, use a port from 1024 up",
+            "portas abaixo de 1024 exigem root ou CAP_NET_BIND_SERVICE, use uma porta a partir \
+             de 1024",
+        ],
+        Ipv6Unavailable => [
+            "IPv6 is disabled on this host, use an IPv4 address",
+            "o IPv6 está desativado neste host, use um endereço IPv4",
+        ],
+        NoRoute => [
+            "there's no route to {}, check the address and that the network is up",
+            "não há rota para {}, confira o endereço e se a rede está ativa",
+        ],
+    }
+}
+
+/// The message in the user's language, with its placeholders filled in with `args`.
+pub fn format(message: Message, args: &[&dyn fmt::Display]) -> String {
+    let template = translations(message)[language() as usize];
+    let mut args = args.iter();
+    let mut text = String::new();
+    let mut parts = template.split("{}");
+    text.push_str(parts.next().unwrap_or_default());
+    for part in parts {
+        if let Some(arg) = args.next() {
+            text.push_str(&arg.to_string());
+        }
+        text.push_str(part);
+    }
+    text
+}
+
+/// Translates a [`Message`], e.g. `tr!(NotAPort, port)`.
+macro_rules! tr {
+    ($message:ident $(, $arg:expr)* $(,)?) => {
+        $crate::messages::format(
+            $crate::messages::Message::$message,
+            &[$(&$arg as &dyn std::fmt::Display),*],
+        )
+    };
+}
+pub(crate) use tr;
@@ -0,0 +1,211 @@
+//! Prometheus metrics (`--metrics ADDR`), for listeners running as permanent endpoints.
+//!
+//! A small HTTP listener on ADDR answers `GET /metrics` with counters in the Prometheus text
+//! format: connections established and open, streams open, bytes sent and received over all
+//! connections, failed handshakes, and a histogram of round-trip times. Traffic is aggregated
+//! from the same per-connection counters the close summary uses; the RTT of every open connection
+//! is sampled every [`RTT_SAMPLE`]. Everything counts from the start of the process.
+
+use std::{
+    error::Error,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tracing::{debug, info};
+
+use crate::{counters, tasks};
+
+/// How often the RTT of open connections is sampled.
+pub const RTT_SAMPLE: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the RTT histogram buckets, in seconds.
+const RTT_BUCKETS: [f64; 12] = [
+    0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0,
+];
+
+/// Most bytes read of a request, which is all headers.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+static HANDSHAKE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+struct Histogram {
+    /// Samples at or below each of [`RTT_BUCKETS`], and above all of them.
+    buckets: [u64; RTT_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+static RTT: Mutex<Histogram> = Mutex::new(Histogram {
+    buckets: [0; RTT_BUCKETS.len() + 1],
+    sum: 0.0,
+});
+
+/// Counts a listener's failed handshake.
+pub fn handshake_failed() {
+    HANDSHAKE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Starts serving metrics on `addr`, failing if it can't be listened on.
+pub async fn serve(addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    tasks::spawn(sample_rtt());
+    tasks::spawn(async move {
+        // dropped with the server, which aborts the requests still being answered
+        let mut scrapes = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        scrapes.spawn(answer(stream));
+                    }
+                    Err(e) => debug!("[metrics] accept failed: {}", e),
+                },
+                Some(joined) = scrapes.join_next() => {
+                    tasks::output(joined, "metrics request");
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Adds the RTT of every open connection to the histogram, every [`RTT_SAMPLE`].
+async fn sample_rtt() {
+    let mut ticks = tokio::time::interval(RTT_SAMPLE);
+    loop {
+        ticks.tick().await;
+        let open = counters::open();
+        let mut rtt = RTT.lock().unwrap();
+        for (conn, _) in open {
+            let seconds = conn.rtt().as_secs_f64();
+            let bucket = RTT_BUCKETS
+                .iter()
+                .position(|&bound| seconds <= bound)
+                .unwrap_or(RTT_BUCKETS.len());
+            rtt.buckets[bucket] += 1;
+            rtt.sum += seconds;
+        }
+    }
+}
+
+/// Answers one HTTP request.
+async fn answer(mut stream: TcpStream) {
+    let mut request = Vec::new();
+    let read = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return false,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        true
+    })
+    .await;
+    if read != Ok(true) {
+        return;
+    }
+    let line = request.split(|&byte| byte == b'\r').next().unwrap_or(&[]);
+    let mut words = line.split(|&byte| byte == b' ');
+    let (status, body) = match (words.next(), words.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", render()),
+        (Some(b"GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("[metrics] could not answer: {}", e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// All metrics, in the Prometheus text format.
+fn render() -> String {
+    let run = counters::run_totals();
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    metric(
+        "nesquic_connections_total",
+        "counter",
+        "Connections established.",
+        run.connections,
+    );
+    metric(
+        "nesquic_open_connections",
+        "gauge",
+        "Connections currently open.",
+        run.open_connections as u64,
+    );
+    metric(
+        "nesquic_open_streams",
+        "gauge",
+        "Streams currently transferring data.",
+        run.open_streams as u64,
+    );
+    metric(
+        "nesquic_sent_bytes_total",
+        "counter",
+        "Application data sent over all connections.",
+        run.sent_bytes,
+    );
+    metric(
+        "nesquic_received_bytes_total",
+        "counter",
+        "Application data received over all connections.",
+        run.received_bytes,
+    );
+    metric(
+        "nesquic_handshake_failures_total",
+        "counter",
+        "Connection attempts whose handshake failed.",
+        HANDSHAKE_FAILURES.load(Ordering::Relaxed),
+    );
+    let rtt = RTT.lock().unwrap();
+    let _ = writeln!(
+        out,
+        "# HELP nesquic_rtt_seconds Round-trip time of open connections, sampled every {}s.",
+        RTT_SAMPLE.as_secs()
+    );
+    let _ = writeln!(out, "# TYPE nesquic_rtt_seconds histogram");
+    let mut count = 0;
+    for (bound, samples) in RTT_BUCKETS.iter().zip(rtt.buckets) {
+        count += samples;
+        let _ = writeln!(
+            out,
+            "nesquic_rtt_seconds_bucket{{le=\"{}\"}} {}",
+            bound, count
+        );
+    }
+    count += rtt.buckets[RTT_BUCKETS.len()];
+    let _ = writeln!(out, "nesquic_rtt_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(out, "nesquic_rtt_seconds_sum {}", rtt.sum);
+    let _ = writeln!(out, "nesquic_rtt_seconds_count {}", count);
+    out
+}
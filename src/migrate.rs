@@ -0,0 +1,93 @@
+//! Connection migration test hook (`--rebind-every`).
+//!
+//! The client periodically moves its endpoint to a fresh UDP socket on a new ephemeral port, so
+//! the server sees the connection arrive from a new address mid-transfer. A migration counts as
+//! survived when the connection is still open and packets arrive on the new socket shortly after,
+//! which they do once the server validated the new path. In interactive mode, `~r` moves it once,
+//! on demand (see [`crate::escape`]).
+
+use std::{error::Error, net::SocketAddr, sync::Mutex, time::Duration};
+
+use quinn::{Connection, Endpoint};
+use tokio::time::{sleep, timeout};
+use tracing::error;
+
+use crate::report::{self, Event};
+use crate::util;
+
+/// Longest time to wait for packets on the new socket before declaring the connection lost.
+const SURVIVAL_WINDOW: Duration = Duration::from_secs(3);
+
+/// The client's endpoint and `--interface`, for rebinding on demand (`~r` in interactive mode).
+static CLIENT: Mutex<Option<(Endpoint, Option<String>)>> = Mutex::new(None);
+
+/// Lets the connections of a client's `endpoint` be moved to a new socket on demand.
+pub fn allow_on_demand(endpoint: &Endpoint, interface: Option<String>) {
+    *CLIENT.lock().unwrap() = Some((endpoint.clone(), interface));
+}
+
+/// Moves `conn` to a new socket now, if this is a client.
+pub async fn rebind_now(conn: &Connection) {
+    let client = CLIENT.lock().unwrap().clone();
+    let Some((endpoint, interface)) = client else {
+        error!("[rebind] only clients can move to a new socket");
+        return;
+    };
+    if let Err(e) = rebind(&endpoint, conn, interface.as_deref(), SURVIVAL_WINDOW).await {
+        error!("[rebind] {}", e);
+    }
+}
+
+/// Rebinds `endpoint` every `every` for as long as `conn` is open, staying on `interface` if
+/// given.
+pub async fn rebind_every(
+    endpoint: Endpoint,
+    conn: Connection,
+    every: Duration,
+    interface: Option<String>,
+) {
+    loop {
+        tokio::select! {
+            _ = conn.closed() => return,
+            _ = sleep(every) => {}
+        }
+        let window = SURVIVAL_WINDOW.min(every);
+        if let Err(e) = rebind(&endpoint, &conn, interface.as_deref(), window).await {
+            error!("[rebind] {}", e);
+        }
+    }
+}
+
+/// Moves `endpoint` to a new socket on the same IP address, and reports whether `conn` survived,
+/// waiting up to `window` for packets on the new socket.
+async fn rebind(
+    endpoint: &Endpoint,
+    conn: &Connection,
+    interface: Option<&str>,
+    window: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let ip = endpoint
+        .local_addr()
+        .map_err(|e| format!("could not get local address: {}", e))?
+        .ip();
+    let socket = util::bind_socket(SocketAddr::new(ip, 0), interface, false)
+        .map_err(|e| format!("could not bind a new socket: {}", e))?;
+    let local_port = socket.local_addr().map_or(0, |addr| addr.port());
+    let received = conn.stats().udp_rx.datagrams;
+    endpoint
+        .rebind(socket)
+        .map_err(|e| format!("could not switch to the new socket: {}", e))?;
+    let survived = timeout(window, async {
+        while conn.close_reason().is_none() && conn.stats().udp_rx.datagrams == received {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .is_ok()
+        && conn.close_reason().is_none();
+    report::emit(Event::Rebind {
+        local_port,
+        survived,
+    });
+    Ok(())
+}
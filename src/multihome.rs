@@ -0,0 +1,140 @@
+//! Listening on several addresses at once (`nesquic -l IP PORT IP PORT...`), e.g. on IPv4 and
+//! IPv6, or on a few interfaces only.
+//!
+//! The sockets are bundled into one, so a single endpoint serves them all and every listener mode
+//! works unchanged: datagrams are read from whichever socket has some, and each peer is answered
+//! from the socket it was last heard on, so its packets keep coming from the address it knows.
+//! IPv6 sockets are bound IPv6-only, so `0.0.0.0` and `::` can share a port.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, IoSliceMut},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use quinn::{
+    udp::{RecvMeta, Transmit, UdpState},
+    AsyncUdpSocket,
+};
+
+/// Most peers whose socket is remembered; beyond, they're forgotten and found again when they
+/// next send something.
+const MAX_ROUTES: usize = 4096;
+
+/// Several sockets acting as one.
+pub struct MultiSocket {
+    sockets: Vec<Box<dyn AsyncUdpSocket>>,
+    /// Socket each peer was last heard on.
+    routes: Mutex<HashMap<SocketAddr, usize>>,
+    /// Socket read from first next time, so a busy one doesn't starve the others.
+    next: AtomicUsize,
+}
+
+impl MultiSocket {
+    /// Bundles `sockets`, of which there is at least one.
+    pub fn new(sockets: Vec<Box<dyn AsyncUdpSocket>>) -> Self {
+        assert!(!sockets.is_empty(), "no socket to listen on");
+        MultiSocket {
+            sockets,
+            routes: Mutex::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Socket to send to `peer` from: the one it was last heard on, or else the first one of the
+    /// same address family.
+    fn route(&self, peer: SocketAddr) -> usize {
+        if let Some(&index) = self.routes.lock().unwrap().get(&peer) {
+            return index;
+        }
+        self.sockets
+            .iter()
+            .position(|socket| {
+                socket
+                    .local_addr()
+                    .is_ok_and(|addr| addr.is_ipv6() == peer.is_ipv6())
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Debug for MultiSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiSocket")
+            .field("sockets", &self.sockets)
+            .finish()
+    }
+}
+
+impl AsyncUdpSocket for MultiSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut sent = 0;
+        while sent < transmits.len() {
+            // hand over the transmits going out of the same socket together
+            let index = self.route(transmits[sent].destination);
+            let batch = transmits[sent..]
+                .iter()
+                .take_while(|transmit| self.route(transmit.destination) == index)
+                .count();
+            match self.sockets[index].poll_send(state, cx, &transmits[sent..sent + batch]) {
+                Poll::Ready(Ok(count)) => {
+                    sent += count;
+                    if count < batch {
+                        break;
+                    }
+                }
+                Poll::Ready(Err(e)) if sent == 0 => return Poll::Ready(Err(e)),
+                Poll::Pending if sent == 0 => return Poll::Pending,
+                Poll::Ready(Err(_)) | Poll::Pending => break,
+            }
+        }
+        Poll::Ready(Ok(sent))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let first = self.next.load(Ordering::Relaxed);
+        for offset in 0..self.sockets.len() {
+            let index = (first + offset) % self.sockets.len();
+            match self.sockets[index].poll_recv(cx, bufs, meta) {
+                Poll::Ready(Ok(count)) => {
+                    self.next.store(index + 1, Ordering::Relaxed);
+                    let mut routes = self.routes.lock().unwrap();
+                    if routes.len() >= MAX_ROUTES {
+                        routes.clear();
+                    }
+                    for received in &meta[..count] {
+                        routes.insert(received.addr, index);
+                    }
+                    return Poll::Ready(Ok(count));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
+            }
+        }
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets[0].local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.sockets.iter().any(|socket| socket.may_fragment())
+    }
+}
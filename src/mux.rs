@@ -0,0 +1,215 @@
+//! Connection sharing between invocations (`--mux SOCKET`), like ssh's ControlMaster.
+//!
+//! The first client invocation given a control socket becomes the master: it connects as usual,
+//! runs its own session and listens on the socket. Later invocations with the same socket find
+//! the master there and attach to it instead of connecting themselves. The master opens a new
+//! stream on its connection for each of them and splices it to the attached invocation's
+//! stdin/stdout, so bursts of short sessions don't pay for a handshake each. The listener treats
+//! these streams as additional sessions (see [`channel`](crate::channel)).
+//!
+//! The master keeps the connection open after its own session is over, until the connection is
+//! closed or the process is stopped (Ctrl+C, `--idle-exit`, `-w`).
+
+use std::{
+    io::{self, stdout, ErrorKind, Read, Write},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use quinn::Connection;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::counters::StreamCounter;
+use crate::report::{self, Direction};
+use crate::sched::{self, Share};
+use crate::{binary, channel, tasks, timers};
+
+/// Attaches to the master listening on `path`, pumping stdin/stdout through it. Returns `false`
+/// if there is no master, in which case this invocation should become one.
+pub async fn attach(path: &Path) -> io::Result<bool> {
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e),
+    };
+    info!("[mux] attached to master on {}", path.display());
+    let (mut from_master, mut to_master) = stream.into_split();
+
+    let outgoing = async {
+        let mut input = spawn_input_reader();
+        while let Some(data) = input.recv().await {
+            to_master.write_all(&data).await?;
+        }
+        to_master.shutdown().await
+    };
+    let incoming = async {
+        let mut buffer = vec![0; 64 * 1024];
+        let mut stdout = stdout();
+        loop {
+            let n = from_master.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            if !binary::allow(&buffer[..n]) {
+                break;
+            }
+            stdout.write_all(&buffer[..n])?;
+            stdout.flush()?;
+        }
+        io::Result::Ok(())
+    };
+    let (sent, received) = tokio::join!(outgoing, incoming);
+    if let Err(e) = sent {
+        warn!("[mux] failed to send data to master: {}", e);
+    }
+    received?;
+    Ok(true)
+}
+
+/// Spawns a thread reading stdin. The channel is closed on EOF.
+fn spawn_input_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(16);
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let length = match stdin.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(length) => length,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("failed to read from stdin: {}", e);
+                    break;
+                }
+            };
+            if tx.blocking_send(buffer[..length].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Control socket of a master, serving attached invocations over its connection.
+///
+/// Dropping it stops accepting attachments and removes the socket.
+pub struct Master {
+    path: PathBuf,
+    conn: Connection,
+    acceptor: JoinHandle<()>,
+}
+
+/// Listens on `path` and serves every invocation attaching there with a new stream on `conn`.
+pub fn serve(path: &Path, conn: &Connection, share: Share) -> io::Result<Master> {
+    // a socket left behind by a master that didn't exit cleanly
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("[mux] master listening on {}", path.display());
+
+    let acceptor_conn = conn.clone();
+    let acceptor = tokio::spawn(async move {
+        // aborted with the acceptor, so attachments don't outlive the master
+        let mut attachments = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        attachments.spawn(splice(acceptor_conn.clone(), stream, share.clone()));
+                    }
+                    Err(e) => {
+                        error!("[mux] failed to accept attachment: {}", e);
+                        break;
+                    }
+                },
+                Some(joined) = attachments.join_next() => {
+                    tasks::output(joined, "[mux] attachment");
+                }
+            }
+        }
+        while let Some(joined) = attachments.join_next().await {
+            tasks::output(joined, "[mux] attachment");
+        }
+    });
+    Ok(Master {
+        path: path.to_path_buf(),
+        conn: conn.clone(),
+        acceptor,
+    })
+}
+
+impl Master {
+    /// Keeps serving attached invocations until the connection is closed.
+    pub async fn persist(self) {
+        info!(
+            "[mux] own session finished, still serving attachments on {}",
+            self.path.display()
+        );
+        let reason = self.conn.closed().await;
+        debug!("[mux] connection closed: {}", reason);
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Runs an attached invocation's session over a new stream.
+async fn splice(conn: Connection, stream: UnixStream, share: Share) {
+    let (mut send, mut recv) = match channel::open_session(&conn).await {
+        Ok(streams) => streams,
+        Err(e) => {
+            error!("[mux] could not open a stream for attachment: {}", e);
+            return;
+        }
+    };
+    report::stream_open(send.id(), "mux", None);
+    share.prioritize(&send);
+    let mut sent = StreamCounter::new(&conn, send.id(), Direction::Sent);
+    let mut received = StreamCounter::new(&conn, recv.id(), Direction::Received);
+    let (mut from_client, mut to_client) = stream.into_split();
+    let expired = {
+        let outgoing = async {
+            match sched::pump(&mut from_client, &mut send, &share, &mut sent).await {
+                Ok(()) => sent.finish(),
+                Err(e) => error!("[mux] sending failed: {}", e),
+            }
+            let _ = send.finish().await;
+        };
+        let incoming = async {
+            match channel::pump(&mut recv, &mut to_client, &mut received).await {
+                Ok(()) => received.finish(),
+                Err(e) => debug!("[mux] receiving failed: {}", e),
+            }
+            let _ = to_client.shutdown().await;
+        };
+        tokio::select! {
+            _ = async { tokio::join!(outgoing, incoming) } => false,
+            _ = timers::stream_expiry() => true,
+        }
+    };
+    if expired {
+        timers::expire_stream(&mut send, &mut recv);
+    }
+    debug!("[mux] attachment finished");
+}
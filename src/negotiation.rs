@@ -0,0 +1,110 @@
+//! QUIC versions (`--quic-version`), and what each connection negotiated.
+//!
+//! By default both sides speak every version quinn implements: QUIC v1 and drafts 29 to 34, the
+//! client offering v1. `--quic-version` restricts an endpoint to the versions listed, the client
+//! offering the first one, e.g. to check how another stack handles a draft or a version it
+//! doesn't speak. quinn doesn't fall back to another version after a version negotiation packet,
+//! so a client whose version the server doesn't speak fails to connect.
+//!
+//! quinn doesn't tell which version a connection uses, nor most of the peer's transport
+//! parameters, so the version is read from the long headers of the packets received, which carry
+//! it, and reported with the handshake; the peer's datagram support is the only transport
+//! parameter that can be seen.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+use quinn::{ClientConfig, Connection, EndpointConfig};
+
+use crate::Cli;
+
+/// Versions quinn implements, by name.
+const VERSIONS: [(&str, u32); 7] = [
+    ("v1", 0x0000_0001),
+    ("draft-29", 0xff00_001d),
+    ("draft-30", 0xff00_001e),
+    ("draft-31", 0xff00_001f),
+    ("draft-32", 0xff00_0020),
+    ("draft-33", 0xff00_0021),
+    ("draft-34", 0xff00_0022),
+];
+
+/// Most peers whose version is remembered, which is plenty for the connections a listener
+/// handles at once.
+const MAX_PEERS: usize = 4096;
+
+/// Version of the last long header packet received from each peer.
+static SEEN: Mutex<Option<HashMap<SocketAddr, u32>>> = Mutex::new(None);
+
+/// Parses a version: a name such as `v1` or `draft-29`, or its number, e.g. `0xff00001d`.
+pub fn parse_version(version: &str) -> Result<u32, String> {
+    let number = match version.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => VERSIONS
+            .iter()
+            .find(|(name, _)| *name == version || name.strip_prefix('v') == Some(version))
+            .map(|&(_, number)| number),
+    };
+    match number {
+        Some(number) if VERSIONS.iter().any(|&(_, known)| known == number) => Ok(number),
+        _ => Err(format!(
+            "unknown QUIC version '{}', expected one of {}",
+            version,
+            VERSIONS.map(|(name, _)| name).join(", ")
+        )),
+    }
+}
+
+/// Name of `version`, or its number if it has none.
+pub fn name(version: u32) -> String {
+    match VERSIONS.iter().find(|&&(_, known)| known == version) {
+        Some((name, _)) => name.to_string(),
+        None => format!("{:#010x}", version),
+    }
+}
+
+/// Restricts an endpoint to the `--quic-version`s, if given.
+pub fn limit_endpoint(config: &mut EndpointConfig, args: &Cli) {
+    if !args.quic_version.is_empty() {
+        config.supported_versions(args.quic_version.clone());
+    }
+}
+
+/// Makes a client offer the first `--quic-version`, if given.
+pub fn limit_client(config: &mut ClientConfig, args: &Cli) {
+    if let Some(&version) = args.quic_version.first() {
+        config.version(version);
+    }
+}
+
+/// Notes the version of a packet received from `peer`, if it has a long header.
+pub fn observe(packet: &[u8], peer: SocketAddr) {
+    let [first, version @ ..] = packet else {
+        return;
+    };
+    let Some(version) = version.get(..4) else {
+        return;
+    };
+    let version = u32::from_be_bytes(version.try_into().unwrap());
+    // short headers carry no version, and version negotiation packets have version 0
+    if first & 0x80 == 0 || version == 0 {
+        return;
+    }
+    let mut seen = SEEN.lock().unwrap();
+    let seen = seen.get_or_insert_with(HashMap::new);
+    if seen.get(&peer) == Some(&version) {
+        return;
+    }
+    if seen.len() >= MAX_PEERS {
+        seen.clear();
+    }
+    seen.insert(peer, version);
+}
+
+/// The version `conn` uses, if a long header packet was seen from its peer.
+pub fn version(conn: &Connection) -> Option<u32> {
+    SEEN.lock()
+        .unwrap()
+        .as_ref()?
+        .get(&conn.remote_address())
+        .copied()
+}
@@ -0,0 +1,239 @@
+//! Writing out received data (`--read-chunk-size SIZE`, `--out-of-order`).
+//!
+//! The session's stream is read `--read-chunk-size` bytes at a time (`--chunk-size` unless given),
+//! and what's read is queued, without copying, for a writer task, which writes out whatever is
+//! queued at once: in a single vectored write, on tokio's blocking threads, so the connection
+//! isn't held up by the output and fast transfers don't take a system call per chunk. The queue
+//! holds up to [`QUEUE_BYTES`]; once it's full, reading the stream waits, and the stream's flow
+//! control holds up the peer. `--min-read` and `--max-latency` hold writes back further (see
+//! [`crate::coalesce`]).
+//!
+//! With `--out-of-order`, received data must go to a file (stdout redirected to one, not appended
+//! to, or `--resume`). The stream is then read in whatever order its data arrives, and each chunk
+//! is written at its place in the file, so data arriving after a lost packet isn't held back until
+//! the packet is retransmitted; the file is whole once the stream ends. What needs the data in
+//! order (`--checksum`, `--compress`, `--strip-cr`, `-x`) can't be used with it.
+
+use std::{
+    fs::File,
+    io::{self, IoSlice, Seek, Write},
+    sync::OnceLock,
+};
+
+use bytes::Bytes;
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    task::{self, JoinHandle},
+};
+
+use crate::backend::Output;
+use crate::coalesce::Coalescer;
+use crate::{input, Cli};
+
+/// Most bytes queued for the writer.
+pub const QUEUE_BYTES: usize = 8 * 1024 * 1024;
+
+/// Most bytes taken off the queue for a single write, unless `--min-read` holds more back.
+const MAX_WRITE: usize = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+struct Settings {
+    read_chunk: Option<usize>,
+    out_of_order: bool,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+pub fn init(args: &Cli) {
+    let _ = SETTINGS.set(Settings {
+        read_chunk: args.read_chunk_size,
+        out_of_order: args.out_of_order,
+    });
+}
+
+/// Most bytes read at once from the session's stream.
+pub fn read_chunk_size() -> usize {
+    SETTINGS
+        .get()
+        .and_then(|settings| settings.read_chunk)
+        .unwrap_or_else(input::chunk_size)
+}
+
+/// Where the writer writes.
+pub enum Sink<W> {
+    /// In the order of the stream.
+    Ordered(W),
+    /// Each chunk at its offset in the stream, after `base` bytes of the file (`--out-of-order`).
+    AtOffsets { file: File, base: u64 },
+}
+
+/// Where data received for `out` is written: to it in order, or at its place in the file it is
+/// with `--out-of-order`.
+pub fn sink(out: Output) -> io::Result<Sink<Output>> {
+    if !SETTINGS.get().is_some_and(|settings| settings.out_of_order) {
+        return Ok(Sink::Ordered(out));
+    }
+    let not_a_file = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--out-of-order needs stdout redirected to a file",
+        )
+    };
+    let mut file = match out {
+        Output::File(file) => file,
+        Output::Stdout(stdout) => stdout_file(&stdout)?,
+        #[cfg(unix)]
+        Output::Socket(_) => return Err(not_a_file()),
+    };
+    if !file.metadata()?.is_file() {
+        return Err(not_a_file());
+    }
+    if appends(&file) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--out-of-order can't write to a file opened for appending (>>)",
+        ));
+    }
+    let base = file.stream_position()?;
+    Ok(Sink::AtOffsets { file, base })
+}
+
+#[cfg(unix)]
+fn stdout_file(stdout: &io::Stdout) -> io::Result<File> {
+    use std::os::fd::AsFd;
+    Ok(File::from(stdout.as_fd().try_clone_to_owned()?))
+}
+
+#[cfg(windows)]
+fn stdout_file(stdout: &io::Stdout) -> io::Result<File> {
+    use std::os::windows::io::AsHandle;
+    Ok(File::from(stdout.as_handle().try_clone_to_owned()?))
+}
+
+/// Whether writes to `file` go to its end wherever they're made.
+#[cfg(unix)]
+fn appends(file: &File) -> bool {
+    use std::os::fd::AsRawFd;
+    let mode = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    mode != -1 && mode & libc::O_APPEND != 0
+}
+
+#[cfg(not(unix))]
+fn appends(_file: &File) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            written => {
+                data = &data[written..];
+                offset += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<W: Write> Sink<W> {
+    fn write(&mut self, pieces: &[(u64, Bytes)]) -> io::Result<()> {
+        match self {
+            Sink::Ordered(out) => {
+                let mut slices: Vec<_> =
+                    pieces.iter().map(|(_, data)| IoSlice::new(data)).collect();
+                let mut slices = &mut slices[..];
+                while !slices.is_empty() {
+                    match out.write_vectored(slices) {
+                        Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                        Ok(written) => IoSlice::advance_slices(&mut slices, written),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                out.flush()
+            }
+            Sink::AtOffsets { file, base } => pieces
+                .iter()
+                .try_for_each(|(offset, data)| write_at(file, data, *base + offset)),
+        }
+    }
+}
+
+/// Task writing out received data, fed through a bounded queue.
+pub struct Writer<W> {
+    queue: Sender<(u64, Bytes)>,
+    task: JoinHandle<io::Result<Sink<W>>>,
+}
+
+impl<W: Write + Send + 'static> Writer<W> {
+    pub fn spawn(sink: Sink<W>) -> Self {
+        let (queue, chunks) = mpsc::channel((QUEUE_BYTES / read_chunk_size()).max(2));
+        Writer {
+            queue,
+            task: tokio::spawn(write_out(sink, chunks)),
+        }
+    }
+
+    /// Queues `data` to be written at `offset`, waiting while the queue is full. Fails if the
+    /// writer stopped on an error, which [`Writer::finish`] returns.
+    pub async fn write(&self, offset: u64, data: Bytes) -> Result<(), ()> {
+        self.queue.send((offset, data)).await.map_err(|_| ())
+    }
+
+    /// Writes out whatever is queued or held back, and hands the sink back.
+    pub async fn finish(self) -> io::Result<Sink<W>> {
+        drop(self.queue);
+        self.task.await.map_err(io::Error::other)?
+    }
+}
+
+async fn write_out<W: Write + Send + 'static>(
+    mut sink: Sink<W>,
+    mut chunks: Receiver<(u64, Bytes)>,
+) -> io::Result<Sink<W>> {
+    let mut coalescer = Coalescer::default();
+    let mut open = true;
+    while open {
+        let next = match coalescer.deadline() {
+            Some(deadline) => tokio::select! {
+                next = chunks.recv() => Some(next),
+                _ = tokio::time::sleep_until(deadline.into()) => None,
+            },
+            None => Some(chunks.recv().await),
+        };
+        match next {
+            Some(Some((offset, data))) => {
+                coalescer.push(offset, data);
+                while coalescer.pending() < MAX_WRITE {
+                    match chunks.try_recv() {
+                        Ok((offset, data)) => coalescer.push(offset, data),
+                        Err(_) => break,
+                    }
+                }
+                if !coalescer.due() {
+                    continue;
+                }
+            }
+            // every sender is gone, so whatever is pending is the last of it
+            Some(None) => open = false,
+            // the oldest pending byte waited --max-latency
+            None => {}
+        }
+        let pieces = coalescer.take();
+        if pieces.is_empty() {
+            continue;
+        }
+        sink = task::spawn_blocking(move || sink.write(&pieces).map(|()| sink))
+            .await
+            .map_err(io::Error::other)??;
+    }
+    Ok(sink)
+}
@@ -0,0 +1,139 @@
+//! Connections waiting for a listener (`--max-pending N`, `--refuse-message MSG`).
+//!
+//! Connection attempts are taken from the endpoint as they arrive and checked against
+//! `--allow`/`--deny`, then wait in a queue of up to N (16 unless given), in arrival order, until
+//! the listener gets to them. Once the queue is full, further attempts are refused instead of
+//! being left hanging: the handshake is completed and the connection closed right away with
+//! application error code [`REFUSED`] and MSG as the reason, which the client reports. A listener
+//! serving a single session refuses everyone who comes after its client.
+
+use std::error::Error;
+
+use quinn::{Connecting, Connection, ConnectionError, Endpoint, VarInt};
+use tokio::{sync::mpsc, task::AbortHandle};
+use tracing::{debug, warn};
+
+use crate::acl::Acl;
+use crate::messages::tr;
+use crate::{tasks, Cli};
+
+/// Application error code used to close connections the listener has no room for.
+pub const REFUSED: u32 = 11;
+
+/// Connection attempts kept waiting when `--max-pending` isn't given.
+pub const DEFAULT_MAX_PENDING: usize = 16;
+
+/// Reason given to refused clients when `--refuse-message` isn't.
+pub const DEFAULT_REFUSE_MESSAGE: &str = "busy, try again later";
+
+/// Most bytes of `--refuse-message`, so it fits in the closing packet.
+const MAX_MESSAGE: usize = 512;
+
+/// Parses `--max-pending`, at least 1.
+pub fn parse_max_pending(max: &str) -> Result<usize, String> {
+    match max.parse() {
+        Ok(0) | Err(_) => Err(format!(
+            "expected a number of connections from 1, got '{}'",
+            max
+        )),
+        Ok(max) => Ok(max),
+    }
+}
+
+/// Parses `--refuse-message`.
+pub fn parse_message(message: &str) -> Result<String, String> {
+    if message.len() > MAX_MESSAGE {
+        return Err(format!("the message is longer than {} bytes", MAX_MESSAGE));
+    }
+    Ok(message.to_string())
+}
+
+/// Connection attempts accepted from an endpoint, waiting for the listener.
+pub struct Queue {
+    waiting: mpsc::Receiver<Connecting>,
+    message: String,
+    /// Takes connection attempts from the endpoint, until the queue is dropped.
+    accepting: AbortHandle,
+}
+
+impl Queue {
+    pub fn new(endpoint: &Endpoint, args: &Cli) -> Self {
+        let max_pending = args.max_pending.unwrap_or(DEFAULT_MAX_PENDING);
+        let message = args
+            .refuse_message
+            .clone()
+            .unwrap_or_else(|| DEFAULT_REFUSE_MESSAGE.to_string());
+        let (tx, waiting) = mpsc::channel(max_pending);
+        let acl = Acl::from_args(args);
+        let endpoint = endpoint.clone();
+        let refusal = message.clone();
+        let accepting = tasks::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let Some(connecting) = acl.check(connecting) else {
+                    continue;
+                };
+                if let Err(full) = tx.try_send(connecting) {
+                    refuse(full.into_inner(), &refusal);
+                }
+            }
+        });
+        Queue {
+            waiting,
+            message,
+            accepting,
+        }
+    }
+
+    /// Next connection attempt, in arrival order, or `None` once the endpoint is closed.
+    pub async fn accept(&mut self) -> Option<Connecting> {
+        self.waiting.recv().await
+    }
+
+    /// Refuses the connection attempts waiting and all those coming in from now on, for a
+    /// listener that won't accept any more.
+    pub fn refuse_rest(&mut self) {
+        self.waiting.close();
+        while let Ok(connecting) = self.waiting.try_recv() {
+            refuse(connecting, &self.message);
+        }
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.accepting.abort();
+    }
+}
+
+/// Turns a connection attempt away in the background, once its handshake is complete.
+fn refuse(connecting: Connecting, message: &str) {
+    let addr = connecting.remote_address();
+    warn!("refusing connection from {}: {}", addr, message);
+    let reason = message.as_bytes().to_vec();
+    tasks::spawn(async move {
+        match connecting.await {
+            Ok(conn) => conn.close(VarInt::from_u32(REFUSED), &reason),
+            Err(e) => debug!("handshake with refused client {} failed: {}", addr, e),
+        }
+    });
+}
+
+/// Error saying the listener refused the connection, if it did, or else `e`.
+pub fn explain(conn: &Connection, e: Box<dyn Error>) -> Box<dyn Error> {
+    match refusal(conn) {
+        Some(reason) => tr!(Refused, reason).into(),
+        None => e,
+    }
+}
+
+/// The reason the listener gave for refusing the connection, if it did.
+pub fn refusal(conn: &Connection) -> Option<String> {
+    match conn.close_reason() {
+        Some(ConnectionError::ApplicationClosed(close))
+            if close.error_code == VarInt::from_u32(REFUSED) =>
+        {
+            Some(String::from_utf8_lossy(&close.reason).into_owned())
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,199 @@
+//! Round-trip time probes (`--ping[=INTERVAL]`), a QUIC-native `ping` toward any listener.
+//!
+//! After the session is set up, the client opens a dedicated stream and sends a probe on it
+//! every INTERVAL: a sequence number and the time it was sent. The listener echoes the stream
+//! back (see [`crate::channel`]), and the client reports each reply with its round-trip time.
+//! When done, after `--count` probes or on Ctrl+C, a summary follows: probes sent and answered,
+//! and the minimum, average and maximum round-trip time along with the jitter, the mean
+//! difference between consecutive round-trip times. SIGUSR1 (Ctrl+Break on Windows) reports the
+//! summary so far.
+//!
+//! Probes travel on a stream, so a lost packet shows up as a late reply rather than a missing
+//! one; probes still unanswered [`REPLY_WAIT`] after the last one was sent count as lost.
+//!
+//! Probe format: sequence number (u32) and microseconds since the first probe (u64), big-endian.
+//! The stream header comes back first, so `--echo` listeners answer probes as well.
+
+use std::{
+    error::Error,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use quinn::{Connection, ReadError, ReadExactError, RecvStream, SendStream};
+use tokio::{
+    sync::mpsc,
+    task::JoinSet,
+    time::{interval, sleep_until, MissedTickBehavior},
+};
+use tracing::debug;
+
+use crate::report::{self, Event};
+use crate::signals::{self, Signals};
+use crate::{channel, timers};
+
+const PROBE_LEN: usize = 12;
+
+/// How long replies are waited for once the last probe was sent.
+const REPLY_WAIT: Duration = Duration::from_secs(2);
+
+/// Round-trip times of the replies so far.
+struct Stats {
+    peer: SocketAddr,
+    sent: u32,
+    rtts: Vec<Duration>,
+}
+
+impl Stats {
+    fn summary(&self) -> Event {
+        let ms = |rtt: &Duration| rtt.as_secs_f64() * 1000.0;
+        let received = self.rtts.len();
+        let average = match received {
+            0 => 0.0,
+            _ => self.rtts.iter().map(ms).sum::<f64>() / received as f64,
+        };
+        let jitter = match received {
+            0 | 1 => 0.0,
+            _ => {
+                let deltas = self
+                    .rtts
+                    .windows(2)
+                    .map(|pair| (ms(&pair[1]) - ms(&pair[0])).abs());
+                deltas.sum::<f64>() / (received - 1) as f64
+            }
+        };
+        Event::PingSummary {
+            peer: self.peer,
+            sent: self.sent,
+            received: received as u32,
+            min_ms: self.rtts.iter().min().map_or(0.0, ms),
+            avg_ms: average,
+            max_ms: self.rtts.iter().max().map_or(0.0, ms),
+            jitter_ms: jitter,
+        }
+    }
+}
+
+/// Pings the peer until `count` probes were sent, or until interrupted. Fails if no probe was
+/// answered.
+pub async fn run(
+    conn: &Connection,
+    mut session_send: SendStream,
+    mut session_recv: RecvStream,
+    every: Duration,
+    count: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    timers::watch_session(conn);
+    // only the probe stream is used: what the listener sends on the session is dropped, and our
+    // side is finished last so the listener doesn't end the connection before the probes are
+    // answered
+    let mut readers = JoinSet::new();
+    readers.spawn(async move {
+        while let Ok(Some(_)) = session_recv.read_chunk(usize::MAX, true).await {}
+    });
+    let mut signals = Signals::install()?;
+    let (mut send, recv) = channel::open_ping(conn).await?;
+    let mut stats = Stats {
+        peer: conn.remote_address(),
+        sent: 0,
+        rtts: Vec::new(),
+    };
+    let start = Instant::now();
+    let mut replies = read_replies(&mut readers, recv, start);
+    let mut ticks = interval(every);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_sent = start;
+    loop {
+        let sending = count.is_none_or(|count| stats.sent < count);
+        if !sending && stats.rtts.len() as u32 == stats.sent {
+            break;
+        }
+        tokio::select! {
+            _ = ticks.tick(), if sending => {
+                stats.sent += 1;
+                let mut probe = stats.sent.to_be_bytes().to_vec();
+                probe.extend_from_slice(&(start.elapsed().as_micros() as u64).to_be_bytes());
+                send.write_all(&probe).await?;
+                last_sent = Instant::now();
+            }
+            reply = replies.recv() => match reply {
+                Some(Ok((seq, rtt))) => {
+                    timers::touch();
+                    report::emit(Event::Ping {
+                        peer: stats.peer,
+                        seq,
+                        rtt_ms: rtt.as_secs_f64() * 1000.0,
+                    });
+                    stats.rtts.push(rtt);
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+            _ = sleep_until((last_sent + REPLY_WAIT).into()), if !sending => {
+                debug!("[ping] giving up on {} probe(s)", stats.sent - stats.rtts.len() as u32);
+                break;
+            }
+            event = signals.recv() => match event {
+                signals::Event::DumpStats => report::emit(stats.summary()),
+                signals::Event::Shutdown | signals::Event::Reload => break,
+            },
+        }
+    }
+    // a listener that went away doesn't acknowledge
+    let finishing = async {
+        let _ = send.finish().await;
+        let _ = session_send.finish().await;
+    };
+    let _ = tokio::time::timeout(REPLY_WAIT, finishing).await;
+    readers.shutdown().await;
+    report::emit(stats.summary());
+    if stats.rtts.is_empty() {
+        return Err("no probe was answered".into());
+    }
+    Ok(())
+}
+
+/// Spawns the task reading the replies into `readers`, passing on their sequence number and
+/// round-trip time. The channel is closed when the peer finishes the stream.
+fn read_replies(
+    readers: &mut JoinSet<()>,
+    mut recv: RecvStream,
+    start: Instant,
+) -> mpsc::Receiver<Result<(u32, Duration), ReadError>> {
+    let (tx, rx) = mpsc::channel(16);
+    readers.spawn(async move {
+        let mut reply = [0u8; PROBE_LEN];
+        // the echoed stream header
+        let mut header = [0u8; 4];
+        if let Err(ReadExactError::ReadError(e)) = recv.read_exact(&mut header).await {
+            let _ = tx.send(Err(e)).await;
+            return;
+        }
+        loop {
+            let read = match recv.read_exact(&mut reply).await {
+                Ok(()) => {
+                    let seq = u32::from_be_bytes(reply[..4].try_into().unwrap());
+                    let sent = u64::from_be_bytes(reply[4..].try_into().unwrap());
+                    let rtt = start.elapsed().saturating_sub(Duration::from_micros(sent));
+                    Ok((seq, rtt))
+                }
+                Err(ReadExactError::FinishedEarly) => return,
+                Err(ReadExactError::ReadError(e)) => Err(e),
+            };
+            let failed = read.is_err();
+            if tx.send(read).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// Echoes probes back to the peer (listening side).
+pub async fn echo(mut send: SendStream, mut recv: RecvStream) {
+    match tokio::io::copy(&mut recv, &mut send).await {
+        Ok(bytes) => debug!("[ping] echoed {} probe(s)", bytes / PROBE_LEN as u64),
+        Err(e) => debug!("[ping] probe stream ended: {}", e),
+    }
+    let _ = send.finish().await;
+}
@@ -0,0 +1,198 @@
+//! External subcommands (`nesquic NAME ARGS...`), like git's and cargo's: an executable named
+//! `nesquic-NAME` on `PATH` is run with the arguments after NAME, so new commands don't need a
+//! fork of nesquic.
+//!
+//! Options given before NAME are nesquic's, and along with the config file they make the
+//! environment of the plugin:
+//! - `NESQUIC`: this executable, to run transfers with,
+//! - `NESQUIC_CONFIG`: the `--config` file, if given,
+//! - `NESQUIC_AUTH_TOKEN` and `NESQUIC_ALPN`: `--auth-token` and `--alpn`, if set,
+//! - `NESQUIC_MUX`: the `--mux` control socket, if given,
+//! - `NESQUIC_FD`: if a `--mux` master is running, a descriptor attached to it, i.e. a new
+//!   stream on the master's connection: what the plugin writes to it goes to the peer, and what
+//!   the peer sends back can be read from it.
+//!
+//! A word that is an IP address, a port or one of nesquic's own subcommands is never taken for a
+//! plugin name.
+
+use std::{
+    env,
+    ffi::OsString,
+    net::IpAddr,
+    path::PathBuf,
+    process::{self, Command},
+};
+
+use clap::Command as ClapCommand;
+use tracing::{debug, error};
+
+use crate::Cli;
+
+const PREFIX: &str = "nesquic-";
+
+/// A plugin to run instead of nesquic itself.
+#[derive(Clone, Debug)]
+pub struct Plugin {
+    name: String,
+    path: PathBuf,
+    args: Vec<OsString>,
+}
+
+/// Splits `nesquic OPTIONS NAME ARGS...` if `nesquic-NAME` is on `PATH`, leaving the options in
+/// `args` and returning the plugin with its arguments.
+pub fn take(command: &mut ClapCommand, args: &mut Vec<OsString>) -> Option<Plugin> {
+    // which options take a value is only settled once built
+    command.build();
+    let position = first_positional(command, args)?;
+    let name = args[position].to_str()?.to_string();
+    let valid_name = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_name
+        || name.starts_with('-')
+        || name.parse::<IpAddr>().is_ok()
+        || name.parse::<u16>().is_ok()
+        || command.find_subcommand(&name).is_some()
+    {
+        return None;
+    }
+    let path = find(&name)?;
+    let plugin_args = args.split_off(position + 1);
+    args.pop();
+    Some(Plugin {
+        name,
+        path,
+        args: plugin_args,
+    })
+}
+
+/// Index of the first argument that isn't an option or an option's value.
+fn first_positional(command: &ClapCommand, args: &[OsString]) -> Option<usize> {
+    let takes_value = |arg: Option<&clap::Arg>| arg.is_some_and(|arg| arg.is_takes_value_set());
+    let mut index = 1;
+    while index < args.len() {
+        let arg = args[index].to_string_lossy();
+        if arg == "--" {
+            return (index + 1 < args.len()).then_some(index + 1);
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let with_value = !long.contains('=')
+                && takes_value(command.get_arguments().find(|a| a.get_long() == Some(long)));
+            if with_value {
+                index += 1;
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // in a cluster like -vvw5s, the first option taking a value takes the rest
+            for (at, short) in shorts.char_indices() {
+                let arg = command
+                    .get_arguments()
+                    .find(|a| a.get_short() == Some(short));
+                if takes_value(arg) {
+                    if at + short.len_utf8() == shorts.len() {
+                        index += 1;
+                    }
+                    break;
+                }
+            }
+        } else {
+            return Some(index);
+        }
+        index += 1;
+    }
+    None
+}
+
+/// `nesquic-NAME` in the first `PATH` directory that has it.
+fn find(name: &str) -> Option<PathBuf> {
+    let file = format!("{}{}{}", PREFIX, name, env::consts::EXE_SUFFIX);
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+}
+
+/// Runs the plugin in place of this process, exiting with its exit code.
+pub fn run(plugin: &Plugin, args: &Cli) -> ! {
+    let mut command = Command::new(&plugin.path);
+    command.args(&plugin.args);
+    if let Ok(exe) = env::current_exe() {
+        command.env("NESQUIC", exe);
+    }
+    if let Some(config) = &args.config {
+        command.env("NESQUIC_CONFIG", config);
+    }
+    if let Some(token) = &args.auth_token {
+        command.env("NESQUIC_AUTH_TOKEN", token);
+    }
+    if !args.alpn.is_empty() {
+        command.env("NESQUIC_ALPN", args.alpn.join(","));
+    }
+    #[cfg(unix)]
+    if let Some(socket) = &args.mux {
+        command.env("NESQUIC_MUX", socket);
+        match unix::attach(socket) {
+            Ok(Some(fd)) => {
+                command.env("NESQUIC_FD", fd.to_string());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!(
+                    "could not attach to the master on {}: {}",
+                    socket.display(),
+                    e
+                );
+                process::exit(1);
+            }
+        }
+    }
+    debug!("running {} ({})", plugin.name, plugin.path.display());
+    let e = exec(&mut command);
+    error!("could not run {}: {}", plugin.path.display(), e);
+    process::exit(1);
+}
+
+#[cfg(unix)]
+fn exec(command: &mut Command) -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+    command.exec()
+}
+
+#[cfg(not(unix))]
+fn exec(command: &mut Command) -> std::io::Error {
+    match command.status() {
+        Ok(status) => process::exit(status.code().unwrap_or(1)),
+        Err(e) => e,
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        io::{self, ErrorKind},
+        os::unix::{
+            io::{IntoRawFd, RawFd},
+            net::UnixStream,
+        },
+        path::Path,
+    };
+
+    /// Attaches to the `--mux` master, returning the descriptor to hand down, or `None` if no
+    /// master is running.
+    pub fn attach(socket: &Path) -> io::Result<Option<RawFd>> {
+        let stream = match UnixStream::connect(socket) {
+            Ok(stream) => stream,
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let fd = stream.into_raw_fd();
+        // SAFETY: plain fcntl calls on a descriptor we own
+        let result = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC)
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(fd))
+    }
+}
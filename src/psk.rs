@@ -0,0 +1,154 @@
+//! Certificates derived from a shared secret (`--psk SECRET`), for secure connections without a
+//! CA.
+//!
+//! Both sides derive the same Ed25519 key pair from the secret (HKDF-SHA256) and present a
+//! self-signed certificate for it: the listener as its server certificate, the client as a client
+//! certificate. Each side accepts the peer's certificate only if it carries that public key, and
+//! TLS makes the peer prove it holds the private key, so only someone who knows the secret can
+//! complete the handshake in either direction. Everything else about the certificates (names,
+//! validity, serial number) is ignored.
+//!
+//! The secret is given in base64 and must be at least 16 bytes, e.g. from
+//! `head -c 32 /dev/urandom | base64`.
+
+use std::{error::Error, fmt, sync::Arc, time::SystemTime};
+
+use base64::Engine;
+use ring::{hkdf, signature::Ed25519KeyPair};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, CertificateError, DistinguishedName, PrivateKey, ServerName,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+const MIN_SECRET_LEN: usize = 16;
+const SALT: &[u8] = b"nesquic-psk";
+const IDENTITY_INFO: &[u8] = b"ed25519 identity";
+/// PKCS #8 v1 encoding of an Ed25519 private key, up to the 32 byte seed.
+const PKCS8_PREFIX: &[u8] = &[
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+/// SubjectPublicKeyInfo of an Ed25519 key, up to the 32 byte public key.
+const SPKI_PREFIX: &[u8] = &[
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Secret both peers derive their identity from.
+#[derive(Clone)]
+pub struct Psk {
+    seed: [u8; 32],
+}
+
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keep the secret out of debug logs
+        f.write_str("Psk(..)")
+    }
+}
+
+struct SeedLen;
+
+impl hkdf::KeyType for SeedLen {
+    fn len(&self) -> usize {
+        32
+    }
+}
+
+/// Parses a base64 secret given on the command line.
+pub fn parse_psk(secret: &str) -> Result<Psk, String> {
+    let secret = base64::engine::general_purpose::STANDARD
+        .decode(secret.trim())
+        .map_err(|e| format!("the secret isn't valid base64: {}", e))?;
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(format!(
+            "the secret must be at least {} bytes, e.g. from `head -c 32 /dev/urandom | base64`",
+            MIN_SECRET_LEN
+        ));
+    }
+    let mut seed = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, SALT)
+        .extract(&secret)
+        .expand(&[IDENTITY_INFO], SeedLen)
+        .and_then(|okm| okm.fill(&mut seed))
+        .map_err(|_| "could not derive a key from the secret")?;
+    Ok(Psk { seed })
+}
+
+impl Psk {
+    /// Public key of the derived identity, as SubjectPublicKeyInfo.
+    fn spki(&self) -> Vec<u8> {
+        let pair = Ed25519KeyPair::from_seed_unchecked(&self.seed).expect("valid Ed25519 seed");
+        let mut spki = SPKI_PREFIX.to_vec();
+        spki.extend_from_slice(ring::signature::KeyPair::public_key(&pair).as_ref());
+        spki
+    }
+
+    /// Self-signed certificate for the derived key pair.
+    pub fn identity(&self) -> Result<(Vec<Certificate>, PrivateKey), Box<dyn Error>> {
+        let mut pkcs8 = PKCS8_PREFIX.to_vec();
+        pkcs8.extend_from_slice(&self.seed);
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".into()]);
+        params.alg = &rcgen::PKCS_ED25519;
+        params.key_pair = Some(rcgen::KeyPair::from_der(&pkcs8)?);
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok((
+            vec![Certificate(cert.serialize_der()?)],
+            PrivateKey(cert.serialize_private_key_der()),
+        ))
+    }
+
+    /// Accepts only certificates for the derived key pair.
+    pub fn verifier(&self) -> Arc<PinnedKey> {
+        Arc::new(PinnedKey { spki: self.spki() })
+    }
+}
+
+/// Verifier of server and client certificates accepting only a given public key.
+pub struct PinnedKey {
+    spki: Vec<u8>,
+}
+
+impl PinnedKey {
+    fn check(&self, end_entity: &Certificate) -> Result<(), rustls::Error> {
+        let (_, cert) = X509Certificate::from_der(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if cert.public_key().raw != self.spki.as_slice() {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for PinnedKey {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
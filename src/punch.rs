@@ -0,0 +1,119 @@
+//! NAT hole punching (`--punch`).
+//!
+//! Both peers register with a relay using the same token, from an endpoint that can both accept
+//! and initiate connections. The relay tells each one the address it sees the other at, and both
+//! send QUIC packets straight to each other: the dialer connects to the acceptor while the
+//! acceptor fires a connection attempt of its own to open its NAT mapping. Since the relay
+//! connection shares the same UDP socket, the addresses observed by the relay are the ones the
+//! NATs will let through. If either side fails to get the direct connection in time, both fall
+//! back to having the relay splice their streams.
+
+use std::{error::Error, net::SocketAddr, time::Duration};
+
+use quinn::{Connection, Endpoint, VarInt};
+use tracing::{debug, info};
+
+use crate::relay::{self, HelloKind, Role};
+use crate::sched::Share;
+use crate::util::{client_bind_addr, configure_client, make_server_endpoint};
+use crate::{auth, report, timers};
+use crate::{run_session, session_error, Cli};
+
+/// How long to try establishing the direct connection before falling back to the relay.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Registers with the relay at `relay_addr`, attempts a direct connection to the peer with the
+/// same token and runs the session over whichever path worked.
+pub async fn run_punch(relay_addr: SocketAddr, args: &Cli) -> Result<(), Box<dyn Error>> {
+    let token = args.token.as_deref().ok_or("--punch requires --token")?;
+    let (mut endpoint, _server_cert) =
+        make_server_endpoint(&[client_bind_addr(relay_addr, args)], args)?;
+    endpoint.set_default_client_config(configure_client(args));
+
+    let connecting = endpoint.connect(relay_addr, "127.0.0.1")?;
+    let relay_conn = timers::connect(connecting, args.connect_timeout).await?;
+    report::connection(&relay_conn);
+    let (mut send, mut recv) = timers::open_bi(&relay_conn).await?;
+    auth::send(&relay_conn, &mut send, args.auth_token.as_deref()).await?;
+    relay::send_hello(&mut send, HelloKind::Punch, token).await?;
+    info!("[punch] registered with relay, waiting for peer");
+
+    let (role, peer_addr) = relay::read_rendezvous(&mut recv).await?;
+    info!("[punch] peer is at {}, acting as {:?}", peer_addr, role);
+    let direct = match role {
+        Role::Dialer => dial(&endpoint, peer_addr).await,
+        Role::Acceptor => accept(&endpoint, peer_addr).await,
+    };
+    send.write_all(&[direct.is_some() as u8]).await?;
+    let mut verdict = [0u8];
+    recv.read_exact(&mut verdict).await?;
+
+    match direct {
+        Some(conn) if verdict[0] == 1 => {
+            info!("[punch] direct connection to {} established", peer_addr);
+            report::connection(&conn);
+            relay_conn.close(VarInt::from_u32(0), b"direct");
+            // the peer was only vouched for by the relay, with a secret it proves itself directly
+            let secret = args.auth_token.as_deref();
+            let (send, recv) = match role {
+                Role::Dialer => {
+                    let (mut send, recv) = timers::open_bi(&conn).await?;
+                    auth::send(&conn, &mut send, secret).await?;
+                    (send, recv)
+                }
+                Role::Acceptor => {
+                    let (send, mut recv) = conn.accept_bi().await?;
+                    if !auth::verify(&conn, &mut recv, secret).await {
+                        return Err("the peer failed to authenticate".into());
+                    }
+                    (send, recv)
+                }
+            };
+            let session = run_session(&conn, send, recv, Share::exclusive(), args).await;
+            session_error(&conn, session)
+        }
+        direct => {
+            if let Some(conn) = direct {
+                conn.close(VarInt::from_u32(0), b"falling back to relay");
+            }
+            info!("[punch] direct connection failed, falling back to relay");
+            let session = run_session(&relay_conn, send, recv, Share::exclusive(), args).await;
+            session_error(&relay_conn, session)
+        }
+    }
+}
+
+/// Connects to the acceptor, giving up after [`PUNCH_TIMEOUT`].
+async fn dial(endpoint: &Endpoint, peer: SocketAddr) -> Option<Connection> {
+    let connecting = endpoint.connect(peer, "127.0.0.1").ok()?;
+    match tokio::time::timeout(PUNCH_TIMEOUT, connecting).await {
+        Ok(Ok(conn)) => Some(conn),
+        Ok(Err(e)) => {
+            debug!("[punch] direct connection failed: {}", e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Waits for the dialer's connection while sending our own attempt towards it so our NAT lets
+/// its packets in. Our attempt is abandoned once the dialer got through.
+async fn accept(endpoint: &Endpoint, peer: SocketAddr) -> Option<Connection> {
+    let opener = endpoint.connect(peer, "127.0.0.1").ok();
+    let accepted = tokio::time::timeout(PUNCH_TIMEOUT, async {
+        while let Some(connecting) = endpoint.accept().await {
+            match connecting.await {
+                Ok(conn) if conn.remote_address() == peer => return Some(conn),
+                Ok(conn) => {
+                    debug!("[punch] ignoring connection from {}", conn.remote_address());
+                    conn.close(VarInt::from_u32(0), b"unexpected peer");
+                }
+                Err(e) => debug!("[punch] incoming direct connection failed: {}", e),
+            }
+        }
+        None
+    });
+    let conn = accepted.await.ok().flatten();
+    drop(opener);
+    conn
+}
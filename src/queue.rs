@@ -0,0 +1,292 @@
+//! Queued outbound transfers (`nesquic queue add JOB`, `--queue-runner`).
+//!
+//! A job is a TOML file with the same keys as the config file, which become the transfer's
+//! options and address, plus a few of its own:
+//!
+//! ```toml
+//! addr = ["203.0.113.7", "5003"]
+//! limit-rate = "5MiB/s"
+//! input = "/srv/backups/db.tar"   # sent as stdin, nothing if missing
+//! output = "/srv/backups/db.ack"  # where received data goes, dropped if missing
+//! attempts = 5                    # default 3
+//! ```
+//!
+//! `queue add` checks the job and copies it to the `pending` directory of the queue
+//! (`~/.local/state/nesquic/queue` unless `--queue-dir` says otherwise). The runner takes pending
+//! jobs oldest first and runs each one as a separate nesquic process, retrying failed attempts
+//! with a growing delay. Finished jobs are moved to `done` or `failed`, and the outcome is
+//! appended to `results.jsonl`. Jobs only leave `pending` once they're finished, so a job
+//! interrupted by a reboot runs again when the runner is started back up.
+
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    iter,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use tokio::{process::Command, time::sleep};
+use toml::{Table, Value};
+use tracing::{error, info, warn};
+
+use crate::{config, Cli};
+
+const INPUT_KEY: &str = "input";
+const OUTPUT_KEY: &str = "output";
+const ATTEMPTS_KEY: &str = "attempts";
+const DEFAULT_ATTEMPTS: u32 = 3;
+
+const PENDING: &str = "pending";
+const DONE: &str = "done";
+const FAILED: &str = "failed";
+const RESULTS: &str = "results.jsonl";
+
+/// How often the runner looks for new jobs when the queue is empty.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Subcommand)]
+pub enum QueueAction {
+    /// Check a job file and add it to the queue
+    Add {
+        #[clap(value_name = "JOB", value_parser)]
+        job: PathBuf,
+    },
+}
+
+/// A transfer ready to run.
+struct Job {
+    args: Vec<OsString>,
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    attempts: u32,
+}
+
+impl Job {
+    fn parse(mut table: Table) -> Result<Self, Box<dyn Error>> {
+        let input = table.remove(INPUT_KEY).map(path).transpose()?;
+        let output = table.remove(OUTPUT_KEY).map(path).transpose()?;
+        let attempts = match table.remove(ATTEMPTS_KEY) {
+            None => DEFAULT_ATTEMPTS,
+            Some(Value::Integer(attempts)) if attempts >= 1 => attempts as u32,
+            Some(_) => return Err("'attempts' must be a positive integer".into()),
+        };
+        let args = config::job_args(&table)?;
+        let cli = Cli::try_parse_from(iter::once(OsString::from("nesquic")).chain(args.clone()))?;
+        if cli.listen || cli.relay || cli.addr.len() != 2 {
+            return Err("jobs must connect to a server given as addr = [IP, PORT]".into());
+        }
+        Ok(Job {
+            args,
+            input,
+            output,
+            attempts,
+        })
+    }
+
+    async fn run(&self) -> Result<ExitStatus, Box<dyn Error>> {
+        let stdin = match &self.input {
+            Some(input) => Stdio::from(File::open(input)?),
+            None => Stdio::null(),
+        };
+        let stdout = match &self.output {
+            Some(output) => Stdio::from(File::create(output)?),
+            None => Stdio::null(),
+        };
+        Ok(Command::new(env::current_exe()?)
+            .args(&self.args)
+            .stdin(stdin)
+            .stdout(stdout)
+            .status()
+            .await?)
+    }
+}
+
+fn path(value: Value) -> Result<PathBuf, Box<dyn Error>> {
+    match value {
+        Value::String(path) => Ok(path.into()),
+        _ => Err("'input' and 'output' must be paths".into()),
+    }
+}
+
+fn load(path: &Path) -> Result<Table, Box<dyn Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("could not read job {}: {}", path.display(), e))?;
+    Ok(text
+        .parse()
+        .map_err(|e| format!("invalid job {}: {}", path.display(), e))?)
+}
+
+/// `--queue-dir`, `$XDG_STATE_HOME/nesquic/queue`, `~/.local/state/nesquic/queue` or
+/// `%LOCALAPPDATA%\nesquic\queue`.
+fn queue_dir(args: &Cli) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(dir) = &args.queue_dir {
+        return Ok(dir.clone());
+    }
+    let dir = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .ok_or("no queue directory, pass --queue-dir")?;
+    Ok(dir.join("nesquic").join("queue"))
+}
+
+/// Runs `nesquic queue ...`.
+pub fn command(action: &QueueAction, args: &Cli) -> Result<(), Box<dyn Error>> {
+    match action {
+        QueueAction::Add { job } => {
+            let id = add(job, &queue_dir(args)?)?;
+            println!("{}", id);
+            Ok(())
+        }
+    }
+}
+
+/// Checks the job and stores it as pending, returning its id.
+fn add(path: &Path, dir: &Path) -> Result<String, Box<dyn Error>> {
+    let mut table = load(path)?;
+    // the runner doesn't run from the current directory
+    let cwd = env::current_dir()?;
+    for key in [INPUT_KEY, OUTPUT_KEY] {
+        if let Some(Value::String(file)) = table.get(key) {
+            let file = cwd.join(file).to_string_lossy().into_owned();
+            table.insert(key.to_string(), Value::String(file));
+        }
+    }
+    Job::parse(table.clone())?;
+
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let id = format!("{}-{}", chrono::Utc::now().format("%Y%m%dT%H%M%S%6f"), name);
+    let pending = dir.join(PENDING);
+    fs::create_dir_all(&pending)?;
+    // written next to the queue and renamed, so the runner never sees half a job
+    let tmp = dir.join(format!(".{}.toml", id));
+    fs::write(&tmp, toml::to_string(&table)?)?;
+    fs::rename(&tmp, pending.join(format!("{}.toml", id)))?;
+    Ok(id)
+}
+
+/// Outcome of a job, as recorded in `results.jsonl`.
+#[derive(Serialize)]
+struct JobResult {
+    job: String,
+    status: &'static str,
+    attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    started: String,
+    finished: String,
+}
+
+/// Runs queued jobs one after the other, forever.
+pub async fn run(args: &Cli) -> Result<(), Box<dyn Error>> {
+    let dir = queue_dir(args)?;
+    for sub in [PENDING, DONE, FAILED] {
+        fs::create_dir_all(dir.join(sub))?;
+    }
+    info!("[queue] running jobs from {}", dir.display());
+    loop {
+        match next_job(&dir)? {
+            Some(path) => finish(&dir, &path, run_job(&path).await)?,
+            None => sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// Oldest pending job, if any.
+fn next_job(dir: &Path) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let mut jobs: Vec<PathBuf> = fs::read_dir(dir.join(PENDING))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    jobs.sort();
+    Ok(jobs.into_iter().next())
+}
+
+async fn run_job(path: &Path) -> JobResult {
+    let id = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut result = JobResult {
+        job: id.to_string(),
+        status: FAILED,
+        attempts: 0,
+        exit_code: None,
+        error: None,
+        started: chrono::Utc::now().to_rfc3339(),
+        finished: String::new(),
+    };
+    let job = match load(path).and_then(Job::parse) {
+        Ok(job) => job,
+        Err(e) => {
+            error!("[queue] job {}: {}", id, e);
+            result.error = Some(e.to_string());
+            result.finished = chrono::Utc::now().to_rfc3339();
+            return result;
+        }
+    };
+
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 1..=job.attempts {
+        info!("[queue] job {}: attempt {}/{}", id, attempt, job.attempts);
+        result.attempts = attempt;
+        match job.run().await {
+            Ok(status) if status.success() => {
+                result.status = DONE;
+                result.exit_code = status.code();
+                result.error = None;
+                break;
+            }
+            Ok(status) => {
+                result.exit_code = status.code();
+                result.error = Some(format!("nesquic {}", status));
+            }
+            Err(e) => {
+                result.exit_code = None;
+                result.error = Some(e.to_string());
+            }
+        }
+        if attempt < job.attempts {
+            warn!(
+                "[queue] job {} failed ({}), retrying in {:?}",
+                id,
+                result.error.as_deref().unwrap_or_default(),
+                delay
+            );
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+    if result.status == FAILED {
+        error!(
+            "[queue] job {} failed after {} attempts: {}",
+            id,
+            result.attempts,
+            result.error.as_deref().unwrap_or_default()
+        );
+    } else {
+        info!("[queue] job {} done", id);
+    }
+    result.finished = chrono::Utc::now().to_rfc3339();
+    result
+}
+
+/// Records the outcome and moves the job out of the pending directory.
+fn finish(dir: &Path, path: &Path, result: JobResult) -> Result<(), Box<dyn Error>> {
+    let mut results = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(RESULTS))?;
+    writeln!(results, "{}", serde_json::to_string(&result)?)?;
+    let name = path.file_name().unwrap_or_default();
+    fs::rename(path, dir.join(result.status).join(name))?;
+    Ok(())
+}
@@ -0,0 +1,218 @@
+//! Send-side throughput shaping.
+//!
+//! A [`TokenBucket`] paces writes to a configured rate (`--limit-rate`). Its rate can be changed at
+//! any time, which is what the `--rate-schedule` scheduler task does to throttle during given
+//! hours of the day, never going above `--limit-rate` when both are given.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{Local, NaiveTime, Timelike};
+use tokio::time::{sleep, Instant};
+use tracing::info;
+
+/// Token bucket pacing writes to `rate` bytes per second, allowing bursts of up to one second
+/// worth of data. A rate of `None` means unlimited.
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    rate: Option<u64>,
+    /// Available bytes, negative when a write larger than the balance was let through.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.refill();
+        state.rate = rate;
+        if let Some(rate) = rate {
+            state.tokens = state.tokens.min(rate as f64);
+        }
+    }
+
+    /// Waits until `bytes` may be sent.
+    pub async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let Some(rate) = state.rate else {
+                return;
+            };
+            state.refill();
+            state.tokens -= bytes as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / rate as f64)
+        };
+        sleep(wait).await;
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last_refill = now;
+    }
+}
+
+/// Parses a rate such as `500KBps`, `1MBps`, `5MiB/s` or `unlimited` into bytes per second.
+pub fn parse_rate(rate: &str) -> Result<Option<u64>, String> {
+    if rate.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    let unit_start = rate
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rate.len());
+    let (value, unit) = rate.split_at(unit_start);
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid rate '{}'", rate))?;
+    let unit = unit
+        .strip_suffix("/s")
+        .or_else(|| unit.strip_suffix("ps"))
+        .unwrap_or(unit);
+    let multiplier: u64 = match unit {
+        "" | "B" => 1,
+        "K" | "KB" => 1000,
+        "M" | "MB" => 1000 * 1000,
+        "G" | "GB" => 1000 * 1000 * 1000,
+        "Ki" | "KiB" => 1024,
+        "Mi" | "MiB" => 1024 * 1024,
+        "Gi" | "GiB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown unit in rate '{}'", rate)),
+    };
+    let bytes = (value * multiplier as f64) as u64;
+    if bytes == 0 {
+        return Err(format!("rate '{}' is zero", rate));
+    }
+    Ok(Some(bytes))
+}
+
+/// Parses a `--limit-rate` value, which unlike schedule entries can't be `unlimited`.
+pub fn parse_limit(rate: &str) -> Result<u64, String> {
+    parse_rate(rate)?.ok_or_else(|| "use a finite rate, e.g. 5MiB/s".to_string())
+}
+
+/// Lowest of two rates, `None` being unlimited.
+pub fn min_rate(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Formats a rate in bytes per second with a decimal unit, e.g. `12.35 MB/s`.
+pub fn format_rate(bytes_per_second: f64) -> String {
+    let units = ["B/s", "KB/s", "MB/s", "GB/s"];
+    let mut value = bytes_per_second;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, units[unit])
+}
+
+/// Time-of-day rate schedule, e.g. `08:00-18:00=1MBps,else=unlimited`.
+///
+/// Windows are checked in order and may wrap around midnight (`22:00-06:00`). Times not covered by
+/// any window use the `else` rate, unlimited if omitted.
+#[derive(Clone, Debug)]
+pub struct RateSchedule {
+    windows: Vec<(NaiveTime, NaiveTime, Option<u64>)>,
+    default: Option<u64>,
+}
+
+impl RateSchedule {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut schedule = RateSchedule {
+            windows: Vec::new(),
+            default: None,
+        };
+        for entry in spec.split(',') {
+            let (window, rate) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected WINDOW=RATE, got '{}'", entry))?;
+            let rate = parse_rate(rate.trim())?;
+            let window = window.trim();
+            if window == "else" {
+                schedule.default = rate;
+                continue;
+            }
+            let (start, end) = window
+                .split_once('-')
+                .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{}'", window))?;
+            let parse_time = |time: &str| {
+                NaiveTime::parse_from_str(time, "%H:%M")
+                    .map_err(|_| format!("invalid time '{}', expected HH:MM", time))
+            };
+            schedule
+                .windows
+                .push((parse_time(start)?, parse_time(end)?, rate));
+        }
+        Ok(schedule)
+    }
+
+    /// Rate that applies at the given time of day.
+    pub fn rate_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|(start, end, _)| {
+                if start <= end {
+                    *start <= time && time < *end
+                } else {
+                    *start <= time || time < *end
+                }
+            })
+            .map_or(self.default, |(_, _, rate)| *rate)
+    }
+
+    /// Rate that applies right now.
+    pub fn current_rate(&self) -> Option<u64> {
+        self.rate_at(Local::now().time())
+    }
+
+    /// Keeps `bucket` at the scheduled rate, but never above `cap`, re-evaluating at every minute
+    /// boundary.
+    pub async fn run(self, bucket: Arc<TokenBucket>, cap: Option<u64>) {
+        let mut current = bucket.rate();
+        loop {
+            let now = Local::now().time();
+            let rate = min_rate(self.rate_at(now), cap);
+            if current != rate {
+                match rate {
+                    Some(rate) => info!("rate schedule: limiting to {} bytes/s", rate),
+                    None => info!("rate schedule: unlimited"),
+                }
+                bucket.set_rate(rate);
+                current = rate;
+            }
+            let to_next_minute = 60 - now.second() as u64;
+            sleep(Duration::from_secs(to_next_minute)).await;
+        }
+    }
+}
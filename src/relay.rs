@@ -0,0 +1,305 @@
+//! Broker/relay mode (`--relay`).
+//!
+//! The relay accepts client connections, reads a rendezvous hello carrying a shared token from
+//! the first bidirectional stream of each, and splices the streams of two clients presenting the
+//! same token together. This lets two peers that can't reach each other (e.g. both behind NAT)
+//! exchange data through a public relay.
+//!
+//! Hello format: `NQR1` magic, one byte token length, token bytes.
+//!
+//! Peers using `--punch` send an `NQP1` hello instead. Once paired, the relay tells each of them
+//! its role and the address it observed for the other one ([`read_rendezvous`]), both attempt a
+//! direct connection, report whether it worked with a single byte, and the relay answers with
+//! the verdict: `1` means both sides got the direct connection and the relay steps aside, `0`
+//! means the relay splices their streams as in plain relay mode.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    error::Error,
+    net::SocketAddr,
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use quinn::{Connection, Endpoint, RecvStream, SendStream, VarInt};
+use tokio::{sync::oneshot, task::JoinSet};
+use tracing::{debug, error, info};
+
+use crate::pending::Queue;
+use crate::util::make_server_endpoint;
+use crate::Cli;
+use crate::{auth, metrics, report, tasks};
+
+const HELLO_MAGIC: &[u8; 4] = b"NQR1";
+const PUNCH_MAGIC: &[u8; 4] = b"NQP1";
+/// How long a freshly connected client has to send its hello.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long punching peers have to report the outcome of their direct connection attempts.
+const PUNCH_RESULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Application close code used when a client sends a malformed hello, or none in time.
+const BAD_HELLO: u32 = 1;
+/// Application close code used when relaying for a client with a valid hello fails.
+const RELAY_FAILED: u32 = 13;
+
+type Streams = (SendStream, RecvStream);
+
+/// What a client asks the relay for in its hello.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HelloKind {
+    /// Splice my streams with my peer's.
+    Relay,
+    /// Exchange addresses with my peer so we can attempt a direct connection first.
+    Punch,
+}
+
+/// Role of a peer in a hole punching attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Waits for the other peer's connection (while sending its own to open the NAT).
+    Acceptor,
+    /// Connects to the other peer.
+    Dialer,
+}
+
+/// A client's streams and the address the relay sees it at.
+type Peer = (Streams, SocketAddr);
+
+/// Clients waiting for their peer, by token.
+type Waiting = Arc<Mutex<HashMap<(HelloKind, String), oneshot::Sender<Peer>>>>;
+
+/// Checks a token given on the command line.
+pub fn parse_token(token: &str) -> Result<String, String> {
+    if token.is_empty() || token.len() > u8::MAX as usize {
+        return Err("tokens must be 1 to 255 bytes long".into());
+    }
+    Ok(token.to_string())
+}
+
+/// Sends the rendezvous hello identifying this client to the relay.
+pub async fn send_hello(
+    send: &mut SendStream,
+    kind: HelloKind,
+    token: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut hello = Vec::with_capacity(HELLO_MAGIC.len() + 1 + token.len());
+    hello.extend_from_slice(match kind {
+        HelloKind::Relay => HELLO_MAGIC,
+        HelloKind::Punch => PUNCH_MAGIC,
+    });
+    hello.push(token.len() as u8);
+    hello.extend_from_slice(token.as_bytes());
+    send.write_all(&hello).await?;
+    Ok(())
+}
+
+/// Reads a rendezvous hello and returns what it asks for and the token it carries.
+pub async fn read_hello(recv: &mut RecvStream) -> Result<(HelloKind, String), Box<dyn Error>> {
+    let mut header = [0u8; 5];
+    recv.read_exact(&mut header).await?;
+    let kind = match &header[..4] {
+        magic if magic == HELLO_MAGIC => HelloKind::Relay,
+        magic if magic == PUNCH_MAGIC => HelloKind::Punch,
+        _ => return Err("not a nesquic relay hello".into()),
+    };
+    let mut token = vec![0u8; header[4] as usize];
+    recv.read_exact(&mut token).await?;
+    Ok((kind, String::from_utf8(token)?))
+}
+
+/// Tells a punching peer its role and where the relay sees the other peer.
+async fn send_rendezvous(
+    send: &mut SendStream,
+    role: Role,
+    peer: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let addr = peer.to_string();
+    let mut msg = vec![(role == Role::Dialer) as u8, addr.len() as u8];
+    msg.extend_from_slice(addr.as_bytes());
+    send.write_all(&msg).await?;
+    Ok(())
+}
+
+/// Reads the role and peer address sent by the relay once a punching peer was paired.
+pub async fn read_rendezvous(recv: &mut RecvStream) -> Result<(Role, SocketAddr), Box<dyn Error>> {
+    let mut header = [0u8; 2];
+    recv.read_exact(&mut header).await?;
+    let role = match header[0] {
+        0 => Role::Acceptor,
+        1 => Role::Dialer,
+        _ => return Err("invalid role in rendezvous message".into()),
+    };
+    let mut addr = vec![0u8; header[1] as usize];
+    recv.read_exact(&mut addr).await?;
+    Ok((role, String::from_utf8(addr)?.parse()?))
+}
+
+/// Runs a relay bound to given addrs, pairing clients by token until killed.
+pub async fn run_relay(addrs: &[SocketAddr], args: &Cli) {
+    let (endpoint, _server_cert) = match make_server_endpoint(addrs, args) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("could not start the relay: {}", e);
+            process::exit(1);
+        }
+    };
+    info!("[relay] running on {:?}, waiting on clients...", addrs);
+    if args.daemon {
+        crate::daemon::run(&endpoint, args, serve(&endpoint, args)).await;
+        return;
+    }
+    serve(&endpoint, args).await;
+}
+
+async fn serve(endpoint: &Endpoint, args: &Cli) {
+    let waiting: Waiting = Arc::default();
+    let mut queue = Queue::new(endpoint, args);
+    let secret = Arc::new(args.auth_token.clone());
+    let mut clients = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(connecting) = queue.accept() => {
+                let waiting = waiting.clone();
+                let secret = secret.clone();
+                clients.spawn(async move {
+                    let conn = match connecting.await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            debug!("[relay] handshake failed: {}", e);
+                            metrics::handshake_failed();
+                            return;
+                        }
+                    };
+                    report::connection(&conn);
+                    if let Err(e) = handle_client(&conn, waiting, secret.as_deref()).await {
+                        error!("[relay] client {}: {}", conn.remote_address(), e);
+                        // a no-op if the hello was bad, which closed the connection already
+                        conn.close(VarInt::from_u32(RELAY_FAILED), b"relay failed");
+                    }
+                });
+            }
+            Some(joined) = clients.join_next() => {
+                tasks::output(joined, "[relay] client");
+            }
+            else => break,
+        }
+    }
+}
+
+/// Reads the hello of a client and either waits for its peer or splices it with a waiting one.
+async fn handle_client(
+    conn: &Connection,
+    waiting: Waiting,
+    secret: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let (send, mut recv) = conn.accept_bi().await?;
+    if !auth::verify(conn, &mut recv, secret).await {
+        return Ok(());
+    }
+    let hello = match tokio::time::timeout(HELLO_TIMEOUT, read_hello(&mut recv)).await {
+        Ok(Ok(hello)) => hello,
+        Ok(Err(e)) => {
+            conn.close(VarInt::from_u32(BAD_HELLO), b"bad hello");
+            return Err(e);
+        }
+        Err(_) => {
+            conn.close(VarInt::from_u32(BAD_HELLO), b"bad hello");
+            return Err("no hello in time".into());
+        }
+    };
+    debug!(
+        "[relay] {} presented a token ({:?})",
+        conn.remote_address(),
+        hello.0
+    );
+
+    // looked up and registered under one lock, so two clients with the same token can't both
+    // miss each other
+    let (tx, rx) = oneshot::channel();
+    let peer = match waiting.lock().unwrap().entry(hello.clone()) {
+        Entry::Occupied(peer) => Some(peer.remove()),
+        Entry::Vacant(vacant) => {
+            vacant.insert(tx);
+            None
+        }
+    };
+    match peer {
+        Some(peer) => {
+            // the waiting client does the splicing, we only hand over our streams
+            if peer.send(((send, recv), conn.remote_address())).is_err() {
+                return Err("peer went away before pairing".into());
+            }
+            info!("[relay] paired {}", conn.remote_address());
+            conn.closed().await;
+        }
+        None => {
+            info!("[relay] {} waiting for its peer", conn.remote_address());
+            let (peer, peer_addr) = tokio::select! {
+                peer = rx => peer?,
+                _ = conn.closed() => {
+                    waiting.lock().unwrap().remove(&hello);
+                    return Ok(());
+                }
+            };
+            let mut ours = (send, recv);
+            let mut peer = peer;
+            if hello.0 == HelloKind::Punch
+                && arbitrate_punch(&mut ours, conn.remote_address(), &mut peer, peer_addr).await?
+            {
+                info!(
+                    "[relay] {} and {} connected directly",
+                    conn.remote_address(),
+                    peer_addr
+                );
+                // let the verdict reach the peer, it closes the connection once it's read
+                conn.closed().await;
+                return Ok(());
+            }
+            splice(ours, peer).await;
+            info!("[relay] session of {} finished", conn.remote_address());
+        }
+    }
+    Ok(())
+}
+
+/// Sends both punching peers their rendezvous message, collects whether their direct connection
+/// attempts worked and announces the verdict. Returns whether the peers went direct.
+async fn arbitrate_punch(
+    a: &mut Streams,
+    a_addr: SocketAddr,
+    b: &mut Streams,
+    b_addr: SocketAddr,
+) -> Result<bool, Box<dyn Error>> {
+    send_rendezvous(&mut a.0, Role::Acceptor, b_addr).await?;
+    send_rendezvous(&mut b.0, Role::Dialer, a_addr).await?;
+
+    let mut a_result = [0u8];
+    let mut b_result = [0u8];
+    let results = tokio::time::timeout(PUNCH_RESULT_TIMEOUT, async {
+        tokio::try_join!(a.1.read_exact(&mut a_result), b.1.read_exact(&mut b_result))
+    });
+    results.await??;
+
+    let direct = a_result[0] == 1 && b_result[0] == 1;
+    a.0.write_all(&[direct as u8]).await?;
+    b.0.write_all(&[direct as u8]).await?;
+    Ok(direct)
+}
+
+/// Copies data both ways between two clients' streams until both directions are finished.
+pub async fn splice(a: Streams, b: Streams) {
+    let (send_a, recv_a) = a;
+    let (send_b, recv_b) = b;
+    let _ = tokio::join!(pipe(recv_a, send_b), pipe(recv_b, send_a));
+}
+
+/// Copies one direction, finishing `send` once `recv` reaches EOF.
+async fn pipe(
+    mut recv: RecvStream,
+    mut send: SendStream,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let bytes = tokio::io::copy(&mut recv, &mut send).await?;
+    debug!("[relay] forwarded {} bytes", bytes);
+    send.finish().await?;
+    Ok(())
+}
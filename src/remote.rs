@@ -0,0 +1,124 @@
+//! Exchange of connection statistics between peers (`--remote-stats`).
+//!
+//! Loss only shows up on the side that sent the lost packets, so each side's statistics hide what
+//! happened in the other direction. With `--remote-stats` on both sides, each one sends its view
+//! of the connection (packets sent and lost, bytes received and the current receive rate) every
+//! [`INTERVAL`] in a QUIC datagram, and the close summary includes the peer's latest view next to
+//! our own. Datagrams may be lost too, so the peer's view can be up to a few intervals old.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use quinn::Connection;
+use tracing::debug;
+
+use crate::{report::PeerView, tasks, Cli};
+
+/// How often each side sends its view.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+/// Leading bytes of a stats datagram.
+const MAGIC: &[u8; 4] = b"NQRS";
+const LEN: usize = MAGIC.len() + 5 * 8;
+
+/// Whether views are exchanged on every connection.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init(args: &Cli) {
+    ENABLED.store(args.remote_stats, Ordering::Relaxed);
+}
+
+/// Latest view received from the peer.
+#[derive(Clone, Default)]
+pub struct Latest(Arc<Mutex<Option<PeerView>>>);
+
+impl Latest {
+    pub fn get(&self) -> Option<PeerView> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Starts sending our view to the peer and collecting its own, if enabled.
+pub fn exchange(conn: &Connection) -> Option<Latest> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let latest = Latest::default();
+    tasks::spawn(send_views(conn.clone()));
+    tasks::spawn(recv_views(conn.clone(), latest.clone()));
+    Some(latest)
+}
+
+async fn send_views(conn: Connection) {
+    let mut ticks = tokio::time::interval(INTERVAL);
+    let mut last = (Instant::now(), 0);
+    loop {
+        tokio::select! {
+            _ = conn.closed() => return,
+            _ = ticks.tick() => {}
+        }
+        let stats = conn.stats();
+        let now = Instant::now();
+        let received = stats.udp_rx.bytes;
+        let seconds = now.duration_since(last.0).as_secs_f64();
+        let rate = if seconds > 0.0 {
+            (received - last.1) as f64 / seconds
+        } else {
+            0.0
+        };
+        last = (now, received);
+        let view = PeerView {
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            received_bytes: received,
+            receive_rate: rate,
+        };
+        if let Err(e) = conn.send_datagram(encode(&view)) {
+            debug!("[remote-stats] could not send our view: {}", e);
+            return;
+        }
+    }
+}
+
+async fn recv_views(conn: Connection, latest: Latest) {
+    while let Ok(datagram) = conn.read_datagram().await {
+        match decode(&datagram) {
+            Some(view) => *latest.0.lock().unwrap() = Some(view),
+            None => debug!("[remote-stats] ignoring unexpected datagram"),
+        }
+    }
+}
+
+fn encode(view: &PeerView) -> Bytes {
+    let mut datagram = BytesMut::with_capacity(LEN);
+    datagram.put_slice(MAGIC);
+    datagram.put_u64(view.sent_packets);
+    datagram.put_u64(view.lost_packets);
+    datagram.put_u64(view.lost_bytes);
+    datagram.put_u64(view.received_bytes);
+    datagram.put_u64(view.receive_rate as u64);
+    datagram.freeze()
+}
+
+fn decode(datagram: &[u8]) -> Option<PeerView> {
+    if datagram.len() != LEN || !datagram.starts_with(MAGIC) {
+        return None;
+    }
+    let field = |i: usize| {
+        let start = MAGIC.len() + i * 8;
+        u64::from_be_bytes(datagram[start..start + 8].try_into().unwrap())
+    };
+    Some(PeerView {
+        sent_packets: field(0),
+        lost_packets: field(1),
+        lost_bytes: field(2),
+        received_bytes: field(3),
+        receive_rate: field(4) as f64,
+    })
+}
@@ -0,0 +1,672 @@
+//! Connection events on stderr (`-v`, `-vv`, `--log-format json`).
+//!
+//! Everything nesquic reports about connections and streams goes through [`emit`] as an [`Event`].
+//! In the default text format, events are printed as human-readable lines depending on the
+//! verbosity: with `-v`, the handshake outcome, the nesquic version of the peer, changes of the
+//! peer address and why the connection closed; `-vv` adds the peer certificate, path details,
+//! streams and transferred bytes. With `--log-format json`, every event is printed as one JSON
+//! object per line regardless of the verbosity, and so are errors logged through `tracing`, so
+//! orchestration tooling can follow along. This is independent of the `RUST_LOG` tracing output,
+//! which is aimed at developers.
+//!
+//! The negotiated cipher suite isn't exposed by quinn, so only the TLS version is shown. Neither
+//! is path validation: only changes of the peer address are reported, and a new path that fails
+//! validation shows up as a change back to the previous address.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{stderr, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use quinn::{Connection, StreamId};
+use rustls::Certificate;
+use serde::Serialize;
+use tracing::{field::Field, Level, Subscriber};
+use tracing_subscriber::{field::Visit, layer::Context, Layer};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{counters, negotiation, rate, remote, tasks, Cli};
+
+/// How often the peer address is checked for migrations.
+const PATH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Output format of the reported events (`--log-format`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+pub fn init(args: &Cli) {
+    VERBOSITY.store(args.verbose, Ordering::Relaxed);
+    let _ = FORMAT.set(args.log_format);
+}
+
+fn json() -> bool {
+    FORMAT.get() == Some(&LogFormat::Json)
+}
+
+fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Something worth reporting.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connect {
+        peer: SocketAddr,
+        alpn: Option<String>,
+        version: Option<String>,
+    },
+    Transport {
+        /// Largest datagram the peer takes, if it takes any.
+        max_datagram: Option<usize>,
+    },
+    PeerVersion {
+        peer: SocketAddr,
+        /// nesquic version the peer runs, if it said.
+        version: Option<String>,
+        protocol: Option<u16>,
+    },
+    Certificate {
+        subject: Option<String>,
+        issuer: Option<String>,
+        not_before: Option<String>,
+        not_after: Option<String>,
+        sha256: String,
+        chain_length: usize,
+    },
+    WebTransportCertificate {
+        sha256: String,
+        valid_days: u64,
+    },
+    Path {
+        rtt_ms: f64,
+        cwnd: u64,
+        average_datagram: u64,
+    },
+    Migration {
+        from: SocketAddr,
+        to: SocketAddr,
+        kind: MigrationKind,
+    },
+    Rebind {
+        local_port: u16,
+        survived: bool,
+    },
+    StreamOpen {
+        stream: u64,
+        kind: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel: Option<u16>,
+    },
+    StreamDeadline {
+        stream: u64,
+        seconds: f64,
+    },
+    BytesTransferred {
+        stream: u64,
+        direction: Direction,
+        bytes: u64,
+        seconds: f64,
+    },
+    Response {
+        status: u16,
+    },
+    Discard {
+        stream: u64,
+        bytes: u64,
+        seconds: f64,
+        bytes_per_second: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        steady_state: Option<SteadyState>,
+    },
+    Latency {
+        ms: f64,
+    },
+    Ping {
+        peer: SocketAddr,
+        seq: u32,
+        rtt_ms: f64,
+    },
+    PingSummary {
+        peer: SocketAddr,
+        sent: u32,
+        received: u32,
+        min_ms: f64,
+        avg_ms: f64,
+        max_ms: f64,
+        jitter_ms: f64,
+    },
+    Stats {
+        peer: SocketAddr,
+        seconds: f64,
+        sent_bytes: u64,
+        received_bytes: u64,
+        rtt_ms: f64,
+        sent_packets: u64,
+        lost_packets: u64,
+    },
+    Streams {
+        peer: SocketAddr,
+        streams: Vec<u64>,
+    },
+    Close {
+        peer: SocketAddr,
+        reason: String,
+        seconds: f64,
+        sent_bytes: u64,
+        received_bytes: u64,
+        sent_packets: u64,
+        lost_packets: u64,
+        lost_bytes: u64,
+        congestion_events: u64,
+        mtu_probes: u64,
+        lost_mtu_probes: u64,
+        black_holes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        peer_view: Option<PeerView>,
+    },
+    Error {
+        target: String,
+        message: String,
+    },
+}
+
+/// The peer's statistics of the connection, as last reported with `--remote-stats`.
+#[derive(Clone, Serialize)]
+pub struct PeerView {
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub lost_bytes: u64,
+    pub received_bytes: u64,
+    pub receive_rate: f64,
+}
+
+/// Throughput measured after the `--warmup` period.
+#[derive(Serialize)]
+pub struct SteadyState {
+    pub warmup_seconds: f64,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub bytes_per_second: f64,
+}
+
+/// How the peer address changed.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationKind {
+    /// Same IP address, another port, as when a NAT reassigns the mapping.
+    NatRebinding,
+    /// Another IP address, as when a client roams to another network.
+    Migration,
+}
+
+impl MigrationKind {
+    fn of(from: SocketAddr, to: SocketAddr) -> Self {
+        if from.ip() == to.ip() {
+            MigrationKind::NatRebinding
+        } else {
+            MigrationKind::Migration
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Sent => write!(f, "sent"),
+            Direction::Received => write!(f, "received"),
+        }
+    }
+}
+
+impl Event {
+    /// Verbosity from which the event is shown in the text format, `None` if never (errors are
+    /// already printed by `tracing`).
+    fn level(&self) -> Option<u8> {
+        match self {
+            Event::Discard { .. }
+            | Event::Latency { .. }
+            | Event::Ping { .. }
+            | Event::PingSummary { .. }
+            | Event::Rebind { .. }
+            | Event::Stats { .. }
+            | Event::StreamDeadline { .. }
+            | Event::Streams { .. }
+            | Event::WebTransportCertificate { .. } => Some(0),
+            Event::Connect { .. }
+            | Event::PeerVersion { .. }
+            | Event::Close { .. }
+            | Event::Migration { .. }
+            | Event::Response { .. } => Some(1),
+            Event::Error { .. } => None,
+            _ => Some(2),
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Event::Connect {
+                peer,
+                alpn,
+                version,
+            } => format!(
+                "* handshake with {} complete (QUIC {}, TLS 1.3, ALPN {})",
+                peer,
+                version.as_deref().unwrap_or("version unknown"),
+                alpn.as_deref().unwrap_or("none")
+            ),
+            Event::PeerVersion {
+                peer,
+                version: Some(version),
+                protocol,
+            } => format!(
+                "* {} runs nesquic {} (protocol {})",
+                peer,
+                version,
+                protocol.unwrap_or_default()
+            ),
+            Event::PeerVersion { peer, .. } => {
+                format!("* {} didn't say which nesquic version it runs", peer)
+            }
+            Event::Transport { max_datagram } => match max_datagram {
+                Some(size) => format!("* transport: peer takes datagrams up to {} bytes", size),
+                None => "* transport: peer takes no datagrams".to_string(),
+            },
+            Event::Certificate {
+                subject,
+                issuer,
+                not_before,
+                not_after,
+                sha256,
+                chain_length,
+            } => {
+                let mut text = match (subject, issuer, not_before, not_after) {
+                    (Some(subject), Some(issuer), Some(not_before), Some(not_after)) => format!(
+                        "* peer certificate: subject \"{}\", issuer \"{}\", valid {} to {}\n",
+                        subject, issuer, not_before, not_after
+                    ),
+                    _ => "* peer certificate could not be parsed\n".to_string(),
+                };
+                let _ = write!(
+                    text,
+                    "* peer certificate SHA-256 {} ({} in chain, not verified)",
+                    sha256, chain_length
+                );
+                text
+            }
+            Event::Path {
+                rtt_ms,
+                cwnd,
+                average_datagram,
+            } => format!(
+                "* path: rtt {:.1} ms, congestion window {} bytes, average datagram {} bytes",
+                rtt_ms, cwnd, average_datagram
+            ),
+            Event::Migration { from, to, kind } => {
+                let kind = match kind {
+                    MigrationKind::NatRebinding => "NAT rebinding",
+                    MigrationKind::Migration => "migration",
+                };
+                format!("* peer address changed from {} to {} ({})", from, to, kind)
+            }
+            Event::Rebind {
+                local_port,
+                survived,
+            } => format!(
+                "[rebind] moved to local port {}, connection {}",
+                local_port,
+                if *survived { "survived" } else { "lost" }
+            ),
+            Event::StreamOpen {
+                stream,
+                kind,
+                channel,
+            } => match channel {
+                Some(channel) => format!("* stream {} opened ({} {})", stream, kind, channel),
+                None => format!("* stream {} opened ({})", stream, kind),
+            },
+            Event::StreamDeadline { stream, seconds } => format!(
+                "[deadline] stream {} still busy after {:.3}s, reset",
+                stream, seconds
+            ),
+            Event::BytesTransferred {
+                stream,
+                direction,
+                bytes,
+                seconds,
+            } => format!(
+                "* stream {}: {} {} bytes in {:.3}s",
+                stream, direction, bytes, seconds
+            ),
+            Event::WebTransportCertificate { sha256, valid_days } => format!(
+                "[webtransport] certificate SHA-256 {} (for serverCertificateHashes, valid {} days)",
+                sha256, valid_days
+            ),
+            Event::Response { status } => format!("< HTTP/3 {}", status),
+            Event::Discard {
+                stream,
+                bytes,
+                seconds,
+                bytes_per_second,
+                steady_state,
+            } => {
+                let mut text = format!(
+                    "[discard] stream {}: {} bytes in {:.3}s ({})",
+                    stream,
+                    bytes,
+                    seconds,
+                    rate::format_rate(*bytes_per_second)
+                );
+                if let Some(steady) = steady_state {
+                    let _ = write!(
+                        text,
+                        ", after {:.3}s warm-up: {} bytes in {:.3}s ({})",
+                        steady.warmup_seconds,
+                        steady.bytes,
+                        steady.seconds,
+                        rate::format_rate(steady.bytes_per_second)
+                    );
+                }
+                text
+            }
+            Event::Latency { ms } => format!("[latency] {:.3} ms", ms),
+            Event::Ping { peer, seq, rtt_ms } => {
+                format!("[ping] reply from {}: seq={} rtt={:.3} ms", peer, seq, rtt_ms)
+            }
+            Event::PingSummary {
+                peer,
+                sent,
+                received,
+                min_ms,
+                avg_ms,
+                max_ms,
+                jitter_ms,
+            } => format!(
+                "[ping] {}: {} probes sent, {} replies ({:.0}% lost), rtt min/avg/max \
+                 {:.3}/{:.3}/{:.3} ms, jitter {:.3} ms",
+                peer,
+                sent,
+                received,
+                100.0 * f64::from(sent.saturating_sub(*received)) / f64::from((*sent).max(1)),
+                min_ms,
+                avg_ms,
+                max_ms,
+                jitter_ms
+            ),
+            Event::Stats {
+                peer,
+                seconds,
+                sent_bytes,
+                received_bytes,
+                rtt_ms,
+                sent_packets,
+                lost_packets,
+            } => format!(
+                "[stats] {}: up {:.1}s, {} bytes sent, {} received, rtt {:.1} ms, {} packets sent \
+                 ({} lost)",
+                peer, seconds, sent_bytes, received_bytes, rtt_ms, sent_packets, lost_packets
+            ),
+            Event::Streams { peer, streams } if streams.is_empty() => {
+                format!("[streams] {}: none open", peer)
+            }
+            Event::Streams { peer, streams } => format!(
+                "[streams] {}: {}",
+                peer,
+                streams
+                    .iter()
+                    .map(u64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Event::Close {
+                peer,
+                reason,
+                seconds,
+                sent_bytes,
+                received_bytes,
+                sent_packets,
+                lost_packets,
+                lost_bytes,
+                congestion_events,
+                mtu_probes,
+                lost_mtu_probes,
+                black_holes,
+                peer_view,
+            } => {
+                let mut text = format!(
+                    "* connection to {} closed after {:.1}s ({} bytes sent, {} received): {}",
+                    peer, seconds, sent_bytes, received_bytes, reason
+                );
+                if let Some(view) = peer_view {
+                    let _ = write!(
+                        text,
+                        "\n* our view: sent {} packets, {} lost\n* peer's view: sent {} packets, \
+                         {} lost, received {} bytes, receiving at {}",
+                        sent_packets,
+                        lost_packets,
+                        view.sent_packets,
+                        view.lost_packets,
+                        view.received_bytes,
+                        rate::format_rate(view.receive_rate)
+                    );
+                }
+                if verbosity() >= 2 {
+                    let _ = write!(
+                        text,
+                        "\n* sent {} packets ({} lost, {} bytes lost), {} congestion events, \
+                         {} MTU probes ({} lost), {} black holes detected",
+                        sent_packets,
+                        lost_packets,
+                        lost_bytes,
+                        congestion_events,
+                        mtu_probes,
+                        lost_mtu_probes,
+                        black_holes
+                    );
+                }
+                text
+            }
+            Event::Error { message, .. } => message.clone(),
+        }
+    }
+}
+
+/// Reports `event` in the configured format, if the verbosity asks for it.
+pub fn emit(event: Event) {
+    let line = if json() {
+        let mut object = serde_json::to_value(&event).unwrap();
+        object["timestamp"] = chrono::Utc::now().to_rfc3339().into();
+        object.to_string()
+    } else {
+        match event.level() {
+            Some(level) if verbosity() >= level => event.text(),
+            _ => return,
+        }
+    };
+    let _ = writeln!(stderr().lock(), "{}", line);
+}
+
+/// Reports an established connection and watches it for later events, counting its traffic,
+/// sampling its statistics and exchanging them with the peer if asked to.
+pub fn connection(conn: &Connection) {
+    crate::stats::watch(conn);
+    let traffic = counters::track(conn);
+    crate::hooks::connection(conn, traffic.clone());
+    let peer_view = remote::exchange(conn);
+    if !json() && verbosity() == 0 {
+        return;
+    }
+    emit(Event::Connect {
+        peer: conn.remote_address(),
+        alpn: crate::negotiated_alpn(conn),
+        version: negotiation::version(conn).map(negotiation::name),
+    });
+    emit(Event::Transport {
+        max_datagram: conn.max_datagram_size(),
+    });
+    certificate(conn);
+    let stats = conn.stats();
+    emit(Event::Path {
+        rtt_ms: conn.rtt().as_secs_f64() * 1000.0,
+        cwnd: stats.path.cwnd,
+        // the average size of the datagrams sent so far is a rough lower bound of the path MTU
+        average_datagram: stats
+            .udp_tx
+            .bytes
+            .checked_div(stats.udp_tx.datagrams)
+            .unwrap_or(0),
+    });
+    tasks::spawn(watch_path(conn.clone()));
+
+    let conn = conn.clone();
+    tasks::spawn(async move {
+        let reason = conn.closed().await;
+        let stats = conn.stats();
+        let totals = traffic.totals();
+        emit(Event::Close {
+            peer: conn.remote_address(),
+            reason: reason.to_string(),
+            seconds: totals.seconds,
+            sent_bytes: totals.sent_bytes,
+            received_bytes: totals.received_bytes,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            congestion_events: stats.path.congestion_events,
+            mtu_probes: stats.path.sent_plpmtud_probes,
+            lost_mtu_probes: stats.path.lost_plpmtud_probes,
+            black_holes: stats.path.black_holes_detected,
+            peer_view: peer_view.and_then(|latest| latest.get()),
+        });
+    });
+}
+
+/// Reports the traffic and path of every open connection so far, on demand.
+pub fn snapshot() {
+    for (conn, totals) in counters::open() {
+        let stats = conn.stats();
+        emit(Event::Stats {
+            peer: conn.remote_address(),
+            seconds: totals.seconds,
+            sent_bytes: totals.sent_bytes,
+            received_bytes: totals.received_bytes,
+            rtt_ms: conn.rtt().as_secs_f64() * 1000.0,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+        });
+    }
+}
+
+/// Reports a new stream carrying a session, a channel or a test peer stream.
+pub fn stream_open(stream: StreamId, kind: &'static str, channel: Option<u16>) {
+    emit(Event::StreamOpen {
+        stream: stream.index(),
+        kind,
+        channel,
+    });
+}
+
+/// Reports how much data went over one direction of a stream once it's done, see
+/// [`counters::StreamCounter`].
+pub fn transferred(stream: StreamId, direction: Direction, bytes: u64, elapsed: Duration) {
+    emit(Event::BytesTransferred {
+        stream: stream.index(),
+        direction,
+        bytes,
+        seconds: elapsed.as_secs_f64(),
+    });
+}
+
+fn certificate(conn: &Connection) {
+    let chain = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<Certificate>>().ok());
+    let Some(leaf) = chain.as_ref().and_then(|chain| chain.first()) else {
+        return;
+    };
+    let fingerprint = ring::digest::digest(&ring::digest::SHA256, &leaf.0);
+    let fingerprint: Vec<String> = fingerprint
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    let parsed = X509Certificate::from_der(&leaf.0).ok();
+    let cert = parsed.as_ref().map(|(_, cert)| cert);
+    emit(Event::Certificate {
+        subject: cert.map(|cert| cert.subject().to_string()),
+        issuer: cert.map(|cert| cert.issuer().to_string()),
+        not_before: cert.map(|cert| cert.validity().not_before.to_string()),
+        not_after: cert.map(|cert| cert.validity().not_after.to_string()),
+        sha256: fingerprint.join(":"),
+        chain_length: chain.map_or(0, |chain| chain.len()),
+    });
+}
+
+/// Reports changes of the peer address until the connection is closed. quinn doesn't say how
+/// validating the new path went; one that fails shows up as another change, back to the previous
+/// address.
+async fn watch_path(conn: Connection) {
+    let mut addr = conn.remote_address();
+    loop {
+        tokio::select! {
+            _ = conn.closed() => return,
+            _ = tokio::time::sleep(PATH_POLL_INTERVAL) => {}
+        }
+        let current = conn.remote_address();
+        if current != addr {
+            emit(Event::Migration {
+                from: addr,
+                to: current,
+                kind: MigrationKind::of(addr, current),
+            });
+            addr = current;
+        }
+    }
+}
+
+/// Tracing layer reporting logged errors as [`Event::Error`], used with `--log-format json`.
+pub struct ErrorEvents;
+
+impl<S: Subscriber> Layer<S> for ErrorEvents {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        emit(Event::Error {
+            target: event.metadata().target().to_string(),
+            message: message.0,
+        });
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
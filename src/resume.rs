@@ -0,0 +1,218 @@
+//! Resuming file transfers cut short (`--resume FILE`).
+//!
+//! A client receiving from a `--serve-file` listener with `--resume FILE` writes what it receives
+//! to FILE, after the data FILE already holds. Before the transfer starts, it sends the length of
+//! that data on a unidirectional control stream, followed by a digest of each block of it as it
+//! reads them. The listener checks every digest against the same block of its own file as it
+//! comes, and stops the stream at the first one that doesn't match. It answers with the length
+//! of the blocks that matched and sends its file from there on, and the client truncates FILE to
+//! that length first. So a FILE that went wrong somewhere only has to be received again from
+//! there, and neither side waits for the other to read all of it.
+//!
+//! The client finishes its side of the session stream once the request is acknowledged, which is
+//! also what lets the listener accept that stream. So once the listener sees that side finished
+//! without a request, none is coming and the file is sent whole, as it is to clients that don't
+//! resume. The answer comes ahead of the file on the session stream, and only if there was a
+//! request.
+//!
+//! Request format: `NQRE` magic, length as big-endian u64, then the SHA-256 digest of every
+//! [`BLOCK`] bytes of that length, the last block being shorter if the length isn't a multiple.
+//! Answer format: `NQRE` magic, length to resume at as big-endian u64.
+
+use std::{error::Error, fs, io, io::SeekFrom, path::Path, time::Duration};
+
+use quinn::{Connection, RecvStream, SendStream, VarInt, WriteError};
+use ring::digest::{Context, Digest, SHA256, SHA256_OUTPUT_LEN};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt},
+    time::timeout,
+};
+use tracing::{info, warn};
+
+use crate::timers;
+
+const MAGIC: &[u8; 4] = b"NQRE";
+/// Bytes covered by each digest of a request.
+const BLOCK: u64 = 1024 * 1024;
+const READ_CHUNK: usize = 64 * 1024;
+/// How long the listener waits for a client to either send a request or finish its side of the
+/// session stream, and for each part of the request, and the client for the answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How much longer the client waits for the answer for every block, in case the listener checks
+/// them slower than the client reads them.
+const BLOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Digest of the next `len` bytes of `file`, or `None` if it ends before.
+async fn block_digest(file: &mut File, len: u64, buffer: &mut [u8]) -> io::Result<Option<Digest>> {
+    let mut context = Context::new(&SHA256);
+    let mut left = len;
+    while left > 0 {
+        let want = left.min(buffer.len() as u64) as usize;
+        let read = file.read(&mut buffer[..want]).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        context.update(&buffer[..read]);
+        left -= read as u64;
+    }
+    Ok(Some(context.finish()))
+}
+
+/// Asks the listener to resume the transfer after what `path` holds, returning the file to write
+/// the rest to (client side).
+pub async fn request(
+    conn: &Connection,
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    path: &Path,
+) -> Result<fs::File, Box<dyn Error>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await
+        .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+    let have = file.metadata().await?.len();
+
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&have.to_be_bytes());
+    // other listeners don't let clients open unidirectional streams
+    let opened = timeout(REQUEST_TIMEOUT, async {
+        let mut control = conn.open_uni().await?;
+        control.write_all(&header).await?;
+        Ok::<_, Box<dyn Error>>(control)
+    })
+    .await;
+    let mut control =
+        opened.map_err(|_| "the listener can't resume transfers, it needs --serve-file")??;
+    let blocks = have.div_ceil(BLOCK);
+    let mut buffer = vec![0; READ_CHUNK];
+    for block in 0..blocks {
+        let len = BLOCK.min(have - block * BLOCK);
+        let digest = block_digest(&mut file, len, &mut buffer)
+            .await?
+            .ok_or("the file changed while reading it")?;
+        match control.write_all(digest.as_ref()).await {
+            Ok(()) => {}
+            // the listener found a block that doesn't match, the rest doesn't matter
+            Err(WriteError::Stopped(_)) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // resolves once the listener has the request, before the session stream is finished
+    match control.finish().await {
+        Ok(()) | Err(WriteError::Stopped(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    send.finish().await?;
+
+    let mut answer = [0u8; MAGIC.len() + 8];
+    let checking = BLOCK_TIMEOUT.saturating_mul(blocks.try_into().unwrap_or(u32::MAX));
+    timeout(REQUEST_TIMEOUT + checking, recv.read_exact(&mut answer))
+        .await
+        .map_err(|_| "the listener didn't answer the resume request")??;
+    if &answer[..MAGIC.len()] != MAGIC {
+        return Err("the listener can't resume transfers, it needs --serve-file".into());
+    }
+    let offset = u64::from_be_bytes(answer[MAGIC.len()..].try_into().unwrap());
+    if offset > have {
+        return Err(format!(
+            "the listener resumes at {} bytes, but {} only holds {}",
+            offset,
+            path.display(),
+            have
+        )
+        .into());
+    }
+    if offset == 0 && have > 0 {
+        warn!(
+            "[resume] {} doesn't match the start of the listener's file, receiving it all again",
+            path.display()
+        );
+    } else if offset < have {
+        warn!(
+            "[resume] only the first {} bytes of {} match the listener's file, receiving the rest \
+             again",
+            offset,
+            path.display()
+        );
+    } else if offset > 0 {
+        info!("[resume] resuming after {} bytes", offset);
+    }
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    Ok(file.into_std().await)
+}
+
+/// Waits for a client's resume request, if it sends one, and answers it. Returns how many bytes
+/// of the file at `path` the client already has (listener side).
+pub async fn answer(
+    conn: &Connection,
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    path: &Path,
+) -> Result<u64, Box<dyn Error>> {
+    let mut byte = [0u8; 1];
+    let waited = timeout(REQUEST_TIMEOUT, async {
+        tokio::select! {
+            // a request is acknowledged before the session stream is finished, so it's seen first
+            biased;
+            control = conn.accept_uni() => Some(control),
+            // a finished side, or data from a client that isn't resuming
+            _ = recv.read(&mut byte) => None,
+        }
+    })
+    .await;
+    let mut control = match waited {
+        Ok(Some(control)) => control?,
+        Ok(None) | Err(_) => return Ok(0),
+    };
+    let mut header = [0u8; MAGIC.len() + 8];
+    timeout(REQUEST_TIMEOUT, control.read_exact(&mut header))
+        .await
+        .map_err(|_| "the resume request didn't arrive in time")??;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err("not a nesquic resume request".into());
+    }
+    let have = u64::from_be_bytes(header[MAGIC.len()..].try_into().unwrap());
+
+    let mut file = File::open(path).await?;
+    let mut buffer = vec![0; READ_CHUNK];
+    let mut theirs = [0u8; SHA256_OUTPUT_LEN];
+    let mut offset = 0;
+    while offset < have {
+        let len = BLOCK.min(have - offset);
+        timeout(REQUEST_TIMEOUT, control.read_exact(&mut theirs))
+            .await
+            .map_err(|_| "the resume request didn't arrive in time")??;
+        // a block past the end of the file doesn't match either
+        let ours = block_digest(&mut file, len, &mut buffer).await?;
+        if ours.as_ref().map(Digest::as_ref) != Some(&theirs[..]) {
+            let _ = control.stop(VarInt::from_u32(0));
+            break;
+        }
+        offset += len;
+    }
+    if offset == have {
+        info!(
+            "[resume] {} has {} bytes already",
+            conn.remote_address(),
+            offset
+        );
+    } else {
+        info!(
+            "[resume] {} has {} bytes, of which the first {} match the file, sending the rest",
+            conn.remote_address(),
+            have,
+            offset
+        );
+    }
+    let mut answer = MAGIC.to_vec();
+    answer.extend_from_slice(&offset.to_be_bytes());
+    send.write_all(&answer).await?;
+    timers::touch();
+    Ok(offset)
+}
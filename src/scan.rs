@@ -0,0 +1,112 @@
+//! QUIC reachability scan over a port range (`--scan IP PORTS`), like `nc -z`.
+//!
+//! A handshake is attempted on every port concurrently, each with a short timeout. Ports where it
+//! completes are listed with the negotiated ALPN and QUIC version. Ports whose server answered but
+//! refused the handshake (e.g. because no offered ALPN protocol matched) are listed too, since
+//! something speaks QUIC there; silent ports are only listed with `-v`.
+
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use quinn::{ConnectionError, Endpoint, VarInt};
+use tokio::{sync::Semaphore, task::JoinSet, time::timeout};
+
+use crate::{util::make_client_endpoint, Cli};
+
+/// Handshake timeout unless `--connect-timeout` is given.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Most handshakes in flight at once.
+const CONCURRENCY: usize = 64;
+/// The only version quinn offers, so the only one a handshake can complete with.
+const QUIC_VERSION: &str = "v1";
+
+/// Port range such as `443` or `400-500`.
+pub fn parse_ports(ports: &str) -> Result<(u16, u16), String> {
+    let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+    let parse = |port: &str| {
+        port.trim()
+            .parse::<u16>()
+            .map_err(|_| format!("invalid port '{}'", port))
+    };
+    let (first, last) = (parse(first)?, parse(last)?);
+    if first > last {
+        return Err(format!("invalid port range '{}'", ports));
+    }
+    Ok((first, last))
+}
+
+enum Outcome {
+    Open(Option<String>),
+    Refused(ConnectionError),
+    Silent,
+}
+
+/// Scans the ports and prints the results sorted by port. Returns whether any handshake
+/// completed.
+pub async fn run(ip: IpAddr, ports: (u16, u16), args: &Cli) -> Result<bool, Box<dyn Error>> {
+    let endpoint = make_client_endpoint(SocketAddr::new(ip, ports.0), args).await?;
+    let limit = args.connect_timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let slots = Arc::new(Semaphore::new(CONCURRENCY));
+
+    let mut probes = JoinSet::new();
+    for port in ports.0..=ports.1 {
+        let endpoint = endpoint.clone();
+        let slots = slots.clone();
+        probes.spawn(async move {
+            let _slot = slots.acquire_owned().await;
+            (
+                port,
+                probe(&endpoint, SocketAddr::new(ip, port), limit).await,
+            )
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        results.push(result?);
+    }
+    results.sort_by_key(|(port, _)| *port);
+
+    let mut open = false;
+    for (port, outcome) in results {
+        match outcome {
+            Outcome::Open(alpn) => {
+                open = true;
+                println!(
+                    "{} {} (quic) open: handshake complete, ALPN {}, QUIC {}",
+                    ip,
+                    port,
+                    alpn.as_deref().unwrap_or("none"),
+                    QUIC_VERSION
+                );
+            }
+            Outcome::Refused(e) => println!("{} {} (quic) refused: {}", ip, port, e),
+            Outcome::Silent if args.verbose > 0 => {
+                println!("{} {} (quic) no answer within {:?}", ip, port, limit)
+            }
+            Outcome::Silent => {}
+        }
+    }
+    // give the close frames a chance to go out
+    let _ = timeout(Duration::from_millis(100), endpoint.wait_idle()).await;
+    Ok(open)
+}
+
+async fn probe(endpoint: &Endpoint, addr: SocketAddr, limit: Duration) -> Outcome {
+    let connecting = match endpoint.connect(addr, "127.0.0.1") {
+        Ok(connecting) => connecting,
+        Err(_) => return Outcome::Silent,
+    };
+    match timeout(limit, connecting).await {
+        Ok(Ok(conn)) => {
+            let alpn = crate::negotiated_alpn(&conn);
+            conn.close(VarInt::from_u32(0), b"scan");
+            Outcome::Open(alpn)
+        }
+        Ok(Err(ConnectionError::TimedOut)) | Err(_) => Outcome::Silent,
+        Ok(Err(e)) => Outcome::Refused(e),
+    }
+}
@@ -0,0 +1,172 @@
+//! Fair sharing of a connection's send capacity between its streams (`--weight`), and their
+//! priorities (`--priority`).
+//!
+//! Quinn interleaves the frames of streams that have data buffered, but a bulk transfer started
+//! first can fill the connection's whole send buffer on its own, so transfers started after it
+//! only get a chance as that buffer drains. Instead, writes to every outgoing stream of a
+//! connection take turns through its [`Scheduler`]: in each turn a stream writes up to its weight
+//! times [`QUANTUM`] bytes, then goes to the back of the line. A stream that can't make progress
+//! within [`TURN`] (e.g. because its reader stalls) gives up its turn rather than holding up the
+//! others.
+//!
+//! Turns decide how much each stream hands over to quinn, and priorities in which order quinn
+//! sends what was handed over: data of a stream with a higher priority goes out before that of
+//! streams with lower ones, so a control channel's writes overtake the bulk transfer buffered
+//! ahead of them. Every stream has priority 0 unless given another one.
+
+use std::{io, sync::Arc, time::Duration};
+
+use bytes::{Buf, Bytes};
+use quinn::SendStream;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::Mutex,
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::{counters::StreamCounter, timers};
+
+/// Bytes written per turn by a stream of weight 1.
+pub const QUANTUM: usize = 16 * 1024;
+/// Longest a stream may wait for send capacity before giving up its turn.
+const TURN: Duration = Duration::from_millis(50);
+const MAX_WEIGHT: u32 = 1000;
+
+/// Parses `--weight N=W`.
+pub fn parse_weight(spec: &str) -> Result<(u16, u32), String> {
+    let (id, weight) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected N=WEIGHT, got '{}'", spec))?;
+    let id = id
+        .parse()
+        .map_err(|_| format!("invalid channel number '{}'", id))?;
+    let weight = weight
+        .parse()
+        .ok()
+        .filter(|weight| (1..=MAX_WEIGHT).contains(weight))
+        .ok_or_else(|| format!("weights range from 1 to {}, got '{}'", MAX_WEIGHT, weight))?;
+    Ok((id, weight))
+}
+
+/// Parses `--priority N=PRIORITY`.
+pub fn parse_priority(spec: &str) -> Result<(u16, i32), String> {
+    let (id, priority) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected N=PRIORITY, got '{}'", spec))?;
+    let id = id
+        .parse()
+        .map_err(|_| format!("invalid channel number '{}'", id))?;
+    let priority = priority
+        .parse()
+        .map_err(|_| format!("invalid priority '{}'", priority))?;
+    Ok((id, priority))
+}
+
+/// Hands out turns to write, in the order they were asked for.
+#[derive(Default)]
+pub struct Scheduler {
+    // tokio's mutex is fair: waiters get the lock first come, first served
+    turns: Mutex<()>,
+}
+
+impl Scheduler {
+    pub fn share(self: &Arc<Self>, weight: u32, priority: i32) -> Share {
+        Share {
+            scheduler: self.clone(),
+            weight,
+            priority,
+        }
+    }
+}
+
+/// One stream's share of the connection.
+#[derive(Clone)]
+pub struct Share {
+    scheduler: Arc<Scheduler>,
+    weight: u32,
+    priority: i32,
+}
+
+impl Share {
+    /// A share of a connection nothing else writes to.
+    pub fn exclusive() -> Self {
+        Arc::new(Scheduler::default()).share(1, 0)
+    }
+
+    /// Gives `send` the priority of this share.
+    pub fn prioritize(&self, send: &SendStream) {
+        if self.priority != 0 {
+            if let Err(e) = send.set_priority(self.priority) {
+                debug!("could not set the priority of stream {}: {}", send.id(), e);
+            }
+        }
+    }
+
+    pub async fn write_all(&self, send: &mut SendStream, mut data: &[u8]) -> io::Result<()> {
+        let quantum = QUANTUM * self.weight as usize;
+        while !data.is_empty() {
+            let _turn = self.scheduler.turns.lock().await;
+            let len = data.len().min(quantum);
+            // a write that times out hasn't written anything, so it's retried on the next turn
+            if let Ok(written) = timeout(TURN, send.write(&data[..len])).await {
+                data = &data[written?..];
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`write_all`](Self::write_all), handing the chunks over to the stream without copying
+    /// them.
+    pub async fn write_chunks(
+        &self,
+        send: &mut SendStream,
+        mut chunks: &mut [Bytes],
+    ) -> io::Result<()> {
+        let quantum = QUANTUM * self.weight as usize;
+        while !chunks.is_empty() {
+            let _turn = self.scheduler.turns.lock().await;
+            // as many whole chunks as fit in the quantum, or else the start of the first one
+            let mut fitting = 0;
+            let mut len = 0;
+            while fitting < chunks.len() && len + chunks[fitting].len() <= quantum {
+                len += chunks[fitting].len();
+                fitting += 1;
+            }
+            if fitting == 0 {
+                let mut head = [chunks[0].slice(..quantum)];
+                if let Ok(written) = timeout(TURN, send.write_chunks(&mut head)).await {
+                    chunks[0].advance(written?.bytes);
+                }
+                continue;
+            }
+            if let Ok(written) = timeout(TURN, send.write_chunks(&mut chunks[..fitting])).await {
+                chunks = &mut std::mem::take(&mut chunks)[written?.chunks..];
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Like [`channel::pump`](crate::channel::pump), with the writes taking turns.
+pub async fn pump<R>(
+    from: &mut R,
+    to: &mut SendStream,
+    share: &Share,
+    counter: &mut StreamCounter,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = from.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        share.write_all(to, &buffer[..n]).await?;
+        timers::touch();
+        counter.add(n);
+    }
+    Ok(())
+}
@@ -0,0 +1,288 @@
+//! Benchmark of the local I/O path (`nesquic selfbench`).
+//!
+//! Runs the two pipelines of a session, stdin to stream and stream to stdout, between two
+//! endpoints exchanging datagrams in memory, so the numbers depend on the copying, scheduling
+//! and counting done around the streams rather than on the network. Each pipeline moves the same
+//! amount of data with several buffer sizes (the size of the stdin reads, and of the reads from
+//! the stream), and reports the copies made per second and the throughput.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    io::{self, IoSliceMut},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use quinn::{
+    udp::{RecvMeta, Transmit, UdpState},
+    AsyncUdpSocket, Connection, RecvStream, SendStream,
+};
+
+use crate::counters::StreamCounter;
+use crate::output::{Sink, Writer};
+use crate::report::Direction;
+use crate::sched::Share;
+use crate::util::{self, SocketFactory};
+use crate::{timers, Cli};
+
+/// Buffer sizes measured, from line-sized reads to the largest reads the session makes.
+const BUFFER_SIZES: [usize; 6] = [1 << 10, 4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20];
+
+/// Size of the writes feeding the stream to stdout pipeline.
+const FEED_CHUNK: usize = 1 << 20;
+
+/// Most datagrams waiting for a socket to read them, like a kernel receive buffer.
+const MAX_QUEUED: usize = 4096;
+
+/// Measures both pipelines, moving `bytes` for each buffer size, and prints the results.
+pub async fn run(bytes: u64, args: &Cli) -> Result<(), Box<dyn Error>> {
+    let network = MemoryNetwork::default();
+    let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let (server, _) = util::make_server_endpoint_with(&[localhost], args, &network)?;
+    let client = util::make_client_endpoint_with(server.local_addr()?, args, &network).await?;
+    let connecting = client.connect(server.local_addr()?, "localhost")?;
+    let accepting = server.accept().await.ok_or("server endpoint closed")?;
+    let (sender, receiver) = tokio::try_join!(connecting, accepting)?;
+
+    println!(
+        "{:<16} {:>8} {:>12} {:>10}",
+        "pipeline", "buffer", "copies/s", "MB/s"
+    );
+    for size in BUFFER_SIZES {
+        let (copies, elapsed) = stdin_to_stream(&sender, &receiver, size, bytes).await?;
+        print_row("stdin -> stream", size, copies, bytes, elapsed);
+    }
+    for size in BUFFER_SIZES {
+        let (copies, elapsed) = stream_to_stdout(&sender, &receiver, size, bytes).await?;
+        print_row("stream -> stdout", size, copies, bytes, elapsed);
+    }
+    sender.close(0u32.into(), b"done");
+    client.wait_idle().await;
+    Ok(())
+}
+
+fn print_row(pipeline: &str, size: usize, copies: u64, bytes: u64, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    let size = if size >= 1 << 20 {
+        format!("{}MiB", size >> 20)
+    } else {
+        format!("{}KiB", size >> 10)
+    };
+    println!(
+        "{:<16} {:>8} {:>12.0} {:>10.1}",
+        pipeline,
+        size,
+        copies as f64 / seconds,
+        bytes as f64 / seconds / 1e6
+    );
+}
+
+async fn open(
+    sender: &Connection,
+    receiver: &Connection,
+) -> Result<(SendStream, RecvStream), Box<dyn Error>> {
+    let (mut send, _) = sender.open_bi().await?;
+    // a stream is only announced to the peer once something is sent on it
+    send.write_all(&[0]).await?;
+    let (_, mut recv) = receiver.accept_bi().await?;
+    recv.read_exact(&mut [0]).await?;
+    Ok((send, recv))
+}
+
+/// Reads of `size` bytes copied into the stream the way stdin is, drained on the other end.
+async fn stdin_to_stream(
+    sender: &Connection,
+    receiver: &Connection,
+    size: usize,
+    bytes: u64,
+) -> Result<(u64, Duration), Box<dyn Error>> {
+    let (mut send, mut recv) = open(sender, receiver).await?;
+    let input = vec![b'x'; size];
+    let share = Share::exclusive();
+    let mut counter = StreamCounter::new(sender, send.id(), Direction::Sent);
+    let started = Instant::now();
+    let sending = async {
+        let mut copies = 0;
+        let mut left = bytes;
+        while left > 0 {
+            let len = left.min(size as u64) as usize;
+            // stdin reads are copied out of the reader's buffer
+            let buffer = input[..len].to_vec();
+            share.write_all(&mut send, &buffer).await?;
+            timers::touch();
+            counter.add(buffer.len());
+            copies += 1;
+            left -= len as u64;
+        }
+        send.finish().await?;
+        Ok::<_, Box<dyn Error>>(copies)
+    };
+    let draining = async {
+        while recv.read_chunk(usize::MAX, true).await?.is_some() {}
+        Ok::<_, Box<dyn Error>>(())
+    };
+    let (copies, drained) = tokio::join!(sending, draining);
+    drained?;
+    Ok((copies?, started.elapsed()))
+}
+
+/// Reads of up to `size` bytes from the stream written out the way stdout is, fed in large
+/// writes from the other end.
+async fn stream_to_stdout(
+    sender: &Connection,
+    receiver: &Connection,
+    size: usize,
+    bytes: u64,
+) -> Result<(u64, Duration), Box<dyn Error>> {
+    let (mut send, mut recv) = open(sender, receiver).await?;
+    let feed = Bytes::from(vec![b'x'; FEED_CHUNK]);
+    let mut counter = StreamCounter::new(receiver, recv.id(), Direction::Received);
+    let started = Instant::now();
+    let feeding = async {
+        let mut left = bytes;
+        while left > 0 {
+            let len = left.min(FEED_CHUNK as u64) as usize;
+            send.write_chunk(feed.slice(..len)).await?;
+            left -= len as u64;
+        }
+        send.finish().await?;
+        Ok::<_, Box<dyn Error>>(())
+    };
+    let receiving = async {
+        let mut copies = 0;
+        let writer = Writer::spawn(Sink::Ordered(io::sink()));
+        while let Some(chunk) = recv.read_chunk(size, true).await? {
+            timers::touch();
+            counter.add(chunk.bytes.len());
+            writer
+                .write(chunk.offset, chunk.bytes)
+                .await
+                .map_err(|()| "the writer stopped")?;
+            copies += 1;
+        }
+        writer.finish().await?;
+        Ok::<_, Box<dyn Error>>(copies)
+    };
+    let (fed, copies) = tokio::join!(feeding, receiving);
+    fed?;
+    Ok((copies?, started.elapsed()))
+}
+
+/// Sockets exchanging datagrams in memory, all on 127.0.0.1, found by port.
+#[derive(Default)]
+struct MemoryNetwork {
+    inboxes: Arc<Mutex<HashMap<u16, Arc<Mutex<Inbox>>>>>,
+    last_port: AtomicU16,
+}
+
+#[derive(Default)]
+struct Inbox {
+    datagrams: VecDeque<(Bytes, RecvMeta)>,
+    waker: Option<Waker>,
+}
+
+impl SocketFactory for MemoryNetwork {
+    fn bind(&self, addr: SocketAddr, _args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        let port = match addr.port() {
+            0 => 10000 + self.last_port.fetch_add(1, Ordering::Relaxed),
+            port => port,
+        };
+        let inbox = Arc::new(Mutex::new(Inbox::default()));
+        self.inboxes.lock().unwrap().insert(port, inbox.clone());
+        Ok(Box::new(MemorySocket {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            inbox,
+            inboxes: self.inboxes.clone(),
+        }))
+    }
+}
+
+struct MemorySocket {
+    addr: SocketAddr,
+    inbox: Arc<Mutex<Inbox>>,
+    inboxes: Arc<Mutex<HashMap<u16, Arc<Mutex<Inbox>>>>>,
+}
+
+impl fmt::Debug for MemorySocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemorySocket")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl AsyncUdpSocket for MemorySocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        _cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let inboxes = self.inboxes.lock().unwrap();
+        for transmit in transmits {
+            // datagrams to nowhere are lost, like over UDP
+            let Some(inbox) = inboxes.get(&transmit.destination.port()) else {
+                continue;
+            };
+            let mut inbox = inbox.lock().unwrap();
+            if inbox.datagrams.len() >= MAX_QUEUED {
+                continue;
+            }
+            let meta = RecvMeta {
+                addr: self.addr,
+                len: transmit.contents.len(),
+                stride: transmit.segment_size.unwrap_or(transmit.contents.len()),
+                ecn: None,
+                dst_ip: None,
+            };
+            inbox.datagrams.push_back((transmit.contents.clone(), meta));
+            if let Some(waker) = inbox.waker.take() {
+                waker.wake();
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut inbox = self.inbox.lock().unwrap();
+        let mut received = 0;
+        while received < bufs.len().min(meta.len()) {
+            let Some((contents, arrived)) = inbox.datagrams.front() else {
+                break;
+            };
+            // too large for the buffer: truncated, i.e. lost
+            if contents.len() <= bufs[received].len() {
+                bufs[received][..contents.len()].copy_from_slice(contents);
+                meta[received] = *arrived;
+                received += 1;
+            }
+            inbox.datagrams.pop_front();
+        }
+        if received == 0 {
+            inbox.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(received))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
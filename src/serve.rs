@@ -0,0 +1,183 @@
+//! One-shot file server for the listener (`--serve-file FILE`, `--count N`).
+//!
+//! Instead of plumbing stdin/stdout, the file is sent to every client that connects, each on its
+//! own connection and stream, concurrently. A transfer is complete once the client acknowledged
+//! all of it; failed transfers don't count. With `--count`, no more clients are taken than
+//! needed to complete N transfers, and the listener exits once they're complete and the clients
+//! hung up. Each transfer is paced by `--limit-rate` and `--rate-schedule`, and `-i` waits
+//! between its chunks. Clients receive with `nesquic --recv-only IP PORT > FILE`, or continue a
+//! transfer cut short with `nesquic --resume FILE IP PORT` (see [`crate::resume`]).
+//!
+//! The connection is left for the client to close: closing it from here could drop data the
+//! client has received but not yet written out.
+
+use std::{
+    fs,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Duration,
+};
+
+use quinn::{Connecting, Connection, Endpoint, SendStream};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    task::JoinSet,
+};
+use tracing::{debug, error, info};
+
+use crate::counters::StreamCounter;
+use crate::pending::Queue;
+use crate::rate::TokenBucket;
+use crate::report::{self, Direction};
+use crate::{auth, metrics, resume, tasks, timers, Cli};
+
+const READ_CHUNK: usize = 64 * 1024;
+
+/// How long the last clients get to hang up once the count is reached.
+const LINGER: Duration = Duration::from_secs(5);
+
+/// Serves `path` to every client until `--count` transfers are complete, or the endpoint is
+/// closed.
+pub async fn files(endpoint: &Endpoint, path: &Path, args: &Cli) {
+    if let Err(e) = fs::File::open(path) {
+        error!("could not open {}: {}", path.display(), e);
+        process::exit(1);
+    }
+    let mut queue = Queue::new(endpoint, args);
+    let secret = Arc::new(args.auth_token.clone());
+    let path = Arc::new(path.to_path_buf());
+    let mut clients = JoinSet::new();
+    let mut completed = 0;
+    loop {
+        let running = clients.len() as u32;
+        let wanted = args.count.is_none_or(|count| completed + running < count);
+        tokio::select! {
+            Some(connecting) = queue.accept(), if wanted => {
+                let limiter = crate::limiter(args);
+                clients.spawn(serve_client(connecting, path.clone(), secret.clone(), limiter));
+            }
+            Some(joined) = clients.join_next() => {
+                if tasks::output(joined, "[serve] client") == Some(true) {
+                    completed += 1;
+                }
+                if args.count == Some(completed) {
+                    info!("[serve] {} transfer(s) complete", completed);
+                    break;
+                }
+            }
+            else => return,
+        }
+    }
+    endpoint.set_server_config(None);
+    let _ = tokio::time::timeout(LINGER, endpoint.wait_idle()).await;
+}
+
+/// Serves a client, returning whether it received the whole file.
+async fn serve_client(
+    connecting: Connecting,
+    path: Arc<PathBuf>,
+    secret: Arc<Option<String>>,
+    limiter: Option<Arc<TokenBucket>>,
+) -> bool {
+    let Some(conn) = send_file(connecting, &path, secret.as_deref(), limiter).await else {
+        return false;
+    };
+    // the client may still be writing out what it received
+    conn.closed().await;
+    true
+}
+
+/// Sends the file to a new client, returning its connection once it acknowledged all of it.
+async fn send_file(
+    connecting: Connecting,
+    path: &Path,
+    secret: Option<&str>,
+    limiter: Option<Arc<TokenBucket>>,
+) -> Option<Connection> {
+    let conn = match connecting.await {
+        Ok(conn) => conn,
+        Err(e) => {
+            debug!("[serve] handshake failed: {}", e);
+            metrics::handshake_failed();
+            return None;
+        }
+    };
+    report::connection(&conn);
+    let (mut send, mut recv) = match conn.accept_bi().await {
+        Ok(streams) => streams,
+        Err(e) => {
+            debug!("[serve] connection closed before opening a stream: {}", e);
+            return None;
+        }
+    };
+    if !auth::verify(&conn, &mut recv, secret).await {
+        return None;
+    }
+    let offset = match resume::answer(&conn, &mut send, &mut recv, path).await {
+        Ok(offset) => offset,
+        Err(e) => {
+            error!(
+                "[serve] could not resume the transfer to {}: {}",
+                conn.remote_address(),
+                e
+            );
+            return None;
+        }
+    };
+    report::stream_open(send.id(), "serve-file", None);
+    let sent = tokio::select! {
+        sent = copy(&conn, path, offset, &mut send, limiter) => sent,
+        _ = timers::stream_expiry() => {
+            timers::expire_stream(&mut send, &mut recv);
+            return None;
+        }
+    };
+    match sent {
+        Ok(bytes) => {
+            info!("[serve] sent {} bytes to {}", bytes, conn.remote_address());
+            Some(conn)
+        }
+        Err(e) => {
+            error!(
+                "[serve] transfer to {} failed: {}",
+                conn.remote_address(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Sends the file from `offset` on, at the rate `limiter` allows, returning the bytes sent.
+async fn copy(
+    conn: &Connection,
+    path: &Path,
+    offset: u64,
+    send: &mut SendStream,
+    limiter: Option<Arc<TokenBucket>>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut counter = StreamCounter::new(conn, send.id(), Direction::Sent);
+    let mut buffer = vec![0; READ_CHUNK];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        if let Some(limiter) = &limiter {
+            limiter.take(read).await;
+        }
+        send.write_all(&buffer[..read]).await?;
+        timers::touch();
+        counter.add(read);
+        timers::send_interval().await;
+    }
+    // resolves once the client acknowledged everything
+    send.finish().await?;
+    let bytes = counter.bytes();
+    counter.finish();
+    Ok(bytes)
+}
@@ -0,0 +1,135 @@
+//! Signals and console events, mapped to the same [`Event`]s on every platform.
+//!
+//! | Event                | Unix            | Windows                                      |
+//! |----------------------|-----------------|----------------------------------------------|
+//! | [`Event::Shutdown`]  | SIGINT, SIGTERM | Ctrl+C, closing the console, system shutdown |
+//! | [`Event::DumpStats`] | SIGUSR1         | Ctrl+Break                                   |
+//! | [`Event::Reload`]    | SIGHUP          | (none)                                       |
+//!
+//! On shutdown, open connections are closed with a CONNECTION_CLOSE the peer sees right away,
+//! instead of it waiting for the idle timeout, and the process exits with
+//! [`INTERRUPTED_EXIT`]. A stats dump reports the traffic and path of every open connection.
+//! Only a `--daemon` has something to reload, and it handles the events itself (see
+//! [`crate::daemon`]); anything else takes SIGHUP to mean its terminal went away, and shuts down.
+
+use std::{
+    io::{self, stdout, Write},
+    process,
+    time::Duration,
+};
+
+use quinn::VarInt;
+use tracing::debug;
+
+use crate::{counters, report, tasks};
+
+/// Exit code after a shutdown signal, as shells report for SIGINT.
+pub const INTERRUPTED_EXIT: i32 = 130;
+
+/// How long closing connections get to send their CONNECTION_CLOSE before exiting.
+pub const CLOSE_GRACE: Duration = Duration::from_millis(100);
+
+/// What a signal asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Shutdown,
+    DumpStats,
+    Reload,
+}
+
+/// Handlers for the signals, installed for as long as this lives.
+#[cfg(unix)]
+pub struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+    user1: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+/// Handlers for the console events, installed for as long as this lives.
+#[cfg(windows)]
+pub struct Signals {
+    ctrl_c: tokio::signal::windows::CtrlC,
+    ctrl_close: tokio::signal::windows::CtrlClose,
+    ctrl_shutdown: tokio::signal::windows::CtrlShutdown,
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+}
+
+impl Signals {
+    #[cfg(unix)]
+    pub fn install() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            user1: signal(SignalKind::user_defined1())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn install() -> io::Result<Self> {
+        use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_shutdown};
+        Ok(Signals {
+            ctrl_c: ctrl_c()?,
+            ctrl_close: ctrl_close()?,
+            ctrl_shutdown: ctrl_shutdown()?,
+            ctrl_break: ctrl_break()?,
+        })
+    }
+
+    /// Waits for the next signal.
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> Event {
+        let (event, name) = tokio::select! {
+            Some(()) = self.interrupt.recv() => (Event::Shutdown, "SIGINT"),
+            Some(()) = self.terminate.recv() => (Event::Shutdown, "SIGTERM"),
+            Some(()) = self.user1.recv() => (Event::DumpStats, "SIGUSR1"),
+            Some(()) = self.hangup.recv() => (Event::Reload, "SIGHUP"),
+            else => std::future::pending().await,
+        };
+        debug!("[signal] {} received", name);
+        event
+    }
+
+    #[cfg(windows)]
+    pub async fn recv(&mut self) -> Event {
+        let (event, name) = tokio::select! {
+            Some(()) = self.ctrl_c.recv() => (Event::Shutdown, "Ctrl+C"),
+            Some(()) = self.ctrl_close.recv() => (Event::Shutdown, "console close"),
+            Some(()) = self.ctrl_shutdown.recv() => (Event::Shutdown, "system shutdown"),
+            Some(()) = self.ctrl_break.recv() => (Event::DumpStats, "Ctrl+Break"),
+            else => std::future::pending().await,
+        };
+        debug!("[signal] {} received", name);
+        event
+    }
+}
+
+/// Handles signals for the rest of the run: dumping stats, or shutting down gracefully.
+pub fn spawn() -> io::Result<()> {
+    let mut signals = Signals::install()?;
+    tasks::spawn(async move {
+        loop {
+            match signals.recv().await {
+                // without anything to reload, a hangup is the terminal going away
+                Event::Shutdown | Event::Reload => shut_down().await,
+                Event::DumpStats => report::snapshot(),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Closes every open connection and exits with [`INTERRUPTED_EXIT`].
+async fn shut_down() -> ! {
+    let open = counters::open();
+    for (conn, _) in &open {
+        conn.close(VarInt::from_u32(0), b"interrupted");
+    }
+    if !open.is_empty() {
+        tokio::time::sleep(CLOSE_GRACE).await;
+    }
+    let _ = stdout().flush();
+    process::exit(INTERRUPTED_EXIT);
+}
@@ -0,0 +1,339 @@
+//! Connecting through a SOCKS5 proxy (`--proxy socks5://host:port`).
+//!
+//! Where outbound UDP has to go through a proxy, the client asks it for a UDP association
+//! (RFC 1928, `UDP ASSOCIATE`) over a TCP control connection, then sends every QUIC datagram to
+//! the relay address the proxy answered with, prefixed with the SOCKS header naming the actual
+//! destination. Datagrams coming back from the relay carry the same header naming their source,
+//! which is stripped before quinn sees them. All of this happens below quinn, in an
+//! [`AsyncUdpSocket`] the endpoint is built on.
+//!
+//! The association lasts as long as the control connection, which is kept open with the socket.
+//! Username/password authentication (RFC 1929) is used if the URL has credentials.
+
+use std::{
+    fmt,
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    task::{ready, Context, Poll},
+};
+
+use quinn::{
+    udp::{RecvMeta, Transmit, UdpState},
+    AsyncUdpSocket,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tracing::{debug, info};
+
+use crate::{util, Cli};
+
+const DEFAULT_PORT: u16 = 1080;
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 proxy to connect through.
+#[derive(Clone)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keep the password out of debug logs
+        f.debug_struct("Proxy")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+/// Parses `socks5://[USER:PASSWORD@]HOST[:PORT]`.
+pub fn parse_proxy(url: &str) -> Result<Proxy, String> {
+    let rest = url
+        .strip_prefix("socks5://")
+        .ok_or_else(|| format!("expected socks5://HOST:PORT, got '{}'", url))?
+        .trim_end_matches('/');
+    let (credentials, authority) = match rest.rsplit_once('@') {
+        Some((userinfo, authority)) => {
+            let (user, password) = userinfo
+                .split_once(':')
+                .ok_or("credentials must be given as USER:PASSWORD")?;
+            if user.len() > 255 || password.len() > 255 {
+                return Err("user name and password are limited to 255 bytes".into());
+            }
+            (Some((user.to_string(), password.to_string())), authority)
+        }
+        None => (None, rest),
+    };
+    // IPv6 literals come bracketed
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("invalid proxy port '{}'", port))?;
+            (host, port)
+        }
+        _ => (authority, DEFAULT_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("the proxy URL has no host".into());
+    }
+    Ok(Proxy {
+        host: host.to_string(),
+        port,
+        credentials,
+    })
+}
+
+/// Sets up a UDP association with `proxy`, returning a socket that sends and receives through
+/// it.
+pub async fn associate(proxy: &Proxy, args: &Cli) -> io::Result<impl AsyncUdpSocket> {
+    let mut control = TcpStream::connect((proxy.host.as_str(), proxy.port))
+        .await
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "could not connect to proxy {}:{}: {}",
+                    proxy.host, proxy.port, e
+                ),
+            )
+        })?;
+    let proxy_addr = control.peer_addr()?;
+    let socket = util::bind_socket(
+        util::client_bind_addr(proxy_addr, args),
+        args.interface.as_deref(),
+        false,
+    )?;
+    socket.set_nonblocking(true)?;
+    let socket = tokio::net::UdpSocket::from_std(socket)?;
+
+    authenticate(&mut control, proxy).await?;
+    // the address we'll send from, which the proxy may restrict the association to
+    let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+    put_addr(&mut request, socket.local_addr()?);
+    control.write_all(&request).await?;
+    let mut reply = [0u8; 3];
+    control.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(io::Error::other("not a SOCKS5 proxy"));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!(
+            "proxy refused the UDP association: {}",
+            reply_message(reply[1])
+        )));
+    }
+    let mut relay = read_addr(&mut control).await?;
+    // proxies commonly answer 0.0.0.0 for "the address you reached me at"
+    if relay.ip().is_unspecified() {
+        relay.set_ip(proxy_addr.ip());
+    }
+    info!("[proxy] UDP association established, relay at {}", relay);
+
+    Ok(ProxySocket {
+        io: socket,
+        relay,
+        _control: control,
+    })
+}
+
+async fn authenticate(control: &mut TcpStream, proxy: &Proxy) -> io::Result<()> {
+    let greeting: &[u8] = match proxy.credentials {
+        Some(_) => &[VERSION, 2, NO_AUTH, USER_PASS],
+        None => &[VERSION, 1, NO_AUTH],
+    };
+    control.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    control.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(io::Error::other("not a SOCKS5 proxy"));
+    }
+    match (choice[1], &proxy.credentials) {
+        (NO_AUTH, _) => Ok(()),
+        (USER_PASS, Some((user, password))) => {
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            control.write_all(&request).await?;
+            let mut status = [0u8; 2];
+            control.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "proxy authentication failed",
+                ));
+            }
+            debug!("[proxy] authenticated as {}", user);
+            Ok(())
+        }
+        (NO_ACCEPTABLE_METHOD, None) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "proxy requires authentication, give credentials in the URL",
+        )),
+        _ => Err(io::Error::other(
+            "proxy offered no supported authentication method",
+        )),
+    }
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn put_addr(buffer: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buffer.push(ATYP_IPV4);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buffer.push(ATYP_IPV6);
+            buffer.extend_from_slice(&ip.octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_addr(control: &mut TcpStream) -> io::Result<SocketAddr> {
+    let ip = match control.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            control.read_exact(&mut ip).await?;
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            control.read_exact(&mut ip).await?;
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; control.read_u8().await? as usize];
+            control.read_exact(&mut name).await?;
+            let port = control.read_u16().await?;
+            let name = String::from_utf8_lossy(&name).into_owned();
+            return tokio::net::lookup_host((name.as_str(), port))
+                .await?
+                .next()
+                .ok_or_else(|| io::Error::other(format!("no address found for {}", name)));
+        }
+        other => {
+            return Err(io::Error::other(format!(
+                "proxy answered with unknown address type {}",
+                other
+            )))
+        }
+    };
+    let port = control.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Parses the header of a datagram from the relay, returning the source it names and the header
+/// length. Fragments and names aren't supported, the proxy has no reason to send either.
+fn parse_header(datagram: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (&[0, 0, 0, atyp], rest) = datagram.split_first_chunk::<4>()? else {
+        return None;
+    };
+    let (ip, len) = match atyp {
+        ATYP_IPV4 => (IpAddr::from(*rest.first_chunk::<4>()?), 4),
+        ATYP_IPV6 => (IpAddr::from(*rest.first_chunk::<16>()?), 16),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(*rest[len..].first_chunk::<2>()?);
+    Some((SocketAddr::new(ip, port), 4 + len + 2))
+}
+
+/// UDP socket sending and receiving through a SOCKS5 relay.
+#[derive(Debug)]
+struct ProxySocket {
+    io: tokio::net::UdpSocket,
+    relay: SocketAddr,
+    /// Closing the control connection would end the association.
+    _control: TcpStream,
+}
+
+impl AsyncUdpSocket for ProxySocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut sent = 0;
+        for transmit in transmits {
+            // a transmit may hold several datagrams for segmentation offload, which the relay
+            // can't split up
+            let size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for datagram in transmit.contents.chunks(size.max(1)) {
+                let mut packet = vec![0, 0, 0];
+                put_addr(&mut packet, transmit.destination);
+                packet.extend_from_slice(datagram);
+                match self.io.poll_send_to(cx, &packet, self.relay) {
+                    Poll::Ready(Ok(_)) => {}
+                    Poll::Ready(Err(e)) if sent == 0 => return Poll::Ready(Err(e)),
+                    Poll::Pending if sent == 0 => return Poll::Pending,
+                    _ => return Poll::Ready(Ok(sent)),
+                }
+            }
+            sent += 1;
+        }
+        Poll::Ready(Ok(sent))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut buffer = ReadBuf::new(&mut bufs[0]);
+            let from = ready!(self.io.poll_recv_from(cx, &mut buffer))?;
+            let len = buffer.filled().len();
+            if from != self.relay {
+                debug!("[proxy] ignoring datagram from {}", from);
+                continue;
+            }
+            let Some((source, header)) = parse_header(&bufs[0][..len]) else {
+                debug!("[proxy] ignoring malformed datagram from the relay");
+                continue;
+            };
+            bufs[0].copy_within(header..len, 0);
+            meta[0] = RecvMeta {
+                addr: source,
+                len: len - header,
+                stride: len - header,
+                ecn: None,
+                dst_ip: None,
+            };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}
@@ -0,0 +1,191 @@
+//! Time series of connection statistics (`--sample-stats INTERVAL:FILE`).
+//!
+//! Every connection, whatever the mode, has its path and UDP statistics sampled at the given
+//! interval and once more when it closes. Samples are written as CSV if the file name ends in
+//! `.csv`, as one JSON object per line otherwise, and identify the connection by its number (in
+//! the order connections were established) and peer address so several connections can share the
+//! file. Counters are totals since the connection was established; rates are left to whatever
+//! analyzes the file.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use quinn::Connection;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{tasks, timers, Cli};
+
+const CSV_HEADER: &str = "timestamp,connection,peer,rtt_ms,cwnd,congestion_events,sent_packets,\
+    lost_packets,lost_bytes,black_holes,tx_bytes,tx_datagrams,rx_bytes,rx_datagrams";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Csv,
+    JsonLines,
+}
+
+/// Sampling interval and output file.
+#[derive(Clone, Debug)]
+pub struct SampleSpec {
+    interval: Duration,
+    path: PathBuf,
+}
+
+/// Parses `INTERVAL:FILE`, e.g. `1s:stats.csv`.
+pub fn parse_spec(spec: &str) -> Result<SampleSpec, String> {
+    let (interval, path) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("expected INTERVAL:FILE, got '{}'", spec))?;
+    let interval = timers::parse_duration(interval)?;
+    if interval.is_zero() {
+        return Err("the sampling interval must not be zero".into());
+    }
+    if path.is_empty() {
+        return Err("missing file name".into());
+    }
+    Ok(SampleSpec {
+        interval,
+        path: path.into(),
+    })
+}
+
+struct Sink {
+    file: File,
+    format: Format,
+    interval: Duration,
+}
+
+static SINK: OnceLock<Mutex<Sink>> = OnceLock::new();
+/// Number of the next sampled connection.
+static NEXT_CONNECTION: AtomicUsize = AtomicUsize::new(1);
+
+/// Creates the samples file if `--sample-stats` was given.
+pub fn init(args: &Cli) -> io::Result<()> {
+    let Some(spec) = &args.sample_stats else {
+        return Ok(());
+    };
+    let format = format_of(&spec.path);
+    let mut file = File::create(&spec.path)?;
+    if format == Format::Csv {
+        writeln!(file, "{}", CSV_HEADER)?;
+    }
+    let _ = SINK.set(Mutex::new(Sink {
+        file,
+        format,
+        interval: spec.interval,
+    }));
+    Ok(())
+}
+
+fn format_of(path: &Path) -> Format {
+    match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+        _ => Format::JsonLines,
+    }
+}
+
+/// Samples `conn` until it's closed, if sampling is enabled.
+pub fn watch(conn: &Connection) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let interval = sink.lock().unwrap().interval;
+    let number = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    let conn = conn.clone();
+    tasks::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = conn.closed() => break,
+                _ = ticks.tick() => record(&conn, number),
+            }
+        }
+        // the final totals
+        record(&conn, number);
+    });
+}
+
+#[derive(Serialize)]
+struct Sample {
+    timestamp: String,
+    connection: usize,
+    peer: SocketAddr,
+    rtt_ms: f64,
+    cwnd: u64,
+    congestion_events: u64,
+    sent_packets: u64,
+    lost_packets: u64,
+    lost_bytes: u64,
+    black_holes: u64,
+    tx_bytes: u64,
+    tx_datagrams: u64,
+    rx_bytes: u64,
+    rx_datagrams: u64,
+}
+
+impl Sample {
+    fn of(conn: &Connection, number: usize) -> Self {
+        let stats = conn.stats();
+        Sample {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            connection: number,
+            peer: conn.remote_address(),
+            rtt_ms: stats.path.rtt.as_secs_f64() * 1000.0,
+            cwnd: stats.path.cwnd,
+            congestion_events: stats.path.congestion_events,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            black_holes: stats.path.black_holes_detected,
+            tx_bytes: stats.udp_tx.bytes,
+            tx_datagrams: stats.udp_tx.datagrams,
+            rx_bytes: stats.udp_rx.bytes,
+            rx_datagrams: stats.udp_rx.datagrams,
+        }
+    }
+
+    fn csv(&self) -> String {
+        format!(
+            "{},{},{},{:.3},{},{},{},{},{},{},{},{},{},{}",
+            self.timestamp,
+            self.connection,
+            self.peer,
+            self.rtt_ms,
+            self.cwnd,
+            self.congestion_events,
+            self.sent_packets,
+            self.lost_packets,
+            self.lost_bytes,
+            self.black_holes,
+            self.tx_bytes,
+            self.tx_datagrams,
+            self.rx_bytes,
+            self.rx_datagrams
+        )
+    }
+}
+
+fn record(conn: &Connection, number: usize) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let sample = Sample::of(conn, number);
+    let mut sink = sink.lock().unwrap();
+    let line = match sink.format {
+        Format::Csv => sample.csv(),
+        Format::JsonLines => serde_json::to_string(&sample).unwrap(),
+    };
+    if let Err(e) = writeln!(sink.file, "{}", line) {
+        error!("could not write stats sample: {}", e);
+    }
+}
@@ -0,0 +1,113 @@
+//! Traffic tap shared by the send and receive paths (`-x/--hexdump`, `--tee-out`, `--tee-in`).
+//!
+//! Everything sent or received is passed to [`sent`]/[`received`], which print a hex+ASCII dump
+//! to stderr and/or copy the raw bytes to the tee files. The tap is set up once per process so
+//! reconnections (`--retry`) keep appending to the same files.
+
+use std::{
+    fs::File,
+    io::{self, stderr, Write},
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use tracing::error;
+
+use crate::Cli;
+
+/// Bytes per hexdump line.
+const LINE_WIDTH: usize = 16;
+
+struct Tap {
+    hexdump: bool,
+    tee_out: Option<File>,
+    tee_in: Option<File>,
+    /// Offsets of the next sent/received byte, shown in the hexdump.
+    sent: u64,
+    received: u64,
+}
+
+static TAP: OnceLock<Mutex<Tap>> = OnceLock::new();
+
+/// Sets up the tap if any of the tap options were given.
+pub fn init(args: &Cli) -> io::Result<()> {
+    if !args.hexdump && args.tee_out.is_none() && args.tee_in.is_none() {
+        return Ok(());
+    }
+    let create = |path: &Option<PathBuf>| path.as_ref().map(File::create).transpose();
+    let tap = Tap {
+        hexdump: args.hexdump,
+        tee_out: create(&args.tee_out)?,
+        tee_in: create(&args.tee_in)?,
+        sent: 0,
+        received: 0,
+    };
+    let _ = TAP.set(Mutex::new(tap));
+    Ok(())
+}
+
+/// Records data that was sent to the peer.
+pub fn sent(data: &[u8]) {
+    let Some(tap) = TAP.get() else {
+        return;
+    };
+    let mut tap = tap.lock().unwrap();
+    if tap.hexdump {
+        dump('>', tap.sent, data);
+    }
+    tap.sent += data.len() as u64;
+    if let Some(file) = &mut tap.tee_out {
+        if let Err(e) = file.write_all(data) {
+            error!("failed to write to --tee-out file: {}", e);
+        }
+    }
+}
+
+/// Records data that was received from the peer.
+pub fn received(data: &[u8]) {
+    let Some(tap) = TAP.get() else {
+        return;
+    };
+    let mut tap = tap.lock().unwrap();
+    if tap.hexdump {
+        dump('<', tap.received, data);
+    }
+    tap.received += data.len() as u64;
+    if let Some(file) = &mut tap.tee_in {
+        if let Err(e) = file.write_all(data) {
+            error!("failed to write to --tee-in file: {}", e);
+        }
+    }
+}
+
+/// Prints `data` to stderr in `hexdump -C` style, each line prefixed with the direction marker.
+fn dump(marker: char, offset: u64, data: &[u8]) {
+    let mut out = String::new();
+    for (i, line) in data.chunks(LINE_WIDTH).enumerate() {
+        let mut hex = String::with_capacity(LINE_WIDTH * 3 + 1);
+        for (j, byte) in line.iter().enumerate() {
+            if j == LINE_WIDTH / 2 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{} {:08x}  {:<49} |{}|\n",
+            marker,
+            offset + (i * LINE_WIDTH) as u64,
+            hex,
+            ascii
+        ));
+    }
+    let _ = stderr().lock().write_all(out.as_bytes());
+}
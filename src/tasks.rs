@@ -0,0 +1,48 @@
+//! Tasks a mode spawns, such as connection handlers and stream pumps, are kept in a `JoinSet`
+//! owned by the mode's main loop, and each one's result is looked at when it's joined.
+//!
+//! Tasks with no loop to own them, such as the watchers of a connection, timers and the metrics
+//! server, are spawned with [`spawn`] in the mode's background set instead. Finished ones are
+//! joined as new ones come, and [`finish`] aborts and joins the rest once the mode is over, so
+//! none of them outlives the mode either.
+
+use std::{future::Future, sync::Mutex};
+
+use tokio::task::{AbortHandle, JoinError, JoinSet};
+use tracing::error;
+
+/// Background tasks of the running mode.
+static BACKGROUND: Mutex<Option<JoinSet<()>>> = Mutex::new(None);
+
+/// Output of the joined `task`, or `None` if it was aborted or panicked, which is logged.
+pub fn output<T>(joined: Result<T, JoinError>, task: &str) -> Option<T> {
+    match joined {
+        Ok(output) => Some(output),
+        Err(e) if e.is_cancelled() => None,
+        Err(e) => {
+            error!("{} panicked: {}", task, e);
+            None
+        }
+    }
+}
+
+/// Runs `task` in the mode's background set, until it's done or the mode is over.
+pub fn spawn(task: impl Future<Output = ()> + Send + 'static) -> AbortHandle {
+    let mut background = BACKGROUND.lock().unwrap();
+    let background = background.get_or_insert_with(JoinSet::new);
+    while let Some(joined) = background.try_join_next() {
+        output(joined, "background task");
+    }
+    background.spawn(task)
+}
+
+/// Aborts the background tasks still running once the mode is over, and joins them.
+pub async fn finish() {
+    let Some(mut background) = BACKGROUND.lock().unwrap().take() else {
+        return;
+    };
+    background.abort_all();
+    while let Some(joined) = background.join_next().await {
+        output(joined, "background task");
+    }
+}
@@ -0,0 +1,257 @@
+//! In-process test harness (`testing` feature): a server and a client endpoint talking over
+//! localhost, optionally through a simulated network that delays and drops packets.
+//!
+//! The simulation is `--emulate`'s (see [`crate::emulate`]), in both endpoints' sockets, so
+//! everything above it, quinn included, runs exactly as it would over a real network. Drops
+//! follow a fixed seed, so a failing run can be reproduced.
+
+// nothing in the binary uses the harness, only the tests
+#![cfg_attr(not(test), allow(dead_code))]
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use clap::Parser;
+use quinn::{AsyncUdpSocket, Connection, Endpoint};
+
+use crate::emulate::Emulation;
+use crate::util::{self, SocketFactory};
+use crate::Cli;
+
+/// How the simulated network treats datagrams, in each direction.
+#[derive(Clone, Copy, Debug)]
+pub struct Network {
+    pub latency: Duration,
+    /// Probability of dropping a datagram, from 0 to 1.
+    pub loss: f64,
+    pub seed: u64,
+}
+
+impl Network {
+    /// Plain localhost.
+    pub fn perfect() -> Self {
+        Network::lossy(Duration::ZERO, 0.0)
+    }
+
+    pub fn lossy(latency: Duration, loss: f64) -> Self {
+        Network {
+            latency,
+            loss,
+            seed: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+}
+
+impl SocketFactory for Network {
+    fn bind(&self, addr: SocketAddr, args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Emulation {
+            delay: self.latency,
+            loss: self.loss,
+            seed: self.seed,
+            ..Emulation::default()
+        }
+        .bind(addr, args)
+    }
+}
+
+/// Default settings, as if nesquic had been run without options.
+pub fn default_args() -> Cli {
+    Cli::parse_from(["nesquic"])
+}
+
+/// A server endpoint and a client endpoint for it, both on the same simulated network.
+pub struct Pair {
+    pub server: Endpoint,
+    pub client: Endpoint,
+    server_addr: SocketAddr,
+}
+
+impl Pair {
+    pub async fn new(network: &Network) -> Self {
+        let args = default_args();
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let (server, _) = util::make_server_endpoint_with(&[localhost], &args, network)
+            .expect("could not start the server endpoint");
+        let server_addr = server.local_addr().unwrap();
+        let client = util::make_client_endpoint_with(server_addr, &args, network)
+            .await
+            .expect("could not start the client endpoint");
+        Pair {
+            server,
+            client,
+            server_addr,
+        }
+    }
+
+    /// Connects the client to the server, returning the client's and the server's connection.
+    pub async fn connect(&self) -> (Connection, Connection) {
+        let connecting = self.client.connect(self.server_addr, "localhost").unwrap();
+        let (client, server) = tokio::join!(connecting, async {
+            self.server
+                .accept()
+                .await
+                .expect("server endpoint closed")
+                .await
+        });
+        (
+            client.expect("client handshake failed"),
+            server.expect("server handshake failed"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quinn::{ReadError, VarInt, WriteError};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::channel;
+    use crate::counters::StreamCounter;
+    use crate::report::Direction;
+    use crate::testpeer::{self, Mode};
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn payload(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn echo_ends_with_eof_after_the_data() {
+        let pair = Pair::new(&Network::perfect()).await;
+        let (client, server) = pair.connect().await;
+        let (mut send, mut recv) = client.open_bi().await.unwrap();
+        send.write_all(b"hello\n").await.unwrap();
+        send.finish().await.unwrap();
+        let (server_send, server_recv) = server.accept_bi().await.unwrap();
+        tokio::spawn(testpeer::serve(
+            server,
+            server_send,
+            server_recv,
+            Mode::Echo,
+            None,
+        ));
+
+        let echoed = tokio::time::timeout(TIMEOUT, recv.read_to_end(1024))
+            .await
+            .expect("no EOF after the echoed data")
+            .unwrap();
+        assert_eq!(echoed, b"hello\n");
+    }
+
+    #[tokio::test]
+    async fn empty_stream_is_just_eof() {
+        let pair = Pair::new(&Network::perfect()).await;
+        let (client, server) = pair.connect().await;
+        let (mut send, _recv) = client.open_bi().await.unwrap();
+        // a stream is only announced to the peer once something is sent on it, FIN included
+        send.finish().await.unwrap();
+        let (_send, mut recv) = server.accept_bi().await.unwrap();
+        let mut received = Vec::new();
+        let mut counter = StreamCounter::new(&server, recv.id(), Direction::Received);
+        channel::pump(&mut recv, &mut received, &mut counter)
+            .await
+            .unwrap();
+        assert!(received.is_empty());
+        assert_eq!(counter.bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn large_transfer_survives_latency_and_loss() {
+        let network = Network::lossy(Duration::from_millis(10), 0.02);
+        let pair = Pair::new(&network).await;
+        let (client, server) = pair.connect().await;
+        let data = payload(4 * 1024 * 1024);
+
+        let sending = async {
+            let (mut send, _recv) = client.open_bi().await.unwrap();
+            let mut counter = StreamCounter::new(&client, send.id(), Direction::Sent);
+            channel::pump(&mut &data[..], &mut send, &mut counter)
+                .await
+                .unwrap();
+            send.finish().await.unwrap();
+            counter.bytes()
+        };
+        let receiving = async {
+            let (_send, mut recv) = server.accept_bi().await.unwrap();
+            let mut received = Vec::new();
+            let mut counter = StreamCounter::new(&server, recv.id(), Direction::Received);
+            channel::pump(&mut recv, &mut received, &mut counter)
+                .await
+                .unwrap();
+            (received, counter.bytes())
+        };
+        let (sent, (received, received_bytes)) =
+            tokio::time::timeout(TIMEOUT, async { tokio::join!(sending, receiving) })
+                .await
+                .expect("transfer didn't complete");
+
+        assert_eq!(sent, data.len() as u64);
+        assert_eq!(received_bytes, data.len() as u64);
+        assert!(received == data, "received data differs from what was sent");
+        assert!(client.stats().path.lost_packets > 0, "no packet was lost");
+    }
+
+    #[tokio::test]
+    async fn reset_stream_is_not_mistaken_for_eof() {
+        let pair = Pair::new(&Network::perfect()).await;
+        let (client, server) = pair.connect().await;
+        let (mut send, _recv) = client.open_bi().await.unwrap();
+        send.write_all(&payload(1000)).await.unwrap();
+        let (_send, mut recv) = server.accept_bi().await.unwrap();
+        send.reset(VarInt::from_u32(42)).unwrap();
+
+        let mut received = Vec::new();
+        let mut counter = StreamCounter::new(&server, recv.id(), Direction::Received);
+        let result = tokio::time::timeout(
+            TIMEOUT,
+            channel::pump(&mut recv, &mut received, &mut counter),
+        )
+        .await
+        .expect("reading a reset stream hangs");
+        assert!(result.is_err(), "a reset stream ended like a finished one");
+    }
+
+    #[tokio::test]
+    async fn abrupt_close_fails_both_directions() {
+        let pair = Pair::new(&Network::lossy(Duration::from_millis(5), 0.0)).await;
+        let (client, server) = pair.connect().await;
+        let (mut send, mut recv) = client.open_bi().await.unwrap();
+        send.write_all(b"start").await.unwrap();
+        let (_server_send, mut server_recv) = server.accept_bi().await.unwrap();
+        let mut first = [0u8; 5];
+        server_recv.read_exact(&mut first).await.unwrap();
+        server.close(VarInt::from_u32(7), b"going away");
+
+        // an endless sender has to notice instead of blocking on flow control forever
+        let mut endless = tokio::io::repeat(0).take(u64::MAX);
+        let mut counter = StreamCounter::new(&client, send.id(), Direction::Sent);
+        let sending = tokio::time::timeout(
+            TIMEOUT,
+            channel::pump(&mut endless, &mut send, &mut counter),
+        )
+        .await
+        .expect("sending to a closed connection hangs");
+        assert!(sending.is_err());
+        assert!(matches!(
+            send.write(b"more").await,
+            Err(WriteError::ConnectionLost(_))
+        ));
+        assert!(matches!(
+            recv.read(&mut [0u8; 16]).await,
+            Err(ReadError::ConnectionLost(_))
+        ));
+    }
+}
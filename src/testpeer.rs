@@ -0,0 +1,186 @@
+//! Self-contained test peer modes for the listener (`--echo`, `--discard`).
+//!
+//! Instead of plumbing stdin/stdout, every stream the client opens is served on its own: echoed
+//! back as it arrives, or read and dropped with a throughput report on stderr once the client
+//! finishes it. Handy for RTT and throughput measurements against a bare `nesquic -l`.
+//!
+//! With `--warmup`, the discard report also gives the throughput measured after the warm-up
+//! period only, so slow start doesn't drag down the numbers compared across runs.
+
+use std::time::{Duration, Instant};
+
+use quinn::{Connection, RecvStream, SendStream};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, warn};
+
+use crate::counters::StreamCounter;
+use crate::report::{self, Direction, Event, SteadyState};
+use crate::{channel, timers, Cli};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Mode {
+    Echo,
+    Discard,
+}
+
+/// Test peer mode asked for on the command line, if any.
+pub fn mode(args: &Cli) -> Option<Mode> {
+    if args.echo {
+        Some(Mode::Echo)
+    } else if args.discard {
+        Some(Mode::Discard)
+    } else {
+        None
+    }
+}
+
+/// Serves the first stream and every stream the client opens after it until the connection is
+/// closed.
+pub async fn serve(
+    conn: Connection,
+    send: SendStream,
+    recv: RecvStream,
+    mode: Mode,
+    warmup: Option<Duration>,
+) {
+    timers::watch_session(&conn);
+    let tracker = TaskTracker::new();
+    tracker.spawn(handle(conn.clone(), mode, send, recv, warmup));
+    loop {
+        match conn.accept_bi().await {
+            Ok((send, recv)) => {
+                tracker.spawn(handle(conn.clone(), mode, send, recv, warmup));
+            }
+            Err(e) => {
+                debug!("[{:?}] connection closed: {}", mode, e);
+                break;
+            }
+        }
+    }
+    tracker.close();
+    tracker.wait().await;
+}
+
+async fn handle(
+    conn: Connection,
+    mode: Mode,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    warmup: Option<Duration>,
+) {
+    let expired = tokio::select! {
+        _ = serve_stream(&conn, mode, &mut send, &mut recv, warmup) => false,
+        _ = timers::stream_expiry() => true,
+    };
+    if expired {
+        timers::expire_stream(&mut send, &mut recv);
+    }
+}
+
+async fn serve_stream(
+    conn: &Connection,
+    mode: Mode,
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    warmup: Option<Duration>,
+) {
+    match mode {
+        Mode::Echo => {
+            report::stream_open(recv.id(), "echo", None);
+            let mut received = StreamCounter::new(conn, recv.id(), Direction::Received);
+            let mut sent = StreamCounter::new(conn, send.id(), Direction::Sent);
+            match channel::pump(recv, send, &mut received).await {
+                Ok(()) => {
+                    // everything received was sent back
+                    sent.add(received.bytes() as usize);
+                    received.finish();
+                    sent.finish();
+                }
+                Err(e) => error!("[echo] stream {}: {}", recv.id().index(), e),
+            }
+            let _ = send.finish().await;
+        }
+        Mode::Discard => {
+            report::stream_open(recv.id(), "discard", None);
+            let _ = send.finish().await;
+            discard(
+                recv,
+                StreamCounter::new(conn, recv.id(), Direction::Received),
+                warmup,
+            )
+            .await;
+        }
+    }
+}
+
+/// Reads the stream to the end and reports how fast it arrived, overall and after `warmup`.
+async fn discard(recv: &mut RecvStream, mut counter: StreamCounter, warmup: Option<Duration>) {
+    // bytes read by the end of the warm-up, and when that was
+    let mut warmed_up: Option<(u64, Instant)> = None;
+    loop {
+        match recv.read_chunk(1024 * 1024, false).await {
+            Ok(Some(chunk)) => {
+                timers::touch();
+                if let Some(warmup) = warmup {
+                    let now = Instant::now();
+                    if warmed_up.is_none() && counter.elapsed() >= warmup {
+                        warmed_up = Some((counter.bytes(), now));
+                    }
+                }
+                counter.add(chunk.bytes.len());
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("[discard] stream {}: {}", recv.id().index(), e);
+                break;
+            }
+        }
+    }
+    let bytes = counter.bytes();
+    let steady_state = match (warmup, warmed_up) {
+        (Some(warmup), Some((before, at))) => {
+            let seconds = at.elapsed().as_secs_f64();
+            Some(SteadyState {
+                warmup_seconds: warmup.as_secs_f64(),
+                bytes: bytes - before,
+                seconds,
+                bytes_per_second: per_second(bytes - before, seconds),
+            })
+        }
+        (Some(warmup), None) => {
+            warn!(
+                "[discard] stream {} ended within the {:?} warm-up, no steady-state throughput",
+                recv.id().index(),
+                warmup
+            );
+            None
+        }
+        _ => None,
+    };
+    report_throughput(recv, bytes, counter.elapsed(), steady_state);
+    counter.finish();
+}
+
+fn report_throughput(
+    recv: &RecvStream,
+    bytes: u64,
+    elapsed: Duration,
+    steady_state: Option<SteadyState>,
+) {
+    let seconds = elapsed.as_secs_f64();
+    report::emit(Event::Discard {
+        stream: recv.id().index(),
+        bytes,
+        seconds,
+        bytes_per_second: per_second(bytes, seconds),
+        steady_state,
+    });
+}
+
+fn per_second(bytes: u64, seconds: f64) -> f64 {
+    if seconds > 0.0 {
+        bytes as f64 / seconds
+    } else {
+        0.0
+    }
+}
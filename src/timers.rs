@@ -0,0 +1,255 @@
+//! Connect timeout, stream open timeout, inactivity timeout and overall deadline
+//! (`--connect-timeout`, `--open-timeout`, `--idle-exit` and `-w`).
+//!
+//! The timers run as watchdog tasks rather than wrapping the session futures because the send
+//! path may be blocked reading stdin. When one fires, the current connection is closed with the
+//! exit code as application error code and the process exits with that code, so scripts can
+//! tell what happened.
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, stdout, Write},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use quinn::{
+    Connecting, Connection, ConnectionError, ReadError, RecvStream, SendStream, VarInt, WriteError,
+};
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::error;
+
+use crate::messages::tr;
+use crate::report::{self, Event};
+use crate::{tasks, Cli};
+
+/// Exit code when connecting took longer than `--connect-timeout`.
+pub const CONNECT_TIMEOUT_EXIT: i32 = 3;
+/// Exit code when no data was transferred for `--idle-exit`.
+pub const IDLE_EXIT: i32 = 4;
+/// Exit code when the `-w` deadline was reached.
+pub const DEADLINE_EXIT: i32 = 5;
+/// Application error code used to reset streams that reached `--stream-deadline`.
+pub const STREAM_DEADLINE_EXCEEDED: u32 = 8;
+
+static START: OnceLock<Instant> = OnceLock::new();
+/// Milliseconds since [`START`] when data was last sent or received.
+static LAST_ACTIVITY: AtomicU64 = AtomicU64::new(0);
+/// Connection of the running session, closed when a timer fires.
+static SESSION: Mutex<Option<Connection>> = Mutex::new(None);
+/// `--open-timeout`, if given.
+static OPEN_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+/// `--stream-deadline`, if given.
+static STREAM_DEADLINE: OnceLock<Duration> = OnceLock::new();
+/// `-i`, if given.
+static SEND_INTERVAL: OnceLock<Duration> = OnceLock::new();
+
+/// Parses a duration such as `5`, `5s`, `500ms` or `2m`. Plain numbers are seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (value, scale) = if let Some(ms) = duration.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(s) = duration.strip_suffix('s') {
+        (s, 1.0)
+    } else if let Some(m) = duration.strip_suffix('m') {
+        (m, 60.0)
+    } else if let Some(h) = duration.strip_suffix('h') {
+        (h, 3600.0)
+    } else {
+        (duration, 1.0)
+    };
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", duration))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("invalid duration '{}'", duration));
+    }
+    Ok(Duration::from_secs_f64(value * scale))
+}
+
+/// Starts the clock and the watchdogs for `--idle-exit` and `-w`.
+pub fn start(args: &Cli) {
+    let start = *START.get_or_init(Instant::now);
+    if let Some(timeout) = args.open_timeout {
+        let _ = OPEN_TIMEOUT.set(timeout);
+    }
+    if let Some(deadline) = args.stream_deadline {
+        let _ = STREAM_DEADLINE.set(deadline);
+    }
+    if let Some(interval) = args.interval {
+        let _ = SEND_INTERVAL.set(interval);
+    }
+
+    if let Some(deadline) = args.deadline {
+        tasks::spawn(async move {
+            sleep_until(start + deadline).await;
+            expire(DEADLINE_EXIT, "deadline reached", &tr!(DeadlineReached)).await;
+        });
+    }
+
+    if let Some(idle) = args.idle_exit {
+        tasks::spawn(async move {
+            loop {
+                let since_activity =
+                    since_start().saturating_sub(LAST_ACTIVITY.load(Ordering::Relaxed));
+                let since_activity = Duration::from_millis(since_activity);
+                if SESSION.lock().unwrap().is_some() && since_activity >= idle {
+                    expire(IDLE_EXIT, "idle timeout", &tr!(IdleTimeout)).await;
+                }
+                sleep(
+                    idle.saturating_sub(since_activity)
+                        .max(Duration::from_millis(100)),
+                )
+                .await;
+            }
+        });
+    }
+}
+
+/// Records that data was sent or received, postponing `--idle-exit`.
+pub fn touch() {
+    LAST_ACTIVITY.store(since_start(), Ordering::Relaxed);
+}
+
+/// Registers the connection of a starting session so timers close it before exiting.
+pub fn watch_session(conn: &Connection) {
+    touch();
+    *SESSION.lock().unwrap() = Some(conn.clone());
+}
+
+/// Waits out `-i` after sending a chunk or line, right away without it.
+pub async fn send_interval() {
+    if let Some(interval) = SEND_INTERVAL.get() {
+        sleep(*interval).await;
+    }
+}
+
+/// Resolves once a stream opened now reaches `--stream-deadline`, never without one.
+pub async fn stream_expiry() {
+    match STREAM_DEADLINE.get() {
+        Some(deadline) => sleep(*deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resets both directions of a stream that reached `--stream-deadline`.
+pub fn expire_stream(send: &mut SendStream, recv: &mut RecvStream) {
+    let code = VarInt::from_u32(STREAM_DEADLINE_EXCEEDED);
+    let _ = send.reset(code);
+    let _ = recv.stop(code);
+    report::emit(Event::StreamDeadline {
+        stream: send.id().index(),
+        seconds: STREAM_DEADLINE.get().map_or(0.0, Duration::as_secs_f64),
+    });
+}
+
+/// Whether a stream failed because the peer reset it at its `--stream-deadline`.
+pub fn is_stream_deadline(e: &io::Error) -> bool {
+    let code = VarInt::from_u32(STREAM_DEADLINE_EXCEEDED);
+    match e.get_ref().map(|e| e as &(dyn Error + 'static)) {
+        Some(e) => {
+            matches!(e.downcast_ref(), Some(WriteError::Stopped(c)) if *c == code)
+                || matches!(e.downcast_ref(), Some(ReadError::Reset(c)) if *c == code)
+        }
+        None => false,
+    }
+}
+
+/// Error of a connection attempt bounded by `--connect-timeout`.
+#[derive(Debug)]
+pub enum ConnectError {
+    TimedOut(Duration),
+    Failed(ConnectionError),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::TimedOut(timeout) => {
+                f.write_str(&tr!(ConnectTimedOut, format!("{:?}", timeout)))
+            }
+            ConnectError::Failed(e) => f.write_str(&tr!(ConnectFailed, e)),
+        }
+    }
+}
+
+impl Error for ConnectError {}
+
+/// Awaits a connection attempt, failing with [`ConnectError::TimedOut`] if it takes longer than
+/// `timeout`.
+pub async fn connect(
+    connecting: Connecting,
+    timeout: Option<Duration>,
+) -> Result<Connection, ConnectError> {
+    let Some(timeout) = timeout else {
+        return connecting.await.map_err(ConnectError::Failed);
+    };
+    match tokio::time::timeout(timeout, connecting).await {
+        Ok(conn) => conn.map_err(ConnectError::Failed),
+        Err(_) => Err(ConnectError::TimedOut(timeout)),
+    }
+}
+
+/// Error of opening a stream bounded by `--open-timeout`.
+#[derive(Debug)]
+pub enum OpenError {
+    TimedOut(Duration),
+    Failed(ConnectionError),
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenError::TimedOut(timeout) => write!(
+                f,
+                "could not open a stream within {:?}, the peer's stream limit may be reached",
+                timeout
+            ),
+            OpenError::Failed(e) => write!(f, "could not open a stream: {}", e),
+        }
+    }
+}
+
+impl Error for OpenError {}
+
+/// Opens a bidirectional stream, failing with [`OpenError::TimedOut`] if the peer doesn't allow
+/// it within `--open-timeout`.
+pub async fn open_bi(conn: &Connection) -> Result<(SendStream, RecvStream), OpenError> {
+    let Some(&timeout) = OPEN_TIMEOUT.get() else {
+        return conn.open_bi().await.map_err(OpenError::Failed);
+    };
+    match tokio::time::timeout(timeout, conn.open_bi()).await {
+        Ok(streams) => streams.map_err(OpenError::Failed),
+        Err(_) => Err(OpenError::TimedOut(timeout)),
+    }
+}
+
+/// Exits with [`CONNECT_TIMEOUT_EXIT`] if `e` is a connect timeout.
+pub fn exit_if_timed_out(e: &(dyn Error + 'static)) {
+    if let Some(ConnectError::TimedOut(_)) = e.downcast_ref() {
+        error!("{}", e);
+        process::exit(CONNECT_TIMEOUT_EXIT);
+    }
+}
+
+fn since_start() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Closes the session's connection, if any, with `reason` and exits with `code`, after showing
+/// `message`.
+pub async fn expire(code: i32, reason: &str, message: &str) -> ! {
+    error!("{}", message);
+    let conn = SESSION.lock().unwrap().take();
+    if let Some(conn) = conn {
+        conn.close(VarInt::from_u32(code as u32), reason.as_bytes());
+        // give the close frame a chance to go out
+        sleep(Duration::from_millis(100)).await;
+    }
+    let _ = stdout().flush();
+    process::exit(code);
+}
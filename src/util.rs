@@ -1,27 +1,426 @@
-use quinn::{ClientConfig, Endpoint, ServerConfig, TransportConfig};
-use std::{error::Error, net::SocketAddr, sync::Arc, time::Duration};
+use chrono::Datelike;
+use quinn::{
+    udp::{RecvMeta, Transmit, UdpState},
+    AsyncUdpSocket, ClientConfig, Connection, Endpoint, EndpointConfig, Runtime, ServerConfig,
+    TransportConfig,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    error::Error,
+    fs,
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
-pub fn make_server_endpoint(bind_addr: SocketAddr) -> Result<(Endpoint, Vec<u8>), Box<dyn Error>> {
-    let (server_config, server_cert) = configure_server()?;
-    let endpoint = Endpoint::server(server_config, bind_addr)?;
+use crate::messages::tr;
+use crate::multihome::MultiSocket;
+use crate::{lowmem, negotiation, socks, Cli};
+
+/// Where endpoints get their sockets from, so nesquic can run over something other than kernel
+/// UDP sockets: a simulated lossy network, a userspace tunnel, or a socket capturing packets.
+pub trait SocketFactory: Send + Sync {
+    /// Socket bound to `addr`, or as close to it as the transport allows.
+    fn bind(&self, addr: SocketAddr, args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>>;
+
+    /// Like [`bind`](Self::bind), for one of several addresses listened on at once, where an IPv6
+    /// socket mustn't take IPv4 traffic too, since that goes to the IPv4 addresses.
+    fn bind_v6_only(&self, addr: SocketAddr, args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        self.bind(addr, args)
+    }
+}
+
+/// Kernel UDP sockets, bound to `--interface` if given.
+pub struct KernelSockets;
+
+impl SocketFactory for KernelSockets {
+    fn bind(&self, addr: SocketAddr, args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        runtime()?.wrap_udp_socket(bind_socket(addr, args.interface.as_deref(), false)?)
+    }
+
+    fn bind_v6_only(&self, addr: SocketAddr, args: &Cli) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        runtime()?.wrap_udp_socket(bind_socket(addr, args.interface.as_deref(), true)?)
+    }
+}
+
+/// Lets a socket from a [`SocketFactory`] be handed to quinn, noting the QUIC version of the
+/// packets received.
+#[derive(Debug)]
+struct BoxedSocket(Box<dyn AsyncUdpSocket>);
+
+impl AsyncUdpSocket for BoxedSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        self.0.poll_send(state, cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let received = self.0.poll_recv(cx, bufs, meta);
+        if let Poll::Ready(Ok(count)) = received {
+            for (buf, meta) in bufs.iter().zip(meta.iter()).take(count) {
+                negotiation::observe(&buf[..meta.len], meta.addr);
+            }
+        }
+        received
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.0.may_fragment()
+    }
+}
+
+fn runtime() -> io::Result<Arc<dyn Runtime>> {
+    quinn::default_runtime().ok_or_else(|| io::Error::other("no async runtime found"))
+}
+
+fn endpoint_on(
+    socket: Box<dyn AsyncUdpSocket>,
+    server_config: Option<ServerConfig>,
+    args: &Cli,
+) -> io::Result<Endpoint> {
+    let mut config = EndpointConfig::default();
+    if args.low_memory.is_some() {
+        lowmem::limit_endpoint(&mut config);
+    }
+    negotiation::limit_endpoint(&mut config, args);
+    Endpoint::new_with_abstract_socket(config, server_config, BoxedSocket(socket), runtime()?)
+}
+
+/// Server endpoint listening on all of `bind_addrs`.
+pub fn make_server_endpoint(
+    bind_addrs: &[SocketAddr],
+    args: &Cli,
+) -> Result<(Endpoint, Vec<u8>), Box<dyn Error>> {
+    make_server_endpoint_with(bind_addrs, args, sockets(args))
+}
+
+/// Like [`make_server_endpoint`], with the sockets from `sockets` unless the service manager
+/// passed one.
+pub fn make_server_endpoint_with(
+    bind_addrs: &[SocketAddr],
+    args: &Cli,
+    sockets: &dyn SocketFactory,
+) -> Result<(Endpoint, Vec<u8>), Box<dyn Error>> {
+    let (server_config, server_cert) = configure_server(args)?;
+    let socket = match (inherited_socket(args)?, bind_addrs) {
+        (Some(socket), _) => runtime()?.wrap_udp_socket(socket)?,
+        (None, [bind_addr]) => sockets.bind(*bind_addr, args)?,
+        (None, bind_addrs) => Box::new(MultiSocket::new(
+            bind_addrs
+                .iter()
+                .map(|bind_addr| sockets.bind_v6_only(*bind_addr, args))
+                .collect::<io::Result<_>>()?,
+        )),
+    };
+    let endpoint = endpoint_on(socket, Some(server_config), args)?;
     Ok((endpoint, server_cert))
 }
-pub fn configure_server() -> Result<(ServerConfig, Vec<u8>), Box<dyn Error>> {
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    let cert_der = cert.serialize_der().unwrap();
-    let priv_key = cert.serialize_private_key_der();
-    let priv_key = rustls::PrivateKey(priv_key);
-    let cert_chain = vec![rustls::Certificate(cert_der.clone())];
-
-    let mut server_config = ServerConfig::with_single_cert(cert_chain, priv_key)?;
-    let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
-    transport_config.max_concurrent_uni_streams(0_u8.into());
-    // Set the idle timeout to higher values
-    transport_config.max_idle_timeout(Some(Duration::from_secs(5 * 60).try_into().unwrap()));
+
+/// Client endpoint for connecting to `remote`, bound to `--source-addr`, `--source-port` and
+/// `--interface` if given, and going through `--proxy` if given. Fails if `remote` can't be
+/// reached from here.
+pub async fn make_client_endpoint(
+    remote: SocketAddr,
+    args: &Cli,
+) -> Result<Endpoint, Box<dyn Error>> {
+    // a proxy is reached over TCP, and --interface routes differently
+    if args.proxy.is_none() && args.interface.is_none() {
+        check_route(remote, args)?;
+    }
+    make_client_endpoint_with(remote, args, sockets(args)).await
+}
+
+/// The kernel's sockets, behind `--emulate`'s impairments if given.
+fn sockets(args: &Cli) -> &dyn SocketFactory {
+    match &args.emulate {
+        Some(emulation) => emulation,
+        None => &KernelSockets,
+    }
+}
+
+/// Like [`make_client_endpoint`], with the socket from `sockets` unless going through a proxy.
+pub async fn make_client_endpoint_with(
+    remote: SocketAddr,
+    args: &Cli,
+    sockets: &dyn SocketFactory,
+) -> Result<Endpoint, Box<dyn Error>> {
+    let socket: Box<dyn AsyncUdpSocket> = match &args.proxy {
+        Some(proxy) => Box::new(socks::associate(proxy, args).await?),
+        None => sockets.bind(client_bind_addr(remote, args), args)?,
+    };
+    let mut endpoint = endpoint_on(socket, None, args)?;
+    endpoint.set_default_client_config(configure_client(args));
+    Ok(endpoint)
+}
+
+/// Local address for connecting to `remote`: `--source-addr` and `--source-port`, or any address
+/// of the same family and an ephemeral port.
+pub fn client_bind_addr(remote: SocketAddr, args: &Cli) -> SocketAddr {
+    let ip = args.source_addr.unwrap_or(if remote.is_ipv6() {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    });
+    SocketAddr::new(ip, args.source_port.unwrap_or(0))
+}
+
+/// Binds a UDP socket to `addr`, only sending and receiving through `interface` if given. With
+/// `v6_only`, an IPv6 socket doesn't take IPv4 traffic, so it doesn't conflict with an IPv4 socket
+/// on the same port.
+pub fn bind_socket(
+    addr: SocketAddr,
+    interface: Option<&str>,
+    v6_only: bool,
+) -> io::Result<UdpSocket> {
+    let bound = if v6_only && addr.is_ipv6() {
+        bind_v6_only(addr)
+    } else {
+        UdpSocket::bind(addr)
+    };
+    let socket = bound.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("could not bind to {}: {}{}", addr, e, hint(&e, addr)),
+        )
+    })?;
+    if let Some(interface) = interface {
+        bind_to_device(&socket, interface).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not bind to interface {}: {}", interface, e),
+            )
+        })?;
+    }
+    Ok(socket)
+}
+
+fn bind_v6_only(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Checks that datagrams to `remote` can leave this host, so a sandbox or a missing route is
+/// reported right away instead of as a handshake timeout. Nothing is sent.
+fn check_route(remote: SocketAddr, args: &Cli) -> io::Result<()> {
+    let local = SocketAddr::new(client_bind_addr(remote, args).ip(), 0);
+    let probe = bind_socket(local, None, false)?;
+    probe.connect(remote).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("could not reach {}: {}{}", remote, e, hint(&e, remote)),
+        )
+    })
+}
+
+/// What to do about a socket error caused by the environment rather than by nesquic, e.g. UDP
+/// being forbidden by a sandbox, as a suffix for the error message.
+fn hint(e: &io::Error, addr: SocketAddr) -> String {
+    let hint = match e.kind() {
+        io::ErrorKind::PermissionDenied if cfg!(unix) && (1..1024).contains(&addr.port()) => {
+            tr!(PrivilegedPort)
+        }
+        io::ErrorKind::PermissionDenied => tr!(UdpNotPermitted),
+        io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable => {
+            tr!(NoRoute, addr.ip())
+        }
+        _ if addr.is_ipv6() && is_family_unsupported(e) => tr!(Ipv6Unavailable),
+        _ => return String::new(),
+    };
+    format!("; {}", hint)
+}
+
+#[cfg(unix)]
+fn is_family_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EAFNOSUPPORT)
+}
+
+#[cfg(windows)]
+fn is_family_unsupported(e: &io::Error) -> bool {
+    // WSAEAFNOSUPPORT
+    e.raw_os_error() == Some(10047)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_family_unsupported(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the name is valid for reads of the length passed
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    use std::{ffi::CString, os::fd::AsRawFd};
+
+    let name =
+        CString::new(interface).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: the name is a valid C string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (level, option) = if socket.local_addr()?.is_ipv6() {
+        (libc::IPPROTO_IPV6, libc::IPV6_BOUND_IF)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_BOUND_IF)
+    };
+    // SAFETY: the index is valid for reads of the length passed
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &index as *const libc::c_uint as *const libc::c_void,
+            std::mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+fn bind_to_device(_socket: &UdpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux and macOS",
+    ))
+}
+
+/// The socket passed by systemd to a `--daemon`, if any.
+#[cfg(unix)]
+fn inherited_socket(args: &Cli) -> std::io::Result<Option<UdpSocket>> {
+    if !args.daemon {
+        return Ok(None);
+    }
+    crate::daemon::listen_socket()
+}
+
+#[cfg(not(unix))]
+fn inherited_socket(_args: &Cli) -> std::io::Result<Option<UdpSocket>> {
+    Ok(None)
+}
+
+pub fn configure_server(args: &Cli) -> Result<(ServerConfig, Vec<u8>), Box<dyn Error>> {
+    let (cert_chain, priv_key) = match (&args.psk, &args.cert, &args.key) {
+        (Some(psk), _, _) => psk.identity()?,
+        (None, Some(cert), Some(key)) => load_cert(cert, key)?,
+        _ => generate_cert(args)?,
+    };
+    let cert_der = cert_chain[0].0.clone();
+
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match &args.psk {
+        Some(psk) => builder.with_client_cert_verifier(psk.verifier()),
+        None => builder.with_no_client_auth(),
+    };
+    let mut crypto = builder.with_single_cert(cert_chain, priv_key)?;
+    crypto.max_early_data_size = u32::MAX;
+    crypto.alpn_protocols = alpn_protocols(args);
+
+    let mut server_config = ServerConfig::with_crypto(Arc::new(crypto));
+    let mut transport_config = transport_config(args);
+    if args.webtransport {
+        // HTTP/3 control and QPACK streams, and those a page opens in the session
+        transport_config.max_concurrent_uni_streams(crate::webtransport::MAX_UNI_STREAMS.into());
+    } else {
+        // the only unidirectional streams are those carrying a client's hello (see
+        // crate::version), its --checksum, or its --resume request to a --serve-file listener
+        let uni_streams = args.checksum.is_some() || args.serve_file.is_some();
+        transport_config.max_concurrent_uni_streams((1 + u8::from(uni_streams)).into());
+    }
+    server_config.transport_config(transport_config.into());
+    if args.low_memory.is_some() {
+        lowmem::limit_server(&mut server_config);
+    }
 
     Ok((server_config, cert_der))
 }
 
+/// Reads the `--cert` chain and `--key` from PEM files.
+fn load_cert(
+    cert: &Path,
+    key: &Path,
+) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey), Box<dyn Error>> {
+    let read = |path: &Path| {
+        let text =
+            fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        pem::parse_many(text).map_err(|e| format!("invalid PEM in {}: {}", path.display(), e))
+    };
+    let chain: Vec<rustls::Certificate> = read(cert)?
+        .into_iter()
+        .filter(|pem| pem.tag() == "CERTIFICATE")
+        .map(|pem| rustls::Certificate(pem.into_contents()))
+        .collect();
+    if chain.is_empty() {
+        return Err(format!("no certificate in {}", cert.display()).into());
+    }
+    // PKCS #8, PKCS #1 (RSA) and SEC1 (EC) keys are all understood by rustls
+    let key = read(key)?
+        .into_iter()
+        .find(|pem| pem.tag().ends_with("PRIVATE KEY"))
+        .map(|pem| rustls::PrivateKey(pem.into_contents()))
+        .ok_or_else(|| format!("no private key in {}", key.display()))?;
+    Ok((chain, key))
+}
+
+/// Self-signed certificate for `localhost`.
+fn generate_cert(
+    args: &Cli,
+) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey), Box<dyn Error>> {
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".into()]);
+    if args.webtransport {
+        // browsers only accept certificates pinned with serverCertificateHashes if they're valid
+        // for at most two weeks
+        let today = chrono::Utc::now().date_naive();
+        let date = |date: chrono::NaiveDate| {
+            rcgen::date_time_ymd(date.year(), date.month() as u8, date.day() as u8)
+        };
+        params.not_before = date(today);
+        params.not_after = date(today + chrono::Days::new(crate::webtransport::CERT_VALIDITY_DAYS));
+    }
+    let cert = rcgen::Certificate::from_params(params)?;
+    let cert_der = cert.serialize_der()?;
+    let priv_key = rustls::PrivateKey(cert.serialize_private_key_der());
+    Ok((vec![rustls::Certificate(cert_der)], priv_key))
+}
+
 /// Dummy certificate verifier that treats any certificate as valid.
 /// NOTE, such verification is vulnerable to MITM attacks, but convenient for testing.
 struct SkipServerVerification;
@@ -46,16 +445,70 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
     }
 }
 
-pub fn configure_client() -> ClientConfig {
-    let crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(SkipServerVerification::new())
-        .with_no_client_auth();
-    // Set timeout to 5min
-    let mut transport_config = TransportConfig::default();
-    transport_config.max_idle_timeout(Some(Duration::from_secs(5 * 60).try_into().unwrap()));
+pub fn configure_client(args: &Cli) -> ClientConfig {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let mut crypto = match &args.psk {
+        Some(psk) => {
+            let (chain, key) = psk.identity().expect("certificate for a derived key pair");
+            builder
+                .with_custom_certificate_verifier(psk.verifier())
+                .with_client_auth_cert(chain, key)
+                .expect("usable derived certificate")
+        }
+        None => builder
+            .with_custom_certificate_verifier(SkipServerVerification::new())
+            .with_no_client_auth(),
+    };
+    crypto.alpn_protocols = alpn_protocols(args);
     let mut client_config = ClientConfig::new(Arc::new(crypto));
-    client_config.transport_config(transport_config.into());
+    client_config.transport_config(transport_config(args).into());
+    negotiation::limit_client(&mut client_config, args);
 
     client_config
 }
+
+/// Writes the certificate chain the peer presented to `path` as PEM, returning the number of
+/// certificates. Works even though the chain isn't verified.
+pub fn save_peer_cert(conn: &Connection, path: &Path) -> Result<usize, Box<dyn Error>> {
+    let chain = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+        .ok_or("the peer presented no certificate")?;
+    let pems: Vec<pem::Pem> = chain
+        .iter()
+        .map(|cert| pem::Pem::new("CERTIFICATE", cert.0.clone()))
+        .collect();
+    fs::write(path, pem::encode_many(&pems))
+        .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(chain.len())
+}
+
+/// Transport settings shared by both sides.
+fn transport_config(args: &Cli) -> TransportConfig {
+    let mut transport_config = TransportConfig::default();
+    // Set the idle timeout to 5min
+    transport_config.max_idle_timeout(Some(Duration::from_secs(5 * 60).try_into().unwrap()));
+    if let Some(max_streams) = args.max_streams {
+        transport_config.max_concurrent_bidi_streams(max_streams.into());
+    }
+    if let Some(budget) = args.low_memory {
+        lowmem::limit_transport(&mut transport_config, budget, args.max_streams);
+    }
+    transport_config
+}
+
+/// Converts the `--alpn` protocol names, or the `--compress` codec, into the wire format expected
+/// by rustls. An empty list means no ALPN extension is sent/required. `--http3` and
+/// `--webtransport` use `h3` unless `--alpn` is given.
+fn alpn_protocols(args: &Cli) -> Vec<Vec<u8>> {
+    if let Some(compression) = args.compress {
+        return vec![compression.alpn().as_bytes().to_vec()];
+    }
+    if (args.http3.is_some() || args.webtransport) && args.alpn.is_empty() {
+        return vec![crate::http3::ALPN.as_bytes().to_vec()];
+    }
+    args.alpn
+        .iter()
+        .map(|proto| proto.as_bytes().to_vec())
+        .collect()
+}
@@ -0,0 +1,386 @@
+//! Version and feature exchange between peers (`--require-version VERSION`).
+//!
+//! At the start of a session, each side sends a hello on a control stream of its own (see
+//! [`crate::control`]), saying which nesquic version it runs, the protocol it speaks, the features
+//! it supports and those it uses for the session. The listener sends its hello first, and a client
+//! answers once it has it, so listeners from before the exchange never get a stream they don't
+//! expect; clients from before it can't use `--checksum` with a listener that sends one though,
+//! as they take the hello for the digest. A peer whose hello doesn't come within
+//! [`HELLO_TIMEOUT`] predates the exchange, or is a listener serving files, directories or test
+//! traffic, which don't take part.
+//!
+//! Mismatches are warned about instead of being left to garble the data or fail the transfer at
+//! the end: when only one side uses `--checksum`, no digest is sent or waited for, and channels
+//! the peer doesn't use are pointed out. Compression codecs are agreed on with ALPN already (see
+//! [`crate::codec`]). With `--require-version`, the session waits for the peer's hello, and a peer
+//! running an older version than VERSION, or not saying, is refused with [`VERSION_MISMATCH`].
+//!
+//! Hello format: `NQHI` magic, protocol as big-endian u16, supported and used features as
+//! big-endian u32 bitsets, length of the version as a u8, version.
+
+use std::{collections::HashMap, error::Error, fmt, sync::Mutex, time::Duration};
+
+use quinn::{Connection, ConnectionError, VarInt};
+use tokio::{sync::watch, time::timeout};
+use tracing::{debug, warn};
+
+use crate::control::{self, Magic};
+use crate::messages::tr;
+use crate::report::{self, Event};
+use crate::{tasks, Cli};
+
+pub const MAGIC: &Magic = b"NQHI";
+
+/// Version of what goes over the wire between peers, raised whenever that changes.
+pub const PROTOCOL: u16 = 1;
+
+/// Application error code used to close connections with peers refused by `--require-version`.
+pub const VERSION_MISMATCH: u32 = 12;
+
+/// How long the peer's hello may take to arrive.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a side about to end the session waits for the exchange to be over, in round-trip
+/// times, and at least.
+const ANSWER_GRACE_RTTS: u32 = 4;
+const MIN_ANSWER_GRACE: Duration = Duration::from_millis(50);
+
+/// Longest version a hello carries.
+const MAX_VERSION_LEN: usize = 64;
+
+/// Features told about in hellos, as bits.
+#[derive(Clone, Copy)]
+pub enum Feature {
+    Checksum = 1 << 0,
+    Zstd = 1 << 1,
+    Lz4 = 1 << 2,
+    Channels = 1 << 3,
+    Resume = 1 << 4,
+}
+
+/// Features this build supports.
+const SUPPORTED: u32 = Feature::Checksum as u32
+    | Feature::Zstd as u32
+    | Feature::Lz4 as u32
+    | Feature::Channels as u32
+    | Feature::Resume as u32;
+
+/// A nesquic version, as major, minor and patch numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(u64, u64, u64);
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Reads a version such as `0.1.0` or `0.2`, ignoring any pre-release or build suffix.
+fn read_version(version: &str) -> Option<Version> {
+    let release = version.split(['-', '+']).next()?;
+    let mut numbers = release.split('.').map(str::parse::<u64>);
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next().unwrap_or(Ok(0)).ok()?;
+    let patch = numbers.next().unwrap_or(Ok(0)).ok()?;
+    numbers
+        .next()
+        .is_none()
+        .then_some(Version(major, minor, patch))
+}
+
+/// Parses `--require-version`.
+pub fn parse_required(version: &str) -> Result<Version, String> {
+    read_version(version)
+        .ok_or_else(|| format!("invalid version '{}', expected e.g. 0.1.0", version))
+}
+
+/// What a side tells its peer about itself.
+#[derive(Clone)]
+struct Hello {
+    protocol: u16,
+    supported: u32,
+    used: u32,
+    version: String,
+}
+
+impl Hello {
+    fn ours(args: &Cli) -> Self {
+        let mut used = 0;
+        let mut using = |feature: Feature, on: bool| {
+            if on {
+                used |= feature as u32;
+            }
+        };
+        using(Feature::Checksum, args.checksum.is_some());
+        using(
+            Feature::Zstd,
+            args.compress == Some(crate::codec::Compression::Zstd),
+        );
+        using(
+            Feature::Lz4,
+            args.compress == Some(crate::codec::Compression::Lz4),
+        );
+        #[cfg(unix)]
+        let mux = args.mux.is_some();
+        #[cfg(not(unix))]
+        let mux = false;
+        using(Feature::Channels, !args.channel.is_empty() || mux);
+        using(Feature::Resume, args.resume.is_some());
+        Hello {
+            protocol: PROTOCOL,
+            supported: SUPPORTED,
+            used,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    fn supports(&self, feature: Feature) -> bool {
+        self.supported & feature as u32 != 0
+    }
+
+    fn uses(&self, feature: Feature) -> bool {
+        self.used & feature as u32 != 0
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut frame = MAGIC.to_vec();
+        frame.extend_from_slice(&self.protocol.to_be_bytes());
+        frame.extend_from_slice(&self.supported.to_be_bytes());
+        frame.extend_from_slice(&self.used.to_be_bytes());
+        frame.push(self.version.len() as u8);
+        frame.extend_from_slice(self.version.as_bytes());
+        frame
+    }
+
+    /// Reads a hello, whose magic was read already.
+    fn decode(frame: &[u8]) -> Option<Self> {
+        let (protocol, rest) = frame.split_first_chunk::<2>()?;
+        let (supported, rest) = rest.split_first_chunk::<4>()?;
+        let (used, rest) = rest.split_first_chunk::<4>()?;
+        let (&len, version) = rest.split_first()?;
+        let version = std::str::from_utf8(version.get(..usize::from(len))?).ok()?;
+        Some(Hello {
+            protocol: u16::from_be_bytes(*protocol),
+            supported: u32::from_be_bytes(*supported),
+            used: u32::from_be_bytes(*used),
+            version: version.to_string(),
+        })
+    }
+}
+
+/// How far the exchange with a peer got.
+#[derive(Clone)]
+enum Exchange {
+    /// The peer's hello may still come.
+    Waiting,
+    /// The peer's hello came, and ours is on its way to the listener.
+    Answering(Hello),
+    /// Over, with the peer's hello if it came.
+    Done(Option<Hello>),
+}
+
+/// Exchange with the peer of each open connection, by stable id.
+static PEERS: Mutex<Option<HashMap<usize, watch::Receiver<Exchange>>>> = Mutex::new(None);
+
+fn exchange_of(conn: &Connection) -> Option<watch::Receiver<Exchange>> {
+    PEERS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|peers| peers.get(&conn.stable_id()).cloned())
+}
+
+/// The exchange with the peer of a connection, kept past the connection's end.
+pub struct Peer(Option<watch::Receiver<Exchange>>);
+
+/// The peer of `conn`, for asking about its features once the connection may be closed.
+pub fn peer(conn: &Connection) -> Peer {
+    Peer(exchange_of(conn))
+}
+
+impl Peer {
+    /// Whether the peer uses `feature`, as far as its hello says, once it came or can't come
+    /// anymore; peers that didn't send one are taken to use it.
+    pub async fn uses(&self, feature: Feature) -> bool {
+        let Some(mut exchange) = self.0.clone() else {
+            return true;
+        };
+        let Ok(over) = exchange
+            .wait_for(|exchange| !matches!(exchange, Exchange::Waiting))
+            .await
+        else {
+            return true;
+        };
+        match &*over {
+            Exchange::Answering(peer) | Exchange::Done(Some(peer)) => peer.uses(feature),
+            _ => true,
+        }
+    }
+}
+
+/// Waits a little for the exchange to be over, so ending the session doesn't cut it short. On the
+/// client, the listener's hello may still be on its way, as it's sent once the session stream
+/// reaches the listener, and the listener may be gone already; on the listener, the client's
+/// hello only comes once the client has the listener's.
+pub async fn answered(conn: &Connection) {
+    let Some(mut exchange) = exchange_of(conn) else {
+        return;
+    };
+    let grace = (conn.rtt() * ANSWER_GRACE_RTTS).max(MIN_ANSWER_GRACE);
+    let _ = timeout(
+        grace,
+        exchange.wait_for(|exchange| matches!(exchange, Exchange::Done(_))),
+    )
+    .await;
+}
+
+/// Exchanges hellos with the peer of the session over `conn`, in the background unless
+/// `--require-version` has to wait for the peer's. Returns why the peer is refused, if it is,
+/// once the connection is closed.
+pub async fn exchange(conn: &Connection, args: &Cli, listener: bool) -> Result<(), String> {
+    let ours = Hello::ours(args);
+    let (progress, exchange) = watch::channel(Exchange::Waiting);
+    PEERS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(conn.stable_id(), exchange);
+    let closing = conn.clone();
+    tasks::spawn(async move {
+        closing.closed().await;
+        if let Some(peers) = PEERS.lock().unwrap().as_mut() {
+            peers.remove(&closing.stable_id());
+        }
+    });
+    let exchanging = hello(conn.clone(), ours, listener, progress);
+    let Some(required) = args.require_version else {
+        tasks::spawn(async move {
+            exchanging.await;
+        });
+        return Ok(());
+    };
+    let peer = exchanging.await;
+    let refusal = match peer.as_ref().map(|peer| read_version(&peer.version)) {
+        None => "it didn't say which version it runs".to_string(),
+        Some(Some(version)) if version >= required => return Ok(()),
+        Some(_) => format!(
+            "it runs nesquic {}, --require-version is {}",
+            peer.unwrap().version,
+            required
+        ),
+    };
+    conn.close(VarInt::from_u32(VERSION_MISMATCH), b"incompatible version");
+    Err(format!("refusing {}: {}", conn.remote_address(), refusal))
+}
+
+/// Whether the peer closed `conn` because of our version.
+pub fn refused(conn: &Connection) -> bool {
+    matches!(
+        conn.close_reason(),
+        Some(ConnectionError::ApplicationClosed(close))
+            if close.error_code == VarInt::from_u32(VERSION_MISMATCH)
+    )
+}
+
+/// A client refused by the listener because of its version, which connecting again won't change.
+#[derive(Debug)]
+pub struct Refused;
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&tr!(VersionRefused))
+    }
+}
+
+impl Error for Refused {}
+
+/// [`Refused`] if the peer refused `conn` because of our version, or else `e`.
+pub fn explain(conn: &Connection, e: Box<dyn Error>) -> Box<dyn Error> {
+    if refused(conn) {
+        Box::new(Refused)
+    } else {
+        e
+    }
+}
+
+/// Sends our hello and receives the peer's, the listener's first.
+async fn hello(
+    conn: Connection,
+    ours: Hello,
+    listener: bool,
+    progress: watch::Sender<Exchange>,
+) -> Option<Hello> {
+    let receiving = timeout(HELLO_TIMEOUT, receive(&conn));
+    let received = if listener {
+        // clients from before the exchange may not take the stream, so sending is bounded too
+        let sending = timeout(HELLO_TIMEOUT, send(conn.clone(), ours.encode()));
+        tokio::join!(sending, receiving).1
+    } else {
+        receiving.await
+    };
+    let peer = match received {
+        Ok(Ok(peer)) => Some(peer),
+        Ok(Err(e)) => {
+            debug!("[version] no hello from the peer: {}", e);
+            None
+        }
+        Err(_) => None,
+    };
+    report::emit(Event::PeerVersion {
+        peer: conn.remote_address(),
+        version: peer.as_ref().map(|peer| peer.version.clone()),
+        protocol: peer.as_ref().map(|peer| peer.protocol),
+    });
+    if let Some(peer) = &peer {
+        warn_mismatches(&ours, peer);
+        if !listener {
+            progress.send_replace(Exchange::Answering(peer.clone()));
+            let _ = timeout(HELLO_TIMEOUT, send(conn.clone(), ours.encode())).await;
+        }
+    }
+    progress.send_replace(Exchange::Done(peer.clone()));
+    peer
+}
+
+async fn send(conn: Connection, frame: Vec<u8>) {
+    let sent = async {
+        let mut send = conn.open_uni().await?;
+        send.write_all(&frame).await?;
+        send.finish().await?;
+        Ok::<_, Box<dyn Error>>(())
+    };
+    if let Err(e) = sent.await {
+        debug!("[version] could not send our hello: {}", e);
+    }
+}
+
+async fn receive(conn: &Connection) -> Result<Hello, Box<dyn Error + Send + Sync>> {
+    let mut recv = control::accept(conn, MAGIC).await?;
+    let frame = recv.read_to_end(MAGIC.len() + 11 + MAX_VERSION_LEN).await?;
+    Ok(Hello::decode(&frame).ok_or("malformed hello")?)
+}
+
+fn warn_mismatches(ours: &Hello, peer: &Hello) {
+    if peer.protocol > ours.protocol {
+        warn!(
+            "[version] the peer runs nesquic {}, whose protocol is newer ({}, {} here); what it \
+             added is left out",
+            peer.version, peer.protocol, ours.protocol
+        );
+    }
+    match (ours.uses(Feature::Checksum), peer.uses(Feature::Checksum)) {
+        (true, false) => warn!(
+            "[version] the peer doesn't use --checksum, so neither side's data will be verified"
+        ),
+        (false, true) => warn!(
+            "[version] the peer uses --checksum but this side doesn't, so neither side's data \
+             will be verified"
+        ),
+        _ => {}
+    }
+    if ours.uses(Feature::Channels) && !peer.supports(Feature::Channels) {
+        warn!("[version] the peer doesn't support channels, only the session stream goes through");
+    } else if ours.uses(Feature::Channels) && !peer.uses(Feature::Channels) {
+        warn!("[version] the peer doesn't use channels, it may refuse the ones opened here");
+    }
+}
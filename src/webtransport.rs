@@ -0,0 +1,206 @@
+//! WebTransport server mode (`-l --webtransport`), so browsers can exchange data with
+//! stdin/stdout.
+//!
+//! The listener speaks HTTP/3 and waits for a page to establish a WebTransport session (an
+//! extended CONNECT request); the first bidirectional stream the page opens in the session then
+//! carries the data like the session stream of a regular connection. Other requests are answered
+//! with 404. Browsers don't trust the self-signed certificate, so its SHA-256 hash is printed on
+//! startup for `serverCertificateHashes`, which only accepts certificates valid for at most two
+//! weeks.
+
+use std::{
+    error::Error,
+    io::{stdout, Write},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use h3::{ext::Protocol, quic::BidiStream as _, server::RequestStream};
+use h3_webtransport::server::{AcceptedBi, WebTransportSession};
+use http::{Method, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, error, info};
+
+use crate::input::{self, Input};
+use crate::pending::Queue;
+use crate::rate::TokenBucket;
+use crate::report::{self, Event};
+use crate::{binary, tap, timers, util::make_server_endpoint, Cli};
+
+/// Validity of the generated certificate, within the two weeks browsers accept.
+pub const CERT_VALIDITY_DAYS: u64 = 13;
+/// Unidirectional streams the client may open: HTTP/3 control and QPACK streams, plus any the page
+/// opens (which are ignored).
+pub const MAX_UNI_STREAMS: u8 = 16;
+
+type Session = WebTransportSession<h3_quinn::Connection, Bytes>;
+
+/// Runs a WebTransport server bound to `addrs` for a single session.
+pub async fn run(addrs: &[SocketAddr], args: &Cli) -> Result<(), Box<dyn Error>> {
+    let (endpoint, cert) = make_server_endpoint(addrs, args)?;
+    let hash = ring::digest::digest(&ring::digest::SHA256, &cert);
+    report::emit(Event::WebTransportCertificate {
+        sha256: hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect(),
+        valid_days: CERT_VALIDITY_DAYS,
+    });
+
+    let mut queue = Queue::new(&endpoint, args);
+    let (_session, send, recv) = loop {
+        match accept_session(&mut queue).await {
+            Ok(Some(accepted)) => break accepted,
+            Ok(None) => {}
+            Err(e) => debug!("[webtransport] session not established: {}", e),
+        }
+    };
+    queue.refuse_rest();
+    info!("[webtransport] session stream opened");
+
+    let limiter = crate::limiter(args);
+    let result = if args.recv_only {
+        recv_to_stdout(recv).await
+    } else if args.send_only {
+        stdin_to_send(send, limiter).await
+    } else {
+        crate::both_ways(
+            args,
+            stdin_to_send(send, limiter),
+            tokio::spawn(recv_to_stdout(recv)),
+        )
+        .await
+    };
+    result.map_err(|()| "session failed".into())
+}
+
+/// Accepts a connection and, if it establishes a WebTransport session, the session's first
+/// bidirectional stream.
+async fn accept_session(
+    queue: &mut Queue,
+) -> Result<
+    Option<(
+        Session,
+        impl AsyncWrite + Unpin + Send + 'static,
+        impl AsyncRead + Unpin + Send + 'static,
+    )>,
+    Box<dyn Error>,
+> {
+    let connecting = queue.accept().await.ok_or("endpoint closed")?;
+    let conn = connecting.await?;
+    report::connection(&conn);
+    timers::watch_session(&conn);
+    let mut h3_conn = h3::server::builder()
+        .enable_webtransport(true)
+        .enable_connect(true)
+        .enable_datagram(true)
+        .max_webtransport_sessions(1)
+        .send_grease(true)
+        .build(h3_quinn::Connection::new(conn))
+        .await?;
+
+    let session = loop {
+        match h3_conn.accept().await? {
+            Some((request, stream)) if is_webtransport(&request) => {
+                info!("[webtransport] session requested for {}", request.uri());
+                break WebTransportSession::accept(request, stream, h3_conn).await?;
+            }
+            Some((request, stream)) => not_found(request, stream).await?,
+            None => return Ok(None),
+        }
+    };
+    loop {
+        match session.accept_bi().await? {
+            Some(AcceptedBi::BidiStream(_, stream)) => {
+                let (send, recv) = stream.split();
+                return Ok(Some((session, send, recv)));
+            }
+            Some(AcceptedBi::Request(request, stream)) => not_found(request, stream).await?,
+            None => return Ok(None),
+        }
+    }
+}
+
+fn is_webtransport(request: &Request<()>) -> bool {
+    request.method() == Method::CONNECT
+        && request.extensions().get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT)
+}
+
+async fn not_found(
+    request: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> Result<(), h3::Error> {
+    debug!(
+        "[webtransport] not a WebTransport session: {} {}",
+        request.method(),
+        request.uri()
+    );
+    let response = Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(())
+        .unwrap();
+    stream.send_response(response).await?;
+    stream.finish().await
+}
+
+async fn recv_to_stdout(mut recv: impl AsyncRead + Unpin) -> Result<(), ()> {
+    let mut stdout = stdout();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match recv.read(&mut buffer).await {
+            Ok(0) => {
+                info!("stream was closed by the peer.");
+                return Ok(());
+            }
+            Ok(n) => {
+                let data = &buffer[..n];
+                timers::touch();
+                tap::received(data);
+                if !binary::allow(data) {
+                    return Err(());
+                }
+                if let Err(e) = stdout.write_all(data).and_then(|()| stdout.flush()) {
+                    error!("failed to write to stdout: {}", e);
+                    return Err(());
+                }
+            }
+            Err(e) => {
+                error!("unexpected error, shutting down {}", e);
+                return Err(());
+            }
+        }
+    }
+}
+
+async fn stdin_to_send(
+    mut send: impl AsyncWrite + Unpin,
+    limiter: Option<Arc<TokenBucket>>,
+) -> Result<(), ()> {
+    let mut input = Input::stdin();
+    loop {
+        let chunks = match input.next().await {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                error!("failed to read from stdin: {}", e);
+                return Err(());
+            }
+        };
+        if chunks.is_empty() {
+            break;
+        }
+        for chunk in chunks {
+            if let Some(limiter) = &limiter {
+                limiter.take(chunk.len()).await;
+            }
+            if let Err(e) = input::watch_stall(send.write_all(&chunk)).await {
+                error!("failed to send data: {}", e);
+                return Err(());
+            }
+            timers::touch();
+            tap::sent(&chunk);
+        }
+    }
+    if let Err(e) = send.shutdown().await {
+        error!("failed to finish stream: {}", e);
+        return Err(());
+    }
+    Ok(())
+}
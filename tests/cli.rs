@@ -0,0 +1,1670 @@
+//! Command-line compatibility suite: runs the built binary in listen/connect pairs over
+//! loopback and checks what scripts depend on, i.e. the flags, exit codes, output formats and
+//! that data arrives byte for byte.
+//!
+//! The flags in `golden/flags.txt` must keep being accepted. Adding a flag doesn't touch the
+//! file, removing or renaming one is a breaking change and has to be done on purpose.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use assert_cmd::Command as Nesquic;
+use predicates::prelude::*;
+
+const BIN: &str = env!("CARGO_BIN_EXE_nesquic");
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn nesquic() -> Nesquic {
+    let mut cmd = Nesquic::new(BIN);
+    // messages are checked in English
+    cmd.env("NO_COLOR", "1").env("LC_ALL", "C").timeout(TIMEOUT);
+    cmd
+}
+
+/// A loopback port nothing is listening on, at least for now.
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A listener running in the background, killed if the test ends before it does.
+struct Listener {
+    child: Child,
+    /// Its stdout and stderr, read as it goes so it never blocks on a full pipe; stderr can be
+    /// looked at before it exits.
+    stdout: Option<JoinHandle<Vec<u8>>>,
+    stderr: (Arc<Mutex<Vec<u8>>>, Option<JoinHandle<()>>),
+}
+
+fn drain(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        pipe.read_to_end(&mut output).unwrap();
+        output
+    })
+}
+
+/// Like [`drain`], with what was read so far shared as it comes.
+fn drain_shared(mut pipe: impl Read + Send + 'static) -> (Arc<Mutex<Vec<u8>>>, JoinHandle<()>) {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let shared = output.clone();
+    let reader = thread::spawn(move || {
+        let mut chunk = [0; 4096];
+        while let Ok(read @ 1..) = pipe.read(&mut chunk) {
+            shared.lock().unwrap().extend_from_slice(&chunk[..read]);
+        }
+    });
+    (output, reader)
+}
+
+impl Listener {
+    fn spawn(port: u16, args: &[&str]) -> Self {
+        Self::start(port, args, &["127.0.0.1", &port.to_string()], Stdio::null())
+    }
+
+    /// Like [`Listener::spawn`], with its stdin fed by the caller.
+    fn spawn_piped(port: u16, args: &[&str]) -> (Self, ChildStdin) {
+        let mut listener = Self::start(
+            port,
+            args,
+            &["127.0.0.1", &port.to_string()],
+            Stdio::piped(),
+        );
+        let stdin = listener.child.stdin.take().unwrap();
+        (listener, stdin)
+    }
+
+    /// Like [`Listener::spawn`], listening on `addrs` given as IP PORT pairs, of which one is
+    /// `port` on 127.0.0.1 or any IPv4 address.
+    fn spawn_on(port: u16, args: &[&str], addrs: &[&str]) -> Self {
+        Self::start(port, args, addrs, Stdio::null())
+    }
+
+    fn start(port: u16, args: &[&str], addrs: &[&str], stdin: Stdio) -> Self {
+        let mut child = Command::new(BIN)
+            .env("NO_COLOR", "1")
+            .env("LC_ALL", "C")
+            .arg("-l")
+            .args(args)
+            .args(addrs)
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = Some(drain(child.stdout.take().unwrap()));
+        let (stderr, reader) = drain_shared(child.stderr.take().unwrap());
+        // ready once its socket is bound
+        let start = Instant::now();
+        while UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+            assert!(start.elapsed() < TIMEOUT, "listener never bound its port");
+            thread::sleep(Duration::from_millis(10));
+        }
+        Listener {
+            child,
+            stdout,
+            stderr: (stderr, Some(reader)),
+        }
+    }
+
+    /// Waits for the listener to exit, returning its exit code, stdout and stderr.
+    fn wait(mut self) -> (Option<i32>, Vec<u8>, String) {
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                break status;
+            }
+            assert!(start.elapsed() < TIMEOUT, "listener didn't exit");
+            thread::sleep(Duration::from_millis(10));
+        };
+        let (stdout, stderr) = self.output();
+        (status.code(), stdout, stderr)
+    }
+
+    /// Stops the listener, returning what it wrote to stderr.
+    fn stop(mut self) -> String {
+        let _ = self.child.kill();
+        self.child.wait().unwrap();
+        self.output().1
+    }
+
+    /// Waits for the listener to write `needle` to stderr.
+    fn wait_for_stderr(&self, needle: &str) {
+        let start = Instant::now();
+        while !String::from_utf8_lossy(&self.stderr.0.lock().unwrap()).contains(needle) {
+            assert!(
+                start.elapsed() < TIMEOUT,
+                "listener never wrote '{}'",
+                needle
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(unix)]
+    fn signal(&self, signal: libc::c_int) {
+        // SAFETY: plain kill(2) on our own child
+        assert_eq!(
+            unsafe { libc::kill(self.child.id() as libc::pid_t, signal) },
+            0
+        );
+    }
+
+    fn output(&mut self) -> (Vec<u8>, String) {
+        let stdout = self.stdout.take().unwrap().join().unwrap();
+        self.stderr.1.take().unwrap().join().unwrap();
+        let stderr = self.stderr.0.lock().unwrap();
+        (stdout, String::from_utf8_lossy(&stderr).into_owned())
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn documented_flags_are_still_accepted() {
+    let help = nesquic().arg("--help").assert().success();
+    let help = String::from_utf8(help.get_output().stdout.clone()).unwrap();
+    // flags with an optional value are listed as --flag[=<VALUE>...]
+    let listed: Vec<_> = help
+        .split_whitespace()
+        .map(|word| word.split("[=").next().unwrap())
+        .collect();
+    let missing: Vec<_> = include_str!("golden/flags.txt")
+        .lines()
+        .filter(|flag| !flag.is_empty() && !listed.contains(flag))
+        .collect();
+    assert!(
+        missing.is_empty(),
+        "flags no longer accepted: {:?}",
+        missing
+    );
+}
+
+#[test]
+fn missing_address_is_a_usage_error() {
+    nesquic()
+        .write_stdin("")
+        .assert()
+        .code(2)
+        .stdout("")
+        .stderr(predicate::str::contains("connecting needs the IP address"))
+        .stderr(predicate::str::contains("EXAMPLES:"));
+}
+
+#[test]
+fn usage_errors_follow_the_locale() {
+    nesquic()
+        .env("LC_ALL", "pt_BR.UTF-8")
+        .args(["127.0.0.1", "http"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("'http' não é um número de porta"));
+    // JSON events stay in English for scripts
+    nesquic()
+        .env("LC_ALL", "pt_BR.UTF-8")
+        .args(["--script-mode", "127.0.0.1", "http"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("'http' isn't a port number"));
+}
+
+#[test]
+fn bad_port_is_a_usage_error() {
+    nesquic()
+        .args(["127.0.0.1", "http"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("http"));
+}
+
+#[test]
+fn checksum_through_a_relay_is_a_usage_error() {
+    // the digest goes on a stream of its own, which relays don't forward
+    nesquic()
+        .args([
+            "--checksum",
+            "sha256",
+            "--token",
+            "abc",
+            "127.0.0.1",
+            "5003",
+        ])
+        .write_stdin("")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--checksum"));
+    nesquic()
+        .args(["--relay", "--checksum", "sha256", "5003"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--checksum"));
+}
+
+#[test]
+fn completions_and_man_page_cover_the_flags() {
+    nesquic()
+        .args(["completions", "bash"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("--serve-file"))
+        .stdout(predicate::str::contains("nesquic profiles"));
+    nesquic()
+        .arg("man")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(".ie"))
+        .stdout(predicate::str::contains(r"\-\-serve\-file"));
+}
+
+#[test]
+fn selfbench_measures_both_pipelines() {
+    let output = nesquic()
+        .args(["selfbench", "--bytes", "262144"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    for pipeline in ["stdin -> stream", "stream -> stdout"] {
+        let rows: Vec<_> = output
+            .lines()
+            .filter(|line| line.starts_with(pipeline))
+            .collect();
+        assert_eq!(rows.len(), 6, "{}", output);
+        for row in rows {
+            let rate: f64 = row.split_whitespace().last().unwrap().parse().unwrap();
+            assert!(rate > 0.0, "{}", row);
+        }
+    }
+}
+
+#[test]
+fn unknown_flag_is_a_usage_error() {
+    nesquic()
+        .arg("--no-such-flag")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--no-such-flag"));
+}
+
+#[test]
+fn one_way_transfer_is_byte_for_byte() {
+    let port = free_port();
+    let data = payload(1024 * 1024);
+    let listener = Listener::spawn(port, &["--recv-only"]);
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin(data.clone())
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(received == data, "received data differs from what was sent");
+}
+
+#[test]
+fn low_memory_transfer_is_byte_for_byte() {
+    let port = free_port();
+    let data = payload(4 * 1024 * 1024);
+    let listener = Listener::spawn(port, &["--recv-only", "--low-memory=64KiB"]);
+    nesquic()
+        .args([
+            "--send-only",
+            "--low-memory",
+            "127.0.0.1",
+            &port.to_string(),
+        ])
+        .write_stdin(data.clone())
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(received == data, "received data differs from what was sent");
+}
+
+#[test]
+fn small_send_buffer_keeps_transfer_byte_for_byte() {
+    let port = free_port();
+    let data = payload(4 * 1024 * 1024);
+    let listener = Listener::spawn(port, &["--recv-only", "--low-memory=64KiB"]);
+    nesquic()
+        .args([
+            "--send-only",
+            "--buffer",
+            "64KiB",
+            "127.0.0.1",
+            &port.to_string(),
+        ])
+        .write_stdin(data.clone())
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(received == data, "received data differs from what was sent");
+
+    nesquic()
+        .args(["--buffer", "4KiB", "127.0.0.1", "4433"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("too small"));
+}
+
+#[test]
+fn chunk_sizes_keep_transfer_byte_for_byte() {
+    for (sender, receiver) in [("1MiB", "64KiB"), ("64KiB", "16MiB")] {
+        let port = free_port();
+        let data = payload(3 * 1024 * 1024 + 17);
+        let listener = Listener::spawn(port, &["--recv-only", "--chunk-size", receiver]);
+        nesquic()
+            .args([
+                "--send-only",
+                "--chunk-size",
+                sender,
+                "--coalesce-send",
+                "5ms",
+            ])
+            .args(["127.0.0.1", &port.to_string()])
+            .write_stdin(data.clone())
+            .assert()
+            .success();
+        let (code, received, _) = listener.wait();
+        assert_eq!(code, Some(0));
+        assert!(received == data, "received data differs from what was sent");
+    }
+}
+
+#[test]
+fn crlf_and_strip_cr_translate_line_endings() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only"]);
+    nesquic()
+        .args(["--send-only", "-C", "127.0.0.1", &port.to_string()])
+        .write_stdin("HELO example.org\nQUIT\r\n\n")
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"HELO example.org\r\nQUIT\r\n\r\n");
+
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only", "--strip-cr"]);
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin("250 OK\r\n221 Bye\r\nno\rend\r")
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"250 OK\n221 Bye\nno\rend\r");
+}
+
+#[test]
+fn interval_paces_every_line_sent() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only"]);
+    let started = Instant::now();
+    nesquic()
+        .args(["--send-only", "-i", "300ms", "127.0.0.1", &port.to_string()])
+        .write_stdin("one\ntwo\nthree\n")
+        .assert()
+        .success();
+    assert!(
+        started.elapsed() >= Duration::from_millis(900),
+        "three lines were sent in {:?}",
+        started.elapsed()
+    );
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"one\ntwo\nthree\n");
+}
+
+#[test]
+fn transfer_over_emulated_bad_network_is_byte_for_byte() {
+    let port = free_port();
+    let data = payload(512 * 1024);
+    let listener = Listener::spawn(
+        port,
+        &[
+            "--recv-only",
+            "--emulate",
+            "loss=5%,delay=5ms,reorder=2%,seed=7",
+        ],
+    );
+    nesquic()
+        .args(["--send-only", "--emulate", "delay=5ms,jitter=2ms,seed=7"])
+        .args(["127.0.0.1", &port.to_string()])
+        .write_stdin(data.clone())
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(received == data, "received data differs from what was sent");
+}
+
+#[test]
+fn wrong_auth_token_is_turned_away() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only", "--auth-token", "s3cret"]);
+    // enough data that the client is still sending when it gets turned away
+    nesquic()
+        .args(["--send-only", "--auth-token", "guess", "127.0.0.1"])
+        .arg(port.to_string())
+        .write_stdin(payload(1024 * 1024))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("rejected the connection"));
+    // the listener keeps waiting for a client that knows the secret
+    nesquic()
+        .args(["--send-only", "--auth-token", "s3cret", "127.0.0.1"])
+        .arg(port.to_string())
+        .write_stdin("friend\n")
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"friend\n");
+}
+
+#[test]
+fn denied_addresses_are_turned_away() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only", "--deny", "127.0.0.0/8"]);
+    // enough data that the client is still sending when it gets turned away
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin(payload(1024 * 1024))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("from our address"));
+    listener.stop();
+
+    let port = free_port();
+    let listener = Listener::spawn(
+        port,
+        &[
+            "--recv-only",
+            "--allow",
+            "127.0.0.1",
+            "--deny",
+            "10.0.0.0/8",
+        ],
+    );
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin("friend\n")
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"friend\n");
+}
+
+#[test]
+fn failed_connects_are_retried() {
+    // nothing listens there
+    let port = free_port();
+    nesquic()
+        .env("RUST_LOG", "warn")
+        .args([
+            "--retry",
+            "1",
+            "--retry-delay",
+            "10ms",
+            "--connect-timeout",
+            "300ms",
+        ])
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin("hi\n")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("retrying in").count(1));
+}
+
+#[test]
+fn sessions_that_started_are_not_retried() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only", "--auth-token", "s3cret"]);
+    nesquic()
+        .env("RUST_LOG", "warn")
+        .args(["--retry", "3", "--retry-delay", "10ms", "--send-only"])
+        .args(["--auth-token", "guess", "127.0.0.1", &port.to_string()])
+        .write_stdin(payload(1024 * 1024))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("rejected the connection"))
+        .stderr(predicate::str::contains("retrying").not());
+    listener.stop();
+}
+
+#[test]
+fn peers_report_each_others_version() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["-v", "--recv-only"]);
+    let client = nesquic()
+        .args(["-v", "--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin("hi\n")
+        .assert()
+        .success();
+    let announced = format!("runs nesquic {} (protocol 1)", env!("CARGO_PKG_VERSION"));
+    let stderr = String::from_utf8_lossy(&client.get_output().stderr).into_owned();
+    assert!(stderr.contains(&announced), "{}", stderr);
+    let (code, received, stderr) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"hi\n");
+    assert!(stderr.contains(&announced), "{}", stderr);
+}
+
+#[test]
+fn checksum_on_one_side_only_is_skipped() {
+    let port = free_port();
+    let data = payload(256 * 1024);
+    let listener = Listener::spawn(port, &["--recv-only", "--checksum", "sha256"]);
+    nesquic()
+        .env("RUST_LOG", "warn")
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin(data.clone())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "the peer uses --checksum but this side doesn't",
+        ));
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(received == data, "received data differs from what was sent");
+}
+
+#[test]
+fn require_version_refuses_older_peers() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only", "--require-version", "99.0"]);
+    // enough data that the client is still sending when it gets refused
+    // being refused is final, so it isn't retried
+    nesquic()
+        .env("RUST_LOG", "warn")
+        .args(["--send-only", "--retry", "2", "--retry-delay", "10ms"])
+        .args(["127.0.0.1", &port.to_string()])
+        .write_stdin(payload(4 * 1024 * 1024))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("requires a newer nesquic version"))
+        .stderr(predicate::str::contains("retrying").not());
+    let (code, received, stderr) = listener.wait();
+    assert_eq!(code, Some(1));
+    assert!(received.is_empty());
+    assert!(stderr.contains("--require-version is 99.0.0"), "{}", stderr);
+}
+
+#[test]
+fn psk_peers_only_accept_each_other() {
+    const SECRET: &str = "bmVzcXVpYyB0ZXN0IHNlY3JldCwgMzIgYnl0ZXMhIQ==";
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only", "--psk", SECRET]);
+    nesquic()
+        .args([
+            "--send-only",
+            "--psk",
+            "b3RoZXIgc2VjcmV0LCBhbHNvIDMyIGJ5dGVzIQ==",
+        ])
+        .args(["127.0.0.1", &port.to_string()])
+        .write_stdin("intruder\n")
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("invalid peer certificate"));
+    nesquic()
+        .args([
+            "--send-only",
+            "--psk",
+            SECRET,
+            "127.0.0.1",
+            &port.to_string(),
+        ])
+        .write_stdin("friend\n")
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"friend\n");
+}
+
+#[test]
+fn serve_file_exits_after_count_transfers() {
+    let port = free_port();
+    let data = payload(512 * 1024);
+    let file = std::env::temp_dir().join(format!("nesquic-serve-{}", port));
+    std::fs::write(&file, &data).unwrap();
+    let listener = Listener::spawn(
+        port,
+        &["--serve-file", file.to_str().unwrap(), "--count", "2"],
+    );
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            let port = port.to_string();
+            thread::spawn(move || {
+                nesquic()
+                    .args(["--recv-only", "127.0.0.1", &port])
+                    .assert()
+                    .success()
+                    .get_output()
+                    .stdout
+                    .clone()
+            })
+        })
+        .collect();
+    for client in clients {
+        assert!(client.join().unwrap() == data, "received file differs");
+    }
+    let (code, _, _) = listener.wait();
+    std::fs::remove_file(file).unwrap();
+    assert_eq!(code, Some(0));
+}
+
+#[test]
+fn serve_file_keeps_to_the_rate_limit() {
+    let port = free_port();
+    let data = payload(160 * 1024);
+    let file = std::env::temp_dir().join(format!("nesquic-serve-rate-{}", port));
+    std::fs::write(&file, &data).unwrap();
+    let listener = Listener::spawn(
+        port,
+        &[
+            "--serve-file",
+            file.to_str().unwrap(),
+            "--count",
+            "1",
+            "--limit-rate",
+            "64KiB/s",
+        ],
+    );
+    let start = Instant::now();
+    let received = nesquic()
+        .args(["--recv-only", "127.0.0.1", &port.to_string()])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let elapsed = start.elapsed();
+    assert!(received == data, "received file differs");
+    // a second's worth goes out right away, the rest at 64 KiB/s
+    assert!(elapsed >= Duration::from_secs(1), "took {:?}", elapsed);
+    let (code, _, _) = listener.wait();
+    std::fs::remove_file(file).unwrap();
+    assert_eq!(code, Some(0));
+}
+
+#[test]
+fn resume_continues_a_transfer_cut_short() {
+    let port = free_port();
+    let data = payload(1024 * 1024);
+    let dir = std::env::temp_dir();
+    let served = dir.join(format!("nesquic-resume-served-{}", port));
+    let partial = dir.join(format!("nesquic-resume-partial-{}", port));
+    let mismatched = dir.join(format!("nesquic-resume-mismatched-{}", port));
+    std::fs::write(&served, &data).unwrap();
+    std::fs::write(&partial, &data[..300 * 1024]).unwrap();
+    std::fs::write(&mismatched, vec![0xff; 4096]).unwrap();
+    let listener = Listener::spawn(
+        port,
+        &[
+            "-v",
+            "--serve-file",
+            served.to_str().unwrap(),
+            "--count",
+            "2",
+        ],
+    );
+    for file in [&partial, &mismatched] {
+        nesquic()
+            .args(["--resume", file.to_str().unwrap(), "127.0.0.1"])
+            .arg(port.to_string())
+            .assert()
+            .success()
+            .stdout("");
+        assert!(std::fs::read(file).unwrap() == data, "resumed file differs");
+    }
+    let (code, _, stderr) = listener.wait();
+    assert_eq!(code, Some(0));
+    // only what was missing from the partial file, then the whole file for the mismatched one
+    assert!(stderr.contains("741376 bytes sent"), "{}", stderr);
+    assert!(stderr.contains("1048576 bytes sent"), "{}", stderr);
+    for file in [served, partial, mismatched] {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+#[test]
+fn resume_keeps_the_blocks_that_match() {
+    let port = free_port();
+    let data = payload(3 * 1024 * 1024);
+    let dir = std::env::temp_dir();
+    let served = dir.join(format!("nesquic-resume-served-{}", port));
+    let damaged = dir.join(format!("nesquic-resume-damaged-{}", port));
+    std::fs::write(&served, &data).unwrap();
+    // the second MiB went bad
+    let mut held = data[..5 * 512 * 1024].to_vec();
+    held[3 * 512 * 1024] ^= 0xff;
+    std::fs::write(&damaged, &held).unwrap();
+    let listener = Listener::spawn(
+        port,
+        &[
+            "-v",
+            "--serve-file",
+            served.to_str().unwrap(),
+            "--count",
+            "1",
+        ],
+    );
+    nesquic()
+        .env("RUST_LOG", "warn")
+        .args(["--resume", damaged.to_str().unwrap(), "127.0.0.1"])
+        .arg(port.to_string())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("only the first 1048576 bytes"));
+    assert!(
+        std::fs::read(&damaged).unwrap() == data,
+        "resumed file differs"
+    );
+    let (code, _, stderr) = listener.wait();
+    assert_eq!(code, Some(0));
+    // everything after the first MiB
+    assert!(stderr.contains("2097152 bytes sent"), "{}", stderr);
+    for file in [served, damaged] {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+#[test]
+fn out_of_order_reads_fill_the_file_in_place() {
+    let port = free_port();
+    let data = payload(1024 * 1024);
+    let dir = std::env::temp_dir();
+    let served = dir.join(format!("nesquic-unordered-served-{}", port));
+    let received = dir.join(format!("nesquic-unordered-received-{}", port));
+    let partial = dir.join(format!("nesquic-unordered-partial-{}", port));
+    std::fs::write(&served, &data).unwrap();
+    std::fs::write(&partial, &data[..300 * 1024]).unwrap();
+    let listener = Listener::spawn(
+        port,
+        &["--serve-file", served.to_str().unwrap(), "--count", "2"],
+    );
+    // stdout redirected to a file
+    let status = Command::new(BIN)
+        .args([
+            "--recv-only",
+            "--out-of-order",
+            "--read-chunk-size",
+            "128KiB",
+        ])
+        .args(["127.0.0.1", &port.to_string()])
+        .stdout(std::fs::File::create(&received).unwrap())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(
+        std::fs::read(&received).unwrap() == data,
+        "received file differs"
+    );
+    // the rest of a resumed file, after what it holds
+    nesquic()
+        .args(["--resume", partial.to_str().unwrap(), "--out-of-order"])
+        .args(["127.0.0.1", &port.to_string()])
+        .assert()
+        .success();
+    assert!(
+        std::fs::read(&partial).unwrap() == data,
+        "resumed file differs"
+    );
+    let (code, _, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    for file in [served, received, partial] {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+#[test]
+fn out_of_order_needs_stdout_in_a_file() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--send-only"]);
+    nesquic()
+        .args([
+            "--recv-only",
+            "--out-of-order",
+            "127.0.0.1",
+            &port.to_string(),
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--out-of-order needs stdout redirected to a file",
+        ));
+    listener.stop();
+}
+
+#[cfg(unix)]
+#[test]
+fn session_dir_keeps_each_session_with_its_metadata() {
+    let port = free_port();
+    let dir = std::env::temp_dir().join(format!("nesquic-sessions-{}", port));
+    let listener = Listener::spawn(
+        port,
+        &["--session-dir", dir.to_str().unwrap(), "--count", "2"],
+    );
+    let sent = [payload(300_000), b"second session".to_vec()];
+    for data in &sent {
+        nesquic()
+            .args(["--send-only", "127.0.0.1", &port.to_string()])
+            .write_stdin(data.clone())
+            .assert()
+            .success();
+    }
+    let (code, _, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    files.sort();
+    assert_eq!(files.len(), 4, "unexpected files: {:?}", files);
+    for (data, pair) in sent.iter().zip(files.chunks(2)) {
+        assert!(std::fs::read(&pair[0]).unwrap() == *data, "session differs");
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&pair[1]).unwrap()).unwrap();
+        assert_eq!(metadata["bytes"], data.len());
+        assert_eq!(metadata["complete"], true);
+        assert!(metadata["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert!(metadata["close_reason"].is_string());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn listener_serves_ipv4_and_ipv6_on_one_port() {
+    let port = free_port();
+    let data = payload(64 * 1024);
+    let file = std::env::temp_dir().join(format!("nesquic-dualstack-{}", port));
+    std::fs::write(&file, &data).unwrap();
+    let port_arg = port.to_string();
+    // the dual-stack example from the README
+    let listener = Listener::spawn_on(
+        port,
+        &["--serve-file", file.to_str().unwrap(), "--count", "2"],
+        &["0.0.0.0", &port_arg, "::", &port_arg],
+    );
+    for host in ["127.0.0.1", "::1"] {
+        let received = nesquic()
+            .args(["--recv-only", host, &port_arg])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        assert!(received == data, "received file differs over {}", host);
+    }
+    let (code, _, stderr) = listener.wait();
+    std::fs::remove_file(file).unwrap();
+    assert_eq!(code, Some(0), "{}", stderr);
+}
+
+#[test]
+fn listener_serves_every_address_it_is_given() {
+    let port = free_port();
+    let other = std::iter::repeat_with(free_port)
+        .find(|other| *other != port)
+        .unwrap();
+    let data = payload(64 * 1024);
+    let file = std::env::temp_dir().join(format!("nesquic-multihome-{}", port));
+    std::fs::write(&file, &data).unwrap();
+    let other_addr = other.to_string();
+    let listener = Listener::spawn(
+        port,
+        &[
+            "--serve-file",
+            file.to_str().unwrap(),
+            "--count",
+            "2",
+            "127.0.0.1",
+            &other_addr,
+        ],
+    );
+    for port in [port, other] {
+        let received = nesquic()
+            .args(["--recv-only", "127.0.0.1", &port.to_string()])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        assert!(received == data, "received file differs on port {}", port);
+    }
+    let (code, _, _) = listener.wait();
+    std::fs::remove_file(file).unwrap();
+    assert_eq!(code, Some(0));
+}
+
+#[cfg(unix)]
+#[test]
+fn directory_transfer_keeps_files_and_modes() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let port = free_port();
+    let root = std::env::temp_dir().join(format!("nesquic-dir-{}", port));
+    let (from, to) = (root.join("from"), root.join("to"));
+    fs::create_dir_all(from.join("sub/deeper")).unwrap();
+    fs::write(from.join("top.txt"), "top\n").unwrap();
+    fs::write(from.join("sub/deeper/data.bin"), payload(200_000)).unwrap();
+    fs::set_permissions(from.join("top.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::set_permissions(from.join("sub"), fs::Permissions::from_mode(0o750)).unwrap();
+
+    let listener = Listener::spawn(port, &["--send-dir", from.to_str().unwrap()]);
+    nesquic()
+        .args(["--recv-dir", to.to_str().unwrap(), "127.0.0.1"])
+        .arg(port.to_string())
+        .assert()
+        .success();
+    let (code, _, _) = listener.wait();
+    assert_eq!(code, Some(0));
+
+    assert_eq!(fs::read(to.join("top.txt")).unwrap(), b"top\n");
+    assert!(fs::read(to.join("sub/deeper/data.bin")).unwrap() == payload(200_000));
+    let mode = |path: &str| fs::metadata(to.join(path)).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode("top.txt"), 0o600);
+    assert_eq!(mode("sub"), 0o750);
+    let mtime = |dir: &std::path::Path| {
+        fs::metadata(dir.join("top.txt"))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    assert_eq!(mtime(&from), mtime(&to));
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn directory_transfer_keeps_existing_files_without_overwrite() {
+    use std::fs;
+
+    let port = free_port();
+    let root = std::env::temp_dir().join(format!("nesquic-dir-{}", port));
+    let (from, to) = (root.join("from"), root.join("to"));
+    fs::create_dir_all(&from).unwrap();
+    fs::create_dir_all(&to).unwrap();
+    fs::write(from.join("top.txt"), "new\n").unwrap();
+    fs::write(to.join("top.txt"), "old\n").unwrap();
+
+    let listener = Listener::spawn(port, &["--send-dir", from.to_str().unwrap()]);
+    nesquic()
+        .args(["--recv-dir", to.to_str().unwrap(), "127.0.0.1"])
+        .arg(port.to_string())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("use --overwrite"));
+    listener.stop();
+    assert_eq!(fs::read(to.join("top.txt")).unwrap(), b"old\n");
+
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--send-dir", from.to_str().unwrap()]);
+    nesquic()
+        .args([
+            "--recv-dir",
+            to.to_str().unwrap(),
+            "--overwrite",
+            "127.0.0.1",
+        ])
+        .arg(port.to_string())
+        .assert()
+        .success();
+    let (code, _, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(fs::read(to.join("top.txt")).unwrap(), b"new\n");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn directory_transfer_refuses_symbolic_links() {
+    use std::fs;
+
+    let port = free_port();
+    let root = std::env::temp_dir().join(format!("nesquic-dir-{}", port));
+    let (from, to, outside) = (root.join("from"), root.join("to"), root.join("outside"));
+    fs::create_dir_all(from.join("sub")).unwrap();
+    fs::create_dir_all(&to).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(from.join("sub/escaped.txt"), "escaped\n").unwrap();
+    std::os::unix::fs::symlink(&outside, to.join("sub")).unwrap();
+
+    let listener = Listener::spawn(port, &["--send-dir", from.to_str().unwrap()]);
+    nesquic()
+        .args([
+            "--recv-dir",
+            to.to_str().unwrap(),
+            "--overwrite",
+            "127.0.0.1",
+        ])
+        .arg(port.to_string())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("through a symbolic link"));
+    listener.stop();
+    assert!(!outside.join("escaped.txt").exists());
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn plugins_on_path_run_as_subcommands() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let dir = std::env::temp_dir().join(format!("nesquic-plugins-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let plugin = dir.join("nesquic-greet");
+    fs::write(
+        &plugin,
+        "#!/bin/sh\necho \"$* alpn=$NESQUIC_ALPN\"\ntest -x \"$NESQUIC\" || exit 9\nexit 4\n",
+    )
+    .unwrap();
+    fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = std::env::join_paths(
+        std::iter::once(dir.clone())
+            .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+
+    nesquic()
+        .env("PATH", &path)
+        .args(["--alpn", "x", "-v", "greet", "--loud", "world"])
+        .assert()
+        .code(4)
+        .stdout("--loud world alpn=x\n");
+    // addresses are never taken for plugin names
+    nesquic()
+        .env("PATH", &path)
+        .args(["greet2", "4433"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("isn't an IP address"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn hooks_run_on_connect_and_close() {
+    let port = free_port();
+    let log = std::env::temp_dir().join(format!("nesquic-hooks-{}", port));
+    let on_connect = format!("echo \"$NESQUIC_EVENT $NESQUIC_PEER\" >> {}", log.display());
+    let on_close = format!(
+        "echo \"$NESQUIC_EVENT $NESQUIC_RECEIVED_BYTES $NESQUIC_DURATION\" >> {}",
+        log.display()
+    );
+    let listener = Listener::spawn(
+        port,
+        &[
+            "--recv-only",
+            "--on-connect",
+            &on_connect,
+            "--on-close",
+            &on_close,
+        ],
+    );
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin("hello\n")
+        .assert()
+        .success();
+    let (code, _, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    let log_text = std::fs::read_to_string(&log).unwrap();
+    std::fs::remove_file(&log).unwrap();
+    let lines: Vec<_> = log_text.lines().collect();
+    assert_eq!(lines.len(), 2, "unexpected hook runs: {:?}", lines);
+    assert!(lines[0].starts_with("connect 127.0.0.1:"), "{:?}", lines);
+    let close = predicate::str::is_match(r"^close 6 \d+\.\d{3}$").unwrap();
+    assert!(close.eval(lines[1]), "{:?}", lines);
+}
+
+#[test]
+fn busy_listener_refuses_with_its_message() {
+    let port = free_port();
+    let listener = Listener::spawn(
+        port,
+        &["--recv-only", "--refuse-message", "busy, use port 5004"],
+    );
+    // the first client takes the listener's only session
+    let mut first = Command::new(BIN)
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = first.stdin.take().unwrap();
+    stdin.write_all(b"first\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    nesquic()
+        .args(["--recv-only", "127.0.0.1", &port.to_string()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the listener refused the connection: busy, use port 5004",
+        ));
+
+    drop(stdin);
+    assert!(first.wait().unwrap().success());
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"first\n");
+}
+
+/// Body of `GET /metrics` from the metrics listener on `port`.
+fn scrape(port: u16) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    response.split_once("\r\n\r\n").unwrap().1.to_string()
+}
+
+#[test]
+fn metrics_count_connections_and_bytes() {
+    let port = free_port();
+    let metrics_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let listener = Listener::spawn(
+        port,
+        &[
+            "--recv-only",
+            "--metrics",
+            &format!("127.0.0.1:{}", metrics_port),
+        ],
+    );
+    thread::sleep(Duration::from_millis(300));
+    let before = scrape(metrics_port);
+    assert!(
+        before.contains("nesquic_connections_total 0\n"),
+        "{}",
+        before
+    );
+    assert!(before.contains("# TYPE nesquic_rtt_seconds histogram\n"));
+
+    let mut client = Command::new(BIN)
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = client.stdin.take().unwrap();
+    stdin.write_all(b"hello\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let during = scrape(metrics_port);
+    for line in [
+        "nesquic_connections_total 1\n",
+        "nesquic_open_connections 1\n",
+        "nesquic_open_streams 1\n",
+        "nesquic_received_bytes_total 6\n",
+        "nesquic_handshake_failures_total 0\n",
+    ] {
+        assert!(during.contains(line), "missing {:?} in:\n{}", line, during);
+    }
+
+    drop(stdin);
+    assert!(client.wait().unwrap().success());
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"hello\n");
+}
+
+#[test]
+fn interactive_escapes_are_run_instead_of_sent() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only"]);
+    let mut client = Command::new(BIN)
+        .args(["--interactive", "127.0.0.1", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = client.stdin.take().unwrap();
+    stdin.write_all(b"hello\n~~tilde\n~#\n~s\n~x\n").unwrap();
+    // closing drops whatever wasn't sent yet
+    thread::sleep(Duration::from_millis(500));
+    stdin.write_all(b"~.\nnever sent\n").unwrap();
+    drop(stdin);
+    let mut stderr = String::new();
+    client
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert!(client.wait().unwrap().success(), "{}", stderr);
+    assert!(stderr.contains("[streams] 127.0.0.1:"), "{}", stderr);
+    assert!(stderr.contains("[stats] 127.0.0.1:"), "{}", stderr);
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"hello\n~tilde\n~x\n");
+}
+
+#[test]
+fn shutdown_on_eof_keeps_receiving_until_the_peer_finishes() {
+    let port = free_port();
+    let (listener, mut listener_stdin) = Listener::spawn_piped(port, &["--keep-sending"]);
+    let mut client = Command::new(BIN)
+        .args(["-N", "127.0.0.1", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // the client is at EOF before the listener sends anything
+    client.stdin.take().unwrap().write_all(b"hello\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    listener_stdin.write_all(b"late\n").unwrap();
+    drop(listener_stdin);
+
+    let mut received = Vec::new();
+    client
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut received)
+        .unwrap();
+    assert_eq!(received, b"late\n");
+    assert!(client.wait().unwrap().success());
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"hello\n");
+}
+
+#[test]
+fn failing_to_write_what_was_received_fails_the_session() {
+    let port = free_port();
+    let (listener, mut listener_stdin) = Listener::spawn_piped(port, &["--send-only"]);
+    let mut client = Command::new(BIN)
+        .args(["--recv-only", "127.0.0.1", &port.to_string()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // nothing reads the client's stdout anymore
+    drop(client.stdout.take());
+    listener_stdin.write_all(&payload(64 * 1024)).unwrap();
+    drop(listener_stdin);
+
+    let status = client.wait().unwrap();
+    let mut stderr = String::new();
+    client
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert_eq!(status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("failed to write to stdout"), "{}", stderr);
+    listener.stop();
+}
+
+#[test]
+fn prioritized_channel_is_transferred_alongside_the_session() {
+    let port = free_port();
+    let dir = std::env::temp_dir();
+    let source = dir.join(format!("nesquic-priority-in-{}", port));
+    let sink = dir.join(format!("nesquic-priority-out-{}", port));
+    let control = payload(64 * 1024);
+    let bulk = payload(4 * 1024 * 1024);
+    std::fs::write(&source, &control).unwrap();
+    let listener = Listener::spawn(
+        port,
+        &[
+            "--recv-only",
+            "--priority",
+            "1=10",
+            "--channel",
+            &format!("1=>{}", sink.display()),
+        ],
+    );
+    nesquic()
+        .args([
+            "--send-only",
+            "--priority",
+            "1=10",
+            "--priority",
+            "0=-1",
+            "--channel",
+            &format!("1=<{}", source.display()),
+            "127.0.0.1",
+            &port.to_string(),
+        ])
+        .write_stdin(bulk.clone())
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(received == bulk, "session data differs from what was sent");
+    assert!(std::fs::read(&sink).unwrap() == control);
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&sink);
+}
+
+#[cfg(unix)]
+#[test]
+fn fd_mapped_twice_is_refused() {
+    nesquic()
+        .args([
+            "--fd-map",
+            "0=ch1",
+            "--fd-map",
+            "0=ch2",
+            "127.0.0.1",
+            "5003",
+        ])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "file descriptor 0 is mapped more than once",
+        ));
+}
+
+#[test]
+fn priority_needs_a_channel_number() {
+    nesquic()
+        .args(["--priority", "high", "127.0.0.1", "5003"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("expected N=PRIORITY"));
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_replaces_stdin_and_stdout() {
+    use std::{net::Shutdown, os::unix::net::UnixListener};
+
+    let port = free_port();
+    let path = std::env::temp_dir().join(format!("nesquic-unix-{}.sock", port));
+    let _ = std::fs::remove_file(&path);
+    let daemon = UnixListener::bind(&path).unwrap();
+    let daemon = thread::spawn(move || {
+        let (mut socket, _) = daemon.accept().unwrap();
+        socket.write_all(b"from daemon\n").unwrap();
+        socket.shutdown(Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).unwrap();
+        received
+    });
+    let listener = Listener::spawn(
+        port,
+        &[
+            "-N",
+            "--keep-sending",
+            "--unix-socket",
+            path.to_str().unwrap(),
+        ],
+    );
+    nesquic()
+        .args(["-N", "127.0.0.1", &port.to_string()])
+        .write_stdin("hello\n")
+        .assert()
+        .success()
+        .stdout("from daemon\n");
+    assert_eq!(daemon.join().unwrap(), b"hello\n");
+    let (code, stdout, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(stdout.is_empty());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn empty_input_transfers_nothing() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only"]);
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin("")
+        .assert()
+        .success();
+    let (code, received, _) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert!(received.is_empty());
+}
+
+#[test]
+fn connect_timeout_exits_with_3() {
+    nesquic()
+        .args(["--connect-timeout", "300ms", "127.0.0.1"])
+        .arg(free_port().to_string())
+        .write_stdin("")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("could not connect within 300ms"));
+}
+
+#[test]
+fn deadline_exits_with_5() {
+    let port = free_port().to_string();
+    nesquic()
+        .args(["-w", "300ms", "-l", "--recv-only", "127.0.0.1", &port])
+        .assert()
+        .code(5)
+        .stderr(predicate::str::contains("deadline reached"));
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_exits_with_130() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only"]);
+    listener.signal(libc::SIGINT);
+    let (code, _, _) = listener.wait();
+    assert_eq!(code, Some(130));
+}
+
+#[test]
+fn bad_proxy_url_is_an_error() {
+    nesquic()
+        .args(["--proxy", "http://proxy", "127.0.0.1", "4433"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("expected socks5://HOST:PORT"));
+}
+
+#[test]
+fn discard_prints_a_summary_per_stream() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--discard"]);
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin(payload(300_000))
+        .assert()
+        .success();
+    // the listener keeps going for the next stream, give it a moment to print the summary
+    thread::sleep(Duration::from_millis(500));
+    let stderr = listener.stop();
+    let summary = predicate::str::is_match(
+        r"\[discard\] stream 0: 300000 bytes in \d+\.\d{3}s \([\d.]+ [kMG]?B/s\)",
+    )
+    .unwrap();
+    assert!(summary.eval(&stderr), "unexpected summary: {}", stderr);
+}
+
+#[test]
+fn ping_reports_every_reply_and_a_summary() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &[]);
+    let output = nesquic()
+        .args([
+            "--ping=50ms",
+            "--count",
+            "3",
+            "127.0.0.1",
+            &port.to_string(),
+        ])
+        .assert()
+        .success()
+        .get_output()
+        .stderr
+        .clone();
+    listener.stop();
+    let stderr = String::from_utf8_lossy(&output);
+    let reply =
+        predicate::str::is_match(r"\[ping\] reply from 127\.0\.0\.1:\d+: seq=\d rtt=").unwrap();
+    assert_eq!(
+        stderr.lines().filter(|line| reply.eval(line)).count(),
+        3,
+        "unexpected replies: {}",
+        stderr
+    );
+    assert!(
+        stderr.contains("3 probes sent, 3 replies (0% lost)"),
+        "unexpected summary: {}",
+        stderr
+    );
+}
+
+#[test]
+fn json_events_have_stable_fields() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--log-format", "json", "--recv-only"]);
+    nesquic()
+        .args(["--send-only", "127.0.0.1", &port.to_string()])
+        .write_stdin("hello\n")
+        .assert()
+        .success();
+    let (code, _, stderr) = listener.wait();
+    assert_eq!(code, Some(0));
+    let events: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("not a JSON event"))
+        .collect();
+    let event = |name: &str| {
+        events
+            .iter()
+            .find(|event| event["event"] == name)
+            .unwrap_or_else(|| panic!("no {} event in {:?}", name, events))
+    };
+    for field in ["peer", "alpn", "version", "timestamp"] {
+        assert!(
+            event("connect").get(field).is_some(),
+            "connect has no {}",
+            field
+        );
+    }
+    let transferred = event("bytes_transferred");
+    assert_eq!(transferred["bytes"], 6);
+    assert_eq!(transferred["direction"], "received");
+    assert_eq!(transferred["stream"], 0);
+    assert!(transferred["seconds"].is_f64());
+}
+
+#[test]
+fn peer_address_changes_are_reported() {
+    let port = free_port();
+    let listener = Listener::spawn(port, &["--recv-only", "-v"]);
+    let mut client = Command::new(BIN)
+        .args([
+            "--send-only",
+            "--rebind-every",
+            "1s",
+            "127.0.0.1",
+            &port.to_string(),
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = client.stdin.take().unwrap();
+    stdin.write_all(b"before\n").unwrap();
+    listener.wait_for_stderr("(NAT rebinding)");
+    stdin.write_all(b"after\n").unwrap();
+    drop(stdin);
+    assert!(client.wait().unwrap().success());
+    let (code, received, stderr) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"before\nafter\n");
+    assert!(
+        stderr.contains("* peer address changed from 127.0.0.1:")
+            && stderr.contains("(NAT rebinding)"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn pinned_quic_version_is_negotiated_and_reported() {
+    let port = free_port();
+    let listener = Listener::spawn(
+        port,
+        &["--recv-only", "-v", "--quic-version", "draft-29,v1"],
+    );
+    nesquic()
+        .args(["--send-only", "--quic-version", "draft-29", "-v"])
+        .args(["127.0.0.1", &port.to_string()])
+        .write_stdin("hello\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("complete (QUIC draft-29, TLS 1.3"));
+    let (code, received, stderr) = listener.wait();
+    assert_eq!(code, Some(0));
+    assert_eq!(received, b"hello\n");
+    assert!(
+        stderr.contains("complete (QUIC draft-29, TLS 1.3"),
+        "{}",
+        stderr
+    );
+
+    // a client offering a version the listener doesn't speak can't connect
+    let listener = Listener::spawn(port, &["--recv-only", "--quic-version", "v1"]);
+    nesquic()
+        .args(["--send-only", "--quic-version", "draft-29"])
+        .args(["--connect-timeout", "2s", "127.0.0.1", &port.to_string()])
+        .write_stdin("hello\n")
+        .assert()
+        .failure();
+    listener.stop();
+}
+
+#[test]
+fn bad_duration_is_rejected() {
+    nesquic()
+        .args(["--connect-timeout", "soon", "127.0.0.1", "4433"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--connect-timeout"));
+}